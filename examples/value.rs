@@ -10,10 +10,8 @@ fn main() {
     let filename = &args[1];
     let mut contents = Vec::new();
     File::open(&Path::new(filename)).unwrap().read_to_end(&mut contents).unwrap();
-    println!("{:?}", contents);
 
-    /* todo
-   let a: msgpack::Value = msgpack::from_msgpack(contents.as_slice()).ok().unwrap();
-   println!("{:?}", a);
-   */
+    let mut decoder = msgpack::Decoder::new(&contents[..]);
+    let a = decoder.decode_value().unwrap();
+    println!("{}", a);
 }
@@ -0,0 +1,95 @@
+//! Wrappers to encode byte buffers as msgpack `bin` instead of arrays.
+
+use std::ops::{Deref, DerefMut};
+use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+// `Encoder` and `Decoder` recognize these struct names and write the
+// sequence of `u8` inside as `bin`, `str` or `ext` respectively. Other
+// encoders simply see a struct with a `bytes` (or `type` and `data`) field.
+pub const BIN_STRUCT_NAME: &'static str = "__msgpack_bin";
+pub const STR_STRUCT_NAME: &'static str = "__msgpack_str";
+pub const EXT_STRUCT_NAME: &'static str = "__msgpack_ext";
+
+// `Decoder` picks the variant of an enum with this name from the next
+// marker, so that `Value` can implement `Decodable`.
+pub const VALUE_ENUM_NAME: &'static str = "__msgpack_value";
+pub const VALUE_VARIANTS: [&'static str; 11] = ["Nil", "Boolean", "Integer", "Unsigned", "Float", "Double",
+                                                 "Array", "Map", "Str", "Binary", "Extended"];
+
+/// A borrowed byte slice that is encoded as msgpack `bin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bytes<'a>(pub &'a [u8]);
+
+/// An owned byte buffer that is encoded and decoded as msgpack `bin`.
+///
+/// In lenient mode, the `Decoder` also accepts `str` values and arrays of
+/// `u8` for a `ByteBuf`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct ByteBuf(pub Vec<u8>);
+
+fn encode_bytes<S: Encoder>(v: &[u8], s: &mut S) -> Result<(), S::Error> {
+    s.emit_struct(BIN_STRUCT_NAME, 1, |s| {
+        s.emit_struct_field("bytes", 0, |s| v.encode(s))
+    })
+}
+
+/// Encodes `v` as msgpack `str`, even if it is not valid UTF-8.
+pub fn encode_raw_str<S: Encoder>(v: &[u8], s: &mut S) -> Result<(), S::Error> {
+    s.emit_struct(STR_STRUCT_NAME, 1, |s| {
+        s.emit_struct_field("bytes", 0, |s| v.encode(s))
+    })
+}
+
+/// Encodes `data` as msgpack `ext` of type `typ`.
+pub fn encode_ext<S: Encoder>(typ: i8, data: &[u8], s: &mut S) -> Result<(), S::Error> {
+    s.emit_struct(EXT_STRUCT_NAME, 2, |s| {
+        try!(s.emit_struct_field("type", 0, |s| s.emit_i8(typ)));
+        s.emit_struct_field("data", 1, |s| data.encode(s))
+    })
+}
+
+impl<'a> Encodable for Bytes<'a> {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        encode_bytes(self.0, s)
+    }
+}
+
+impl Encodable for ByteBuf {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        encode_bytes(&self.0, s)
+    }
+}
+
+impl Decodable for ByteBuf {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ByteBuf, D::Error> {
+        d.read_struct(BIN_STRUCT_NAME, 1, |d| {
+            d.read_struct_field("bytes", 0, |d| Decodable::decode(d).map(ByteBuf))
+        })
+    }
+}
+
+impl<'a> Deref for Bytes<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { self.0 }
+}
+
+impl Deref for ByteBuf {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> { &self.0 }
+}
+
+impl DerefMut for ByteBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> { &mut self.0 }
+}
+
+impl From<Vec<u8>> for ByteBuf {
+    fn from(v: Vec<u8>) -> ByteBuf { ByteBuf(v) }
+}
+
+impl From<ByteBuf> for Vec<u8> {
+    fn from(b: ByteBuf) -> Vec<u8> { b.0 }
+}
+
+impl<'a> From<&'a [u8]> for Bytes<'a> {
+    fn from(v: &'a [u8]) -> Bytes<'a> { Bytes(v) }
+}
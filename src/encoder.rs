@@ -1,6 +1,7 @@
 use std::io::Write;
 use byteorder::{self,BigEndian, WriteBytesExt};
 use std::{self, mem};
+use super::_invalid_input;
 
 #[inline]
 fn encode_u7<W:Write>(wr: &mut W, val: u8) -> byteorder::Result<()> {
@@ -8,46 +9,106 @@ fn encode_u7<W:Write>(wr: &mut W, val: u8) -> byteorder::Result<()> {
     wr.write_u8(val as u8)
 }
 
-#[inline]
-fn encode_u8<W:Write>(wr: &mut W, val: u8) -> byteorder::Result<()> {
+/// Encodes `val` as uint 8 (`0xcc`), regardless of its magnitude
+pub fn encode_u8_exact<W:Write>(wr: &mut W, val: u8) -> byteorder::Result<()> {
     try!(wr.write_u8(0xcc));
     wr.write_u8(val)
 }
 
-#[inline]
-fn encode_u16<W:Write>(wr: &mut W, val: u16) -> byteorder::Result<()> {
+/// Encodes `val` as uint 16 (`0xcd`), regardless of its magnitude
+pub fn encode_u16_exact<W:Write>(wr: &mut W, val: u16) -> byteorder::Result<()> {
     try!(wr.write_u8(0xcd));
     wr.write_u16::<BigEndian>(val)
 }
 
-#[inline]
-fn encode_u32<W:Write>(wr: &mut W, val: u32) -> byteorder::Result<()> {
+/// Encodes `val` as uint 32 (`0xce`), regardless of its magnitude
+pub fn encode_u32_exact<W:Write>(wr: &mut W, val: u32) -> byteorder::Result<()> {
     try!(wr.write_u8(0xce));
     wr.write_u32::<BigEndian>(val)
 }
 
-#[inline]
-fn encode_u64<W:Write>(wr: &mut W, val: u64) -> byteorder::Result<()> {
+/// Encodes `val` as uint 64 (`0xcf`), regardless of its magnitude
+pub fn encode_u64_exact<W:Write>(wr: &mut W, val: u64) -> byteorder::Result<()> {
     try!(wr.write_u8(0xcf));
     wr.write_u64::<BigEndian>(val)
 }
 
+/// Encodes `val` as int 8 (`0xd0`), regardless of its magnitude
+pub fn encode_i8_exact<W:Write>(wr: &mut W, val: i8) -> byteorder::Result<()> {
+    try!(wr.write_u8(0xd0));
+    wr.write_i8(val)
+}
+
+/// Encodes `val` as int 16 (`0xd1`), regardless of its magnitude
+pub fn encode_i16_exact<W:Write>(wr: &mut W, val: i16) -> byteorder::Result<()> {
+    try!(wr.write_u8(0xd1));
+    wr.write_i16::<BigEndian>(val)
+}
+
+/// Encodes `val` as int 32 (`0xd2`), regardless of its magnitude
+pub fn encode_i32_exact<W:Write>(wr: &mut W, val: i32) -> byteorder::Result<()> {
+    try!(wr.write_u8(0xd2));
+    wr.write_i32::<BigEndian>(val)
+}
+
+/// Encodes `val` as int 64 (`0xd3`), regardless of its magnitude
+pub fn encode_i64_exact<W:Write>(wr: &mut W, val: i64) -> byteorder::Result<()> {
+    try!(wr.write_u8(0xd3));
+    wr.write_i64::<BigEndian>(val)
+}
+
 /// Encodes the most efficient representation of the given unsigned integer
 pub fn encode_unsigned<W:Write>(wr: &mut W, val: u64) -> byteorder::Result<()> {
     if val <= 127 {
         encode_u7(wr, val as u8)
     }
     else if val <= std::u8::MAX as u64 {
-        encode_u8(wr, val as u8)
+        encode_u8_exact(wr, val as u8)
     }
     else if val <= std::u16::MAX as u64 {
-        encode_u16(wr, val as u16)
+        encode_u16_exact(wr, val as u16)
     }
     else if val <= std::u32::MAX as u64 {
-        encode_u32(wr, val as u32)
+        encode_u32_exact(wr, val as u32)
+    }
+    else {
+        encode_u64_exact(wr, val)
+    }
+}
+
+/// Encodes the most efficient representation of the given signed integer.
+///
+/// Non-negative values are written with the unsigned formats, like
+/// `encode_unsigned` does.
+pub fn encode_signed<W:Write>(wr: &mut W, val: i64) -> byteorder::Result<()> {
+    if val >= 0 {
+        encode_unsigned(wr, val as u64)
+    }
+    else if val >= -32 {
+        wr.write_i8(val as i8)
+    }
+    else if val >= std::i8::MIN as i64 {
+        encode_i8_exact(wr, val as i8)
+    }
+    else if val >= std::i16::MIN as i64 {
+        encode_i16_exact(wr, val as i16)
+    }
+    else if val >= std::i32::MIN as i64 {
+        encode_i32_exact(wr, val as i32)
     }
     else {
-        encode_u64(wr, val)
+        encode_i64_exact(wr, val)
+    }
+}
+
+/// Converts the length of a str, bin, ext or container to the `u32` used
+/// on the wire, failing for lengths msgpack cannot represent.
+#[inline]
+pub fn checked_len(len: usize) -> byteorder::Result<u32> {
+    if len as u64 > std::u32::MAX as u64 {
+        Err(_invalid_input("Length does not fit into u32"))
+    } else {
+        Ok(len as u32)
     }
 }
 
@@ -89,6 +150,20 @@ pub fn encode_map_len<W:Write>(wr: &mut W, len: u32) -> byteorder::Result<()> {
     encode_op_len(wr, len, Some((0x80, 15)), None, 0xde, 0xdf)
 }
 
+/// Encodes the header of an ext value, using a fixext format when `len`
+/// matches one of the fixed sizes.
+pub fn encode_ext_len<W:Write>(wr: &mut W, len: u32, typ: i8) -> byteorder::Result<()> {
+    match len {
+        1  => try!(wr.write_u8(0xd4)),
+        2  => try!(wr.write_u8(0xd5)),
+        4  => try!(wr.write_u8(0xd6)),
+        8  => try!(wr.write_u8(0xd7)),
+        16 => try!(wr.write_u8(0xd8)),
+        _  => try!(encode_op_len(wr, len, None, Some(0xc7), 0xc8, 0xc9))
+    }
+    wr.write_i8(typ)
+}
+
 pub fn encode_nil<W:Write>(wr: &mut W) -> byteorder::Result<()> {
     wr.write_u8(0xc0)
 }
@@ -102,14 +177,9 @@ pub fn encode_bool<W:Write>(wr: &mut W, val: bool) -> byteorder::Result<()> {
 }
 
 pub fn encode_str<W:Write>(wr: &mut W, val: &str) -> byteorder::Result<()> {
-    let len = val.len();
-    assert!(len <= std::u32::MAX as usize);
-    try!(encode_str_len(wr, len as u32));
-    // XXX There has to be a better way to do this...
-    match wr.write_all(val.as_bytes()) {
-        Ok(_)  => Ok(()),
-        Err(e) => Err(byteorder::Error::Io(e))
-    }
+    let len = try!(checked_len(val.len()));
+    try!(encode_str_len(wr, len));
+    write_raw(wr, val.as_bytes())
 }
 
 pub fn encode_f32<W:Write>(wr: &mut W, val: f32) -> byteorder::Result<()> {
@@ -121,3 +191,127 @@ pub fn encode_f64<W:Write>(wr: &mut W, val: f64) -> byteorder::Result<()> {
     try!(wr.write_u8(0xcb));
     unsafe { wr.write_u64::<BigEndian>(mem::transmute(val)) }
 }
+
+/// Returns the marker byte that `f` writes first, e.g. `0xcd` for
+/// `marker(|w| encode_unsigned(w, 300))`. Meant for the length and integer
+/// headers, which fit into a small buffer.
+pub(crate) fn marker<F>(f: F) -> u8 where F: FnOnce(&mut &mut [u8]) -> byteorder::Result<()> {
+    let mut buf = [0u8; 16];
+    {
+        let mut w = &mut buf[..];
+        let _ = f(&mut w);
+    }
+    buf[0]
+}
+
+#[inline]
+fn write_raw<W:Write>(wr: &mut W, data: &[u8]) -> byteorder::Result<()> {
+    match wr.write_all(data) {
+        Ok(_)  => Ok(()),
+        Err(e) => Err(byteorder::Error::Io(e))
+    }
+}
+
+pub fn encode_bin<W:Write>(wr: &mut W, val: &[u8]) -> byteorder::Result<()> {
+    let len = try!(checked_len(val.len()));
+    try!(encode_bin_len(wr, len));
+    write_raw(wr, val)
+}
+
+/// Encodes an ext value of type `typ`, choosing the smallest format that
+/// can hold `data`.
+pub fn encode_ext<W:Write>(wr: &mut W, typ: i8, data: &[u8]) -> byteorder::Result<()> {
+    let len = try!(checked_len(data.len()));
+    try!(encode_ext_len(wr, len, typ));
+    write_raw(wr, data)
+}
+
+/// Encodes a fixext value. `data` has to be 1, 2, 4, 8 or 16 bytes long.
+pub fn encode_fixext<W:Write>(wr: &mut W, typ: i8, data: &[u8]) -> byteorder::Result<()> {
+    match data.len() {
+        1 | 2 | 4 | 8 | 16 => encode_ext(wr, typ, data),
+        _                  => Err(_invalid_input("Invalid fixext length"))
+    }
+}
+
+/// Encodes a point in time as the predefined timestamp ext type (-1),
+/// using the smallest of the timestamp 32, 64 and 96 formats.
+pub fn encode_timestamp<W:Write>(wr: &mut W, secs: i64, nsecs: u32) -> byteorder::Result<()> {
+    if nsecs >= 1_000_000_000 {
+        return Err(_invalid_input("Invalid timestamp nanoseconds"));
+    }
+    if secs >= 0 && (secs >> 34) == 0 {
+        if nsecs == 0 && secs <= std::u32::MAX as i64 {
+            try!(encode_ext_len(wr, 4, -1));
+            wr.write_u32::<BigEndian>(secs as u32)
+        } else {
+            try!(encode_ext_len(wr, 8, -1));
+            wr.write_u64::<BigEndian>(((nsecs as u64) << 34) | (secs as u64))
+        }
+    } else {
+        try!(encode_ext_len(wr, 12, -1));
+        try!(wr.write_u32::<BigEndian>(nsecs));
+        wr.write_i64::<BigEndian>(secs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encoded<F>(f: F) -> Vec<u8> where F: FnOnce(&mut Vec<u8>) -> ::byteorder::Result<()> {
+        let mut v = Vec::new();
+        f(&mut v).unwrap();
+        v
+    }
+
+    #[test]
+    fn test_encode_signed() {
+        assert_eq!(vec![0x05], encoded(|w| encode_signed(w, 5)));
+        assert_eq!(vec![0xff], encoded(|w| encode_signed(w, -1)));
+        assert_eq!(vec![0xe0], encoded(|w| encode_signed(w, -32)));
+        assert_eq!(vec![0xd0, 0xdf], encoded(|w| encode_signed(w, -33)));
+        assert_eq!(vec![0xd1, 0xff, 0x7f], encoded(|w| encode_signed(w, -129)));
+        assert_eq!(vec![0xd2, 0xff, 0xff, 0x7f, 0xff], encoded(|w| encode_signed(w, -32769)));
+        assert_eq!(vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0], encoded(|w| encode_signed(w, ::std::i64::MIN)));
+    }
+
+    #[test]
+    fn test_encode_exact() {
+        assert_eq!(vec![0xce, 0, 0, 0, 1], encoded(|w| encode_u32_exact(w, 1)));
+        assert_eq!(vec![0xd1, 0, 1], encoded(|w| encode_i16_exact(w, 1)));
+    }
+
+    #[test]
+    fn test_encode_bin() {
+        assert_eq!(vec![0xc4, 2, 1, 2], encoded(|w| encode_bin(w, &[1, 2])));
+        let v = encoded(|w| encode_bin(w, &[0; 256]));
+        assert_eq!(&[0xc5, 1, 0][..], &v[..3]);
+        assert_eq!(259, v.len());
+    }
+
+    #[test]
+    fn test_encode_ext() {
+        assert_eq!(vec![0xd5, 3, 1, 2], encoded(|w| encode_ext(w, 3, &[1, 2])));
+        assert_eq!(vec![0xc7, 3, 5, 1, 2, 3], encoded(|w| encode_ext(w, 5, &[1, 2, 3])));
+        assert_eq!(vec![0xd4, 3, 9], encoded(|w| encode_fixext(w, 3, &[9])));
+        assert!(encode_fixext(&mut Vec::new(), 3, &[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_encode_timestamp() {
+        assert_eq!(vec![0xd6, 0xff, 0, 0, 0, 1], encoded(|w| encode_timestamp(w, 1, 0)));
+        assert_eq!(vec![0xd7, 0xff, 0, 0, 0, 4, 0, 0, 0, 1], encoded(|w| encode_timestamp(w, 1, 1)));
+        assert_eq!(vec![0xc7, 12, 0xff, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+                   encoded(|w| encode_timestamp(w, -1, 0)));
+        assert!(encode_timestamp(&mut Vec::new(), 0, 1_000_000_000).is_err());
+    }
+
+    #[test]
+    fn test_checked_len() {
+        assert_eq!(::std::u32::MAX, checked_len(::std::u32::MAX as usize).unwrap());
+        if ::std::mem::size_of::<usize>() > 4 {
+            assert!(checked_len(::std::u32::MAX as usize + 1).is_err());
+        }
+    }
+}
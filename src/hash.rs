@@ -0,0 +1,112 @@
+//! Hashing of the canonical msgpack encoding of values.
+
+use std::hash::Hasher;
+use std::io::{self, Write};
+use rustc_serialize::Encodable;
+use super::{Encoder, MsgpackResult};
+
+/// Feeds the canonical encoding of values into a `Hasher` as it is
+/// produced, without building the encoded buffer. Only the entries of maps
+/// are buffered, as they have to be sorted.
+pub struct HashingEncoder<H: Hasher> {
+    hasher: H
+}
+
+impl<H: Hasher> HashingEncoder<H> {
+    pub fn new(hasher: H) -> HashingEncoder<H> {
+        HashingEncoder { hasher: hasher }
+    }
+
+    /// Hashes the canonical encoding of `t`.
+    pub fn encode<T: Encodable>(&mut self, t: &T) -> MsgpackResult<()> {
+        let mut encoder = Encoder::new(self as &mut Write);
+        encoder.set_canonical(true);
+        t.encode(&mut encoder)
+    }
+
+    /// Returns the hash of everything encoded so far.
+    pub fn finish(&self) -> u64 {
+        self.hasher.finish()
+    }
+
+    pub fn into_inner(self) -> H {
+        self.hasher
+    }
+}
+
+impl<H: Hasher> Write for HashingEncoder<H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The 64-bit FNV-1a hash. Unlike the hashers in `std`, its output is
+/// guaranteed to stay the same across Rust versions and platforms.
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::hash::Hasher;
+    use super::{HashingEncoder, FnvHasher};
+    use super::super::Encoder;
+
+    fn hash_of<T: ::rustc_serialize::Encodable>(t: &T) -> u64 {
+        let mut encoder = HashingEncoder::new(FnvHasher::default());
+        encoder.encode(t).unwrap();
+        encoder.finish()
+    }
+
+    #[test]
+    fn test_streamed_hash() {
+        let mut inner = HashMap::new();
+        inner.insert(300u32, "x".to_string());
+        inner.insert(1, "y".to_string());
+        let mut v = HashMap::new();
+        v.insert("b".to_string(), vec![inner.clone(), HashMap::new()]);
+        v.insert("a".to_string(), vec![inner]);
+
+        let mut hasher = FnvHasher::default();
+        hasher.write(&Encoder::to_msgpack_canonical(&v).unwrap());
+        assert_eq!(hasher.finish(), hash_of(&v));
+    }
+
+    #[test]
+    fn test_hash_map_order() {
+        // the same entries, inserted in opposite orders into maps of
+        // different capacities, so that they iterate differently
+        let mut a = HashMap::new();
+        let mut b = HashMap::with_capacity(1000);
+        for i in 0 .. 100u32 {
+            a.insert(i, vec![i; (i % 3) as usize]);
+            b.insert(99 - i, vec![99 - i; ((99 - i) % 3) as usize]);
+        }
+        assert!(a.iter().zip(b.iter()).any(|(x, y)| x.0 != y.0));
+        assert_eq!(hash_of(&a), hash_of(&b));
+        b.insert(100, vec![]);
+        assert!(hash_of(&a) != hash_of(&b));
+    }
+}
@@ -0,0 +1,390 @@
+//! Conversion between `Value` and `rustc_serialize::json::Json`.
+//!
+//! JSON has no counterpart for `bin`, `ext`, non-string map keys and
+//! integers beyond the precision of a double, so `JsonOptions` decides how
+//! these are converted. Strings that are not valid UTF-8 are converted
+//! lossily. The conversion back with `Value::from_json` is the plain
+//! mapping and does not undo these policies; `Value::from_json_with` undoes
+//! them as far as the JSON allows.
+
+use std::collections::BTreeMap;
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::Json;
+use super::{Value, MsgpackResult, _invalid_input};
+
+// 2^53 - 1, the largest integer that JavaScript and most other JSON
+// consumers represent exactly.
+const MAX_SAFE_INTEGER: u64 = 9007199254740991;
+
+/// How `bin` values (and the data of `ext` values) are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinPolicy {
+    /// A string in standard base64 with padding
+    Base64,
+    /// An array of byte values
+    Array
+}
+
+/// How map keys other than strings are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPolicy {
+    /// Use the JSON text of the converted key, e.g. `1` or `[1,2]`. Keys
+    /// that end up the same after conversion keep the last value.
+    Stringify,
+    Error
+}
+
+/// How `ext` values are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtPolicy {
+    /// `{"type": type, "data": data}`
+    Object,
+    /// `[type, data]`
+    Array,
+    Error
+}
+
+/// How integers beyond +/-(2^53 - 1) are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BigIntPolicy {
+    /// An exact JSON number; some consumers will lose precision
+    Number,
+    /// The nearest double
+    Double,
+    /// A string with the decimal digits
+    String,
+    Error
+}
+
+/// Policies for `Value::to_json_with`. The default never fails: `Base64`,
+/// `Stringify`, `Object` and `Number`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonOptions {
+    pub bin: BinPolicy,
+    pub keys: KeyPolicy,
+    pub ext: ExtPolicy,
+    pub big_ints: BigIntPolicy
+}
+
+impl Default for JsonOptions {
+    fn default() -> JsonOptions {
+        JsonOptions {
+            bin: BinPolicy::Base64,
+            keys: KeyPolicy::Stringify,
+            ext: ExtPolicy::Object,
+            big_ints: BigIntPolicy::Number
+        }
+    }
+}
+
+fn bin_to_json(data: &[u8], opts: &JsonOptions) -> Json {
+    match opts.bin {
+        BinPolicy::Base64 => Json::String(data.to_base64(STANDARD)),
+        BinPolicy::Array => Json::Array(data.iter().map(|&b| Json::U64(b as u64)).collect())
+    }
+}
+
+fn big_int_to_json(exact: Json, approx: f64, opts: &JsonOptions) -> MsgpackResult<Json> {
+    match opts.big_ints {
+        BigIntPolicy::Number => Ok(exact),
+        BigIntPolicy::Double => Ok(Json::F64(approx)),
+        BigIntPolicy::String => Ok(Json::String(exact.to_string())),
+        BigIntPolicy::Error => Err(_invalid_input("Integer out of JSON range"))
+    }
+}
+
+// The bytes of `data` converted by `bin_to_json`, if it is the exact output
+// of it. Empty strings are taken as text.
+fn bin_from_json(data: &Json, opts: &JsonOptions) -> Option<Vec<u8>> {
+    match (data, opts.bin) {
+        (&Json::String(ref s), BinPolicy::Base64) if !s.is_empty() => {
+            s.from_base64().ok().and_then(|b| if b.to_base64(STANDARD) == *s { Some(b) } else { None })
+        }
+        (&Json::Array(ref a), BinPolicy::Array) => {
+            a.iter().map(|b| match *b {
+                Json::U64(b) if b <= 0xff => Some(b as u8),
+                _ => None
+            }).collect()
+        }
+        _ => None
+    }
+}
+
+fn ext_from_json(json: &Json, opts: &JsonOptions) -> Option<Value> {
+    let (typ, data) = match (json, opts.ext) {
+        (&Json::Object(ref o), ExtPolicy::Object) if o.len() == 2 => {
+            match (o.get("type"), o.get("data")) {
+                (Some(typ), Some(data)) => (typ, data),
+                _ => return None
+            }
+        }
+        (&Json::Array(ref a), ExtPolicy::Array) if a.len() == 2 => (&a[0], &a[1]),
+        _ => return None
+    };
+    let typ = match *typ {
+        Json::U64(t) if t <= 127 => t as i8,
+        Json::I64(t) if t >= -128 && t < 0 => t as i8,
+        _ => return None
+    };
+    bin_from_json(data, opts).map(|data| Value::Extended(typ, data))
+}
+
+fn str_from_json(s: &str, opts: &JsonOptions) -> Value {
+    match key_from_json(s, opts) {
+        Value::Str(text) => match bin_from_json(&Json::String(s.to_string()), opts) {
+            Some(b) => Value::Binary(b),
+            None => Value::Str(text)
+        },
+        v => v
+    }
+}
+
+// Map keys are not read as base64, so that e.g. `"data"` stays a string.
+fn key_from_json(s: &str, opts: &JsonOptions) -> Value {
+    if opts.big_ints == BigIntPolicy::String {
+        if let Ok(u) = s.parse::<u64>() {
+            if u > MAX_SAFE_INTEGER && u.to_string() == s {
+                return Value::Unsigned(u);
+            }
+        } else if let Ok(i) = s.parse::<i64>() {
+            if i < -(MAX_SAFE_INTEGER as i64) && i.to_string() == s {
+                return Value::Integer(i);
+            }
+        }
+    }
+    Value::Str(s.as_bytes().to_vec())
+}
+
+impl Value {
+    /// Converts to JSON with the default `JsonOptions`.
+    pub fn to_json(&self) -> Json {
+        self.to_json_with(&JsonOptions::default()).unwrap()
+    }
+
+    /// Converts to JSON, failing only where `opts` asks for an error.
+    pub fn to_json_with(&self, opts: &JsonOptions) -> MsgpackResult<Json> {
+        Ok(match *self {
+            Value::Nil => Json::Null,
+            Value::Boolean(b) => Json::Boolean(b),
+            Value::Unsigned(u) if u > MAX_SAFE_INTEGER => {
+                return big_int_to_json(Json::U64(u), u as f64, opts);
+            }
+            Value::Unsigned(u) => Json::U64(u),
+            Value::Integer(i) if i < -(MAX_SAFE_INTEGER as i64) || i > MAX_SAFE_INTEGER as i64 => {
+                let exact = if i < 0 { Json::I64(i) } else { Json::U64(i as u64) };
+                return big_int_to_json(exact, i as f64, opts);
+            }
+            Value::Integer(i) if i < 0 => Json::I64(i),
+            Value::Integer(i) => Json::U64(i as u64),
+            Value::Float(f) => Json::F64(f as f64),
+            Value::Double(f) => Json::F64(f),
+            Value::Str(ref s) => Json::String(String::from_utf8_lossy(s).into_owned()),
+            Value::Binary(ref b) => bin_to_json(b, opts),
+            Value::Array(ref a) => {
+                let mut out = Vec::with_capacity(a.len());
+                for v in a {
+                    out.push(try!(v.to_json_with(opts)));
+                }
+                Json::Array(out)
+            }
+            Value::Map(ref m) => {
+                let mut out = BTreeMap::new();
+                for &(ref k, ref v) in m {
+                    let key = match (try!(k.to_json_with(opts)), opts.keys) {
+                        (Json::String(s), _) => s,
+                        (other, KeyPolicy::Stringify) => other.to_string(),
+                        (_, KeyPolicy::Error) => return Err(_invalid_input("Non-string map key"))
+                    };
+                    out.insert(key, try!(v.to_json_with(opts)));
+                }
+                Json::Object(out)
+            }
+            Value::Extended(typ, ref data) => {
+                let typ = if typ < 0 { Json::I64(typ as i64) } else { Json::U64(typ as u64) };
+                match opts.ext {
+                    ExtPolicy::Object => {
+                        let mut out = BTreeMap::new();
+                        out.insert("type".to_string(), typ);
+                        out.insert("data".to_string(), bin_to_json(data, opts));
+                        Json::Object(out)
+                    }
+                    ExtPolicy::Array => Json::Array(vec![typ, bin_to_json(data, opts)]),
+                    ExtPolicy::Error => return Err(_invalid_input("Ext value in JSON"))
+                }
+            }
+        })
+    }
+
+    /// Converts from JSON. Non-negative integers become `Unsigned`,
+    /// negative ones `Integer` and all other numbers `Double`. See
+    /// `from_json_with` to undo the policies of `to_json_with`.
+    pub fn from_json(json: &Json) -> Value {
+        match *json {
+            Json::Null => Value::Nil,
+            Json::Boolean(b) => Value::Boolean(b),
+            Json::U64(u) => Value::Unsigned(u),
+            Json::I64(i) if i >= 0 => Value::Unsigned(i as u64),
+            Json::I64(i) => Value::Integer(i),
+            Json::F64(f) => Value::Double(f),
+            Json::String(ref s) => Value::Str(s.as_bytes().to_vec()),
+            Json::Array(ref a) => Value::Array(a.iter().map(Value::from_json).collect()),
+            Json::Object(ref o) => {
+                Value::Map(o.iter().map(|(k, v)| (Value::from(&k[..]), Value::from_json(v))).collect())
+            }
+        }
+    }
+
+    /// Converts from JSON like `from_json`, but undoes what `to_json_with`
+    /// did with the same `opts`:
+    ///
+    /// * With `BinPolicy::Base64`, every non-empty string value in padded
+    ///   standard base64 becomes `Binary`, but map keys stay strings. With
+    ///   `BinPolicy::Array`, only the data of ext values is read as bytes.
+    /// * Objects (or arrays) of the shape `ExtPolicy` produces, with a type
+    ///   in the `i8` range, become `Extended`.
+    /// * With `KeyPolicy::Stringify`, keys that are the JSON text of
+    ///   another value become that value.
+    /// * With `BigIntPolicy::String`, strings with the decimal digits of an
+    ///   integer beyond +/-(2^53 - 1) become that integer, and with
+    ///   `BigIntPolicy::Error` such integers fail.
+    ///
+    /// JSON does not record which of these were plain strings, arrays or
+    /// maps to begin with, so such values are converted as well.
+    pub fn from_json_with(json: &Json, opts: &JsonOptions) -> MsgpackResult<Value> {
+        if let Some(ext) = ext_from_json(json, opts) {
+            return Ok(ext);
+        }
+        Ok(match *json {
+            Json::U64(u) if u > MAX_SAFE_INTEGER && opts.big_ints == BigIntPolicy::Error => {
+                return Err(_invalid_input("Integer out of JSON range"));
+            }
+            Json::I64(i) if i < -(MAX_SAFE_INTEGER as i64) && opts.big_ints == BigIntPolicy::Error => {
+                return Err(_invalid_input("Integer out of JSON range"));
+            }
+            Json::String(ref s) => str_from_json(s, opts),
+            Json::Array(ref a) => {
+                let mut out = Vec::with_capacity(a.len());
+                for v in a {
+                    out.push(try!(Value::from_json_with(v, opts)));
+                }
+                Value::Array(out)
+            }
+            Json::Object(ref o) => {
+                let mut out = Vec::with_capacity(o.len());
+                for (k, v) in o {
+                    let key = match (opts.keys, Json::from_str(k)) {
+                        (KeyPolicy::Stringify, Ok(ref j)) if !j.is_string() && j.to_string() == *k => {
+                            try!(Value::from_json_with(j, opts))
+                        }
+                        _ => key_from_json(k, opts)
+                    };
+                    out.push((key, try!(Value::from_json_with(v, opts))));
+                }
+                Value::Map(out)
+            }
+            _ => Value::from_json(json)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::json::Json;
+    use super::super::Value;
+    use super::{JsonOptions, BinPolicy, KeyPolicy, ExtPolicy, BigIntPolicy};
+
+    fn json(s: &str) -> Json {
+        Json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_to_json_default() {
+        let v = msgpack!({
+            "a": [1u8, -2i8, 1.5f32, null, true],
+            "b": bin([0, 1, 2]),
+            "c": ext(5, [0xff]),
+            1u8: "x",
+            [1u8, 2u8]: "y"
+        });
+        assert_eq!(json(r#"{"a": [1, -2, 1.5, null, true], "b": "AAEC", "c": {"type": 5, "data": "/w=="},
+                            "1": "x", "[1,2]": "y"}"#), v.to_json());
+        assert_eq!(Json::String("a\u{fffd}".to_string()), Value::Str(vec![b'a', 0xff]).to_json());
+    }
+
+    #[test]
+    fn test_to_json_policies() {
+        let opts = JsonOptions { bin: BinPolicy::Array, ext: ExtPolicy::Array, .. JsonOptions::default() };
+        assert_eq!(json("[[1, 2], [-1, [3]]]"), msgpack!([bin([1, 2]), ext(-1, [3])]).to_json_with(&opts).unwrap());
+
+        let opts = JsonOptions { ext: ExtPolicy::Error, .. JsonOptions::default() };
+        assert!(msgpack!([ext(1, [])]).to_json_with(&opts).is_err());
+
+        let opts = JsonOptions { keys: KeyPolicy::Error, .. JsonOptions::default() };
+        assert!(msgpack!({"a": 1}).to_json_with(&opts).is_ok());
+        assert!(msgpack!({1: "a"}).to_json_with(&opts).is_err());
+    }
+
+    #[test]
+    fn test_to_json_big_ints() {
+        let big = msgpack!([9007199254740991u64, 18446744073709551615u64, -9007199254740992i64]);
+        assert_eq!(Json::Array(vec![Json::U64(9007199254740991), Json::U64(18446744073709551615),
+                                    Json::I64(-9007199254740992)]), big.to_json());
+
+        let opts = JsonOptions { big_ints: BigIntPolicy::String, .. JsonOptions::default() };
+        assert_eq!(json(r#"[9007199254740991, "18446744073709551615", "-9007199254740992"]"#),
+                   big.to_json_with(&opts).unwrap());
+
+        let opts = JsonOptions { big_ints: BigIntPolicy::Double, .. JsonOptions::default() };
+        assert_eq!(Json::F64(18446744073709551616.0), Value::Unsigned(18446744073709551615).to_json_with(&opts).unwrap());
+
+        let opts = JsonOptions { big_ints: BigIntPolicy::Error, .. JsonOptions::default() };
+        assert!(Value::Unsigned(9007199254740991).to_json_with(&opts).is_ok());
+        assert!(Value::Integer(9007199254740992).to_json_with(&opts).is_err());
+    }
+
+    #[test]
+    fn test_from_json() {
+        let j = json(r#"{"a": [1, -2, 2.5, null, false, "s"], "b": {}}"#);
+        assert_eq!(msgpack!({"a": [1u8, -2i8, 2.5, null, false, "s"], "b": {}}), Value::from_json(&j));
+        assert_eq!(j, Value::from_json(&j).to_json());
+    }
+
+    fn round_trip(v: &Value, opts: &JsonOptions) {
+        let back = Value::from_json_with(&v.to_json_with(opts).unwrap(), opts).unwrap();
+        assert_eq!(format!("{:?}", v), format!("{:?}", back));
+    }
+
+    #[test]
+    fn test_from_json_with() {
+        // in the order of the JSON object keys
+        let v = msgpack!({
+            1: "some text",
+            [1, -2]: "",
+            "a": [1, -2, 1.5, null, true, "text!"],
+            "b": bin([0, 1, 2]),
+            "c": ext(5, [0xff]),
+            "d": [ext(-1, [0, 0, 0, 1]), {"type": "x"}],
+            true: {}
+        });
+        round_trip(&v, &JsonOptions::default());
+        let opts = JsonOptions { bin: BinPolicy::Array, ext: ExtPolicy::Array, .. JsonOptions::default() };
+        round_trip(&msgpack!({1: ext(-1, []), "a": ext(1, [2, 3]), "b": [1, 2]}), &opts);
+
+        let big = msgpack!(["9007199254740991", 18446744073709551615u64, -9007199254740992i64]);
+        let opts = JsonOptions { bin: BinPolicy::Array, big_ints: BigIntPolicy::String, .. JsonOptions::default() };
+        round_trip(&big, &opts);
+
+        let opts = JsonOptions { big_ints: BigIntPolicy::Error, .. JsonOptions::default() };
+        assert!(Value::from_json_with(&json("[9007199254740991]"), &opts).is_ok());
+        assert!(Value::from_json_with(&json("[9007199254740992]"), &opts).is_err());
+
+        // the plain mapping leaves all of these alone
+        let j = json(r#"{"1": "AAEC", "e": {"type": 5, "data": "/w=="}}"#);
+        assert_eq!(msgpack!({"1": "AAEC", "e": {"type": 5, "data": "/w=="}}), Value::from_json(&j));
+        assert_eq!(msgpack!({1: bin([0, 1, 2]), "e": ext(5, [0xff])}),
+                   Value::from_json_with(&j, &JsonOptions::default()).unwrap());
+        let opts = JsonOptions { keys: KeyPolicy::Error, ext: ExtPolicy::Error, .. JsonOptions::default() };
+        assert_eq!(msgpack!({"1": bin([0, 1, 2]), "e": {"type": 5, "data": bin([0xff])}}),
+                   Value::from_json_with(&j, &opts).unwrap());
+    }
+}
@@ -12,18 +12,51 @@ use std::io::ErrorKind;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::str::from_utf8;
 use std::mem;
+use std::cmp;
 
 use rustc_serialize::{Encodable, Decodable};
 
 pub type MsgpackResult<T> = Result<T, byteorder::Error>;
 
+#[macro_use]
+#[doc(hidden)]
+pub mod macros;
+
 pub mod slice_reader;
 pub mod encoder;
-
-#[cfg(todo)]
-mod rpc;
-
-#[derive(Debug)]
+mod bytes;
+mod value;
+pub mod notation;
+pub mod json;
+pub mod transcode;
+pub mod hash;
+
+pub use bytes::{Bytes, ByteBuf};
+pub use hash::HashingEncoder;
+
+pub mod rpc;
+
+/// A dynamically typed msgpack value.
+///
+/// Values are totally ordered by kind first:
+///
+/// `Nil < Boolean < integers < floats < Str < Binary < Array < Map < Extended`
+///
+/// and then by content:
+///
+/// * `Integer` and `Unsigned` form one kind and compare by their numeric
+///   value, so `Integer(1) == Unsigned(1)`.
+/// * `Float` and `Double` form one kind and compare by their numeric value,
+///   so `Float(0.5) == Double(0.5)` and `-0.0 == 0.0`. All NaNs are equal to
+///   each other and greater than every other float.
+/// * `Str` and `Binary` compare their bytes lexicographically.
+/// * `Array` compares its elements lexicographically.
+/// * `Map` compares its entries lexicographically after sorting them, so the
+///   order of the entries does not matter.
+/// * `Extended` compares the type first, then the data.
+///
+/// `Hash` is consistent with this equality.
+#[derive(Debug, Clone)]
 pub enum Value {
     Nil,
     Boolean(bool),
@@ -38,6 +71,18 @@ pub enum Value {
     Extended(i8, Vec<u8>)
 }
 
+impl Value {
+    /// Hashes the canonical encoding of the value with `hash::FnvHasher`, so
+    /// equal values hash equally regardless of map order, across runs and
+    /// platforms. Fails like the `Encoder` for lengths beyond `u32`, which
+    /// msgpack cannot represent.
+    pub fn content_hash(&self) -> MsgpackResult<u64> {
+        let mut encoder = HashingEncoder::new(hash::FnvHasher::default());
+        try!(encoder.encode(self));
+        Ok(encoder.finish())
+    }
+}
+
 #[inline]
 fn read_float(rd: &mut Read) -> MsgpackResult<f32> {
     rd.read_u32::<BigEndian>().map(|v| unsafe { mem::transmute(v) })
@@ -48,15 +93,110 @@ fn read_double(rd: &mut Read) -> MsgpackResult<f64> {
     rd.read_u64::<BigEndian>().map(|v| unsafe { mem::transmute(v) })
 }
 
+// Upper bound for preallocating containers from untrusted length prefixes.
+const MAX_PREALLOC: usize = 4096;
+
+// 2^64 and 2^63, the first integers beyond the range of u64 and i64.
+const TWO_POW_64: f64 = 18446744073709551616.0;
+const TWO_POW_63: f64 = 9223372036854775808.0;
+
+#[inline]
+fn u64_to_f64_exact(v: u64) -> Option<f64> {
+    let f = v as f64;
+    if f < TWO_POW_64 && f as u64 == v { Some(f) } else { None }
+}
+
+#[inline]
+fn i64_to_f64_exact(v: i64) -> Option<f64> {
+    let f = v as f64;
+    if f < TWO_POW_63 && f as i64 == v { Some(f) } else { None }
+}
+
+#[inline]
+fn f64_to_u64_exact(v: f64) -> Option<u64> {
+    if v >= 0.0 && v < TWO_POW_64 && v.trunc() == v { Some(v as u64) } else { None }
+}
+
+#[inline]
+fn f64_to_i64_exact(v: f64) -> Option<i64> {
+    if v >= -TWO_POW_63 && v < TWO_POW_63 && v.trunc() == v { Some(v as i64) } else { None }
+}
+
 pub fn _invalid_input(s: &'static str) -> byteorder::Error {
     let err = io::Error::new(ErrorKind::InvalidInput, s);
     byteorder::Error::Io(err)
 }
 
+/// How forgiving a `Decoder` is about the markers it accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    /// Only accept the markers the `Encoder` would produce for a type.
+    /// Signed integers may use the unsigned formats, as written by a
+    /// canonical `Encoder`, if the value fits.
+    Strict,
+    /// Also accept compatible encodings, e.g. `str` or an array of
+    /// integers where `bin` is expected. Numbers are converted between
+    /// signed, unsigned and float as long as no precision is lost.
+    Lenient,
+    /// Like `Strict`, but `decode_value` additionally rejects every encoding
+    /// that is not the shortest one (as written by the functions in
+    /// `encoder`), as well as maps with duplicate keys.
+    Canonical
+}
+
 /// A structure to decode Msgpack from a reader.
 pub struct Decoder<R: Read> {
+    rd: Source<R>,
+    next_byte: Option<u8>,
+    strictness: Strictness,
+    // set while decoding one of the structs in `bytes`
+    bin: Option<RawSeq>,
+    // set while the elements of a `ByteBuf` are plain bytes
+    raw: bool,
+    // set by `read_enum` for a `Value`
+    value: bool,
+    // the structs being decoded, innermost last
+    structs: Vec<StructFields>
+}
+
+// What a `Decoder` reads from: the reader, or the bytes of a struct field
+// that was read ahead.
+struct Source<R> {
     rd: R,
-    next_byte: Option<u8>
+    // the fields being decoded from read ahead bytes, innermost last
+    ahead: Vec<io::Cursor<Vec<u8>>>,
+    // set while reading a value ahead, collects the bytes read
+    copy: Option<Vec<u8>>
+}
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.ahead.last_mut() {
+            Some(field) => try!(field.read(buf)),
+            None        => try!(self.rd.read(buf))
+        };
+        if let Some(ref mut copy) = self.copy {
+            copy.extend_from_slice(&buf[.. n]);
+        }
+        Ok(n)
+    }
+}
+
+// A struct being decoded. Its fields are read in order as long as their
+// names match; once one does not, the rest of them are read ahead.
+struct StructFields {
+    // the number of entries not read yet
+    remaining: usize,
+    ahead: Option<Vec<(String, Vec<u8>)>>
+}
+
+// What the sequence inside one of the structs in `bytes` is read from.
+#[derive(Clone, Copy)]
+enum RawSeq {
+    Bin,
+    Str,
+    // type and length, read with the struct
+    Ext(i8, usize)
 }
 
 impl<R: Read> Decoder<R> {
@@ -64,10 +204,23 @@ impl<R: Read> Decoder<R> {
     /// specified reader.
     pub fn new(rd: R) -> Decoder<R> {
         Decoder {
-            rd: rd,
-            next_byte: None
+            rd: Source { rd: rd, ahead: Vec::new(), copy: None },
+            next_byte: None,
+            strictness: Strictness::Strict,
+            bin: None,
+            raw: false,
+            value: false,
+            structs: Vec::new()
         }
     }
+
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    pub fn strictness(&self) -> Strictness {
+        self.strictness
+    }
 }
 
 impl<'a, R: Read> Decoder<R> {
@@ -99,8 +252,99 @@ impl<'a, R: Read> Decoder<R> {
         }
     }
 
+    // Reads the remaining entries of the innermost struct, starting with the
+    // value of `key`, so that its fields can be decoded in any order.
+    fn _read_fields_ahead(&mut self, key: String) -> MsgpackResult<()> {
+        let remaining = match self.structs.last() {
+            Some(s) => s.remaining,
+            None    => return Err(_invalid_input("struct field name mismatch"))
+        };
+        let mut ahead = Vec::with_capacity(cmp::min(remaining, MAX_PREALLOC));
+        ahead.push((key, try!(self._read_value_ahead())));
+        for _ in 1 .. remaining {
+            let key = try!(rustc_serialize::Decoder::read_str(self));
+            ahead.push((key, try!(self._read_value_ahead())));
+        }
+        let s = self.structs.last_mut().unwrap();
+        s.remaining = 0;
+        s.ahead = Some(ahead);
+        Ok(())
+    }
+
+    // Reads the next value and returns its bytes.
+    fn _read_value_ahead(&mut self) -> MsgpackResult<Vec<u8>> {
+        self._read_copied(|d| d.decode_value()).map(|(_, bytes)| bytes)
+    }
+
+    // Runs `f` and returns its result together with the bytes it read. The
+    // copies nest: an outer one has already collected the peeked byte.
+    fn _read_copied<T, F>(&mut self, f: F) -> MsgpackResult<(T, Vec<u8>)>
+        where F: FnOnce(&mut Self) -> MsgpackResult<T>
+    {
+        let start = match self.rd.copy {
+            Some(ref copy) => Some(copy.len() - self.next_byte.iter().count()),
+            None           => {
+                self.rd.copy = Some(self.next_byte.into_iter().collect());
+                None
+            }
+        };
+        let res = f(self);
+        let bytes = match start {
+            Some(start) => self.rd.copy.as_ref().unwrap()[start ..].to_vec(),
+            None        => self.rd.copy.take().unwrap()
+        };
+        res.map(|v| (v, bytes))
+    }
+
+    #[inline]
+    fn _lenient(&self) -> bool {
+        self.strictness == Strictness::Lenient
+    }
+
     fn _read_unsigned(&mut self) -> MsgpackResult<u64> {
         let c = try!(self._read_byte());
+        match c {
+            0x00 ... 0x7f | 0xcc ... 0xcf => self._read_unsigned_marker(c),
+            0xd0 ... 0xd3 | 0xe0 ... 0xff if self._lenient() => {
+                let v = try!(self._read_signed_marker(c));
+                if v >= 0 {
+                    Ok(v as u64)
+                } else {
+                    Err(_invalid_input("negative value for unsigned integer"))
+                }
+            }
+            0xca | 0xcb if self._lenient() => {
+                let v = try!(self._read_float_marker(c));
+                f64_to_u64_exact(v).ok_or(_invalid_input("float is not an unsigned integer"))
+            }
+            _            => Err(_invalid_input("No unsigned integer"))
+        }
+    }
+
+    fn _read_signed(&mut self) -> MsgpackResult<i64> {
+        let c = try!(self._read_byte());
+        match c {
+            0xd0 ... 0xd3 | 0xe0 ... 0xff => self._read_signed_marker(c),
+            // canonical data has non-negative integers in unsigned formats,
+            // so they are accepted at every strictness
+            0x00 ... 0x7f | 0xcc ... 0xcf => {
+                let v = try!(self._read_unsigned_marker(c));
+                if v <= std::i64::MAX as u64 {
+                    Ok(v as i64)
+                } else {
+                    Err(_invalid_input("value does not fit inside i64"))
+                }
+            }
+            0xca | 0xcb if self._lenient() => {
+                let v = try!(self._read_float_marker(c));
+                f64_to_i64_exact(v).ok_or(_invalid_input("float is not a signed integer"))
+            }
+            _            => Err(_invalid_input("No signed integer"))
+        }
+    }
+
+    // Reads the unsigned integer introduced by the already consumed marker `c`.
+    fn _read_unsigned_marker(&mut self, c: u8) -> MsgpackResult<u64> {
         match c {
             0x00 ... 0x7f => Ok(c as u64),
             0xcc         => Ok(try!(self.rd.read_u8()) as u64),
@@ -111,8 +355,8 @@ impl<'a, R: Read> Decoder<R> {
         }
     }
 
-    fn _read_signed(&mut self) -> MsgpackResult<i64> {
-        let c = try!(self._read_byte());
+    // Reads the signed integer introduced by the already consumed marker `c`.
+    fn _read_signed_marker(&mut self, c: u8) -> MsgpackResult<i64> {
         match c {
             0xd0         => Ok(try!(self.rd.read_i8()) as i64),
             0xd1         => Ok(try!(self.rd.read_i16::<BigEndian>()) as i64),
@@ -123,23 +367,29 @@ impl<'a, R: Read> Decoder<R> {
         }
     }
 
+    // Reads the float introduced by the already consumed marker `c`.
+    fn _read_float_marker(&mut self, c: u8) -> MsgpackResult<f64> {
+        match c {
+            0xca         => read_float(&mut self.rd).map(|v| v as f64),
+            0xcb         => read_double(&mut self.rd),
+            _            => Err(_invalid_input("No float"))
+        }
+    }
+
+    // Reads any integer as f64, failing if it cannot be represented exactly.
+    fn _read_integer_as_f64(&mut self, c: u8) -> MsgpackResult<f64> {
+        let v = match c {
+            0x00 ... 0x7f | 0xcc ... 0xcf => u64_to_f64_exact(try!(self._read_unsigned_marker(c))),
+            _ => i64_to_f64_exact(try!(self._read_signed_marker(c)))
+        };
+        v.ok_or(_invalid_input("value does not fit inside f64"))
+    }
+
     fn _read_raw(&mut self, len: usize) -> MsgpackResult<Vec<u8>> {
-        // XXX Don't have: self.rd.read_exact(len)
-        // XXX Should probably be optimized.
-
-        let mut vec = Vec::new();
-        let mut nread = 0;
-        let mut buf = [0; 1];
-        while nread < len {
-            match self.rd.read(&mut buf) {
-                Ok(0) => return Err(byteorder::Error::UnexpectedEOF),
-                Ok(n) => {
-                    nread += n;
-                    vec.push(buf[0]);
-                },
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
-                Err(e) => return Err(From::from(e)),
-            }
+        let mut vec = Vec::with_capacity(cmp::min(len, MAX_PREALLOC));
+        try!((&mut self.rd).take(len as u64).read_to_end(&mut vec));
+        if vec.len() < len {
+            return Err(byteorder::Error::UnexpectedEOF);
         }
         Ok(vec)
     }
@@ -151,6 +401,17 @@ impl<'a, R: Read> Decoder<R> {
         }
     }
 
+    fn _read_str_len(&mut self) -> MsgpackResult<usize> {
+        let c = try!(self._read_byte());
+        match c {
+            0xa0 ... 0xbf => Ok((c as usize) & 0x1F),
+            0xd9         => self.rd.read_u8().map(|i| i as usize),
+            0xda         => self.rd.read_u16::<BigEndian>().map(|i| i as usize),
+            0xdb         => self.rd.read_u32::<BigEndian>().map(|i| i as usize),
+            _            => Err(_invalid_input("Invalid string"))
+        }
+    }
+
     fn _read_vec_len(&mut self) -> MsgpackResult<usize> {
         let c = try!(self._read_byte());
 
@@ -172,8 +433,54 @@ impl<'a, R: Read> Decoder<R> {
         }
     }
 
+    // Returns the length of a bin value and whether its elements are raw
+    // bytes. In lenient mode, `str` and arrays are accepted as well.
+    fn _read_bin_len(&mut self) -> MsgpackResult<(usize, bool)> {
+        let c = try!(self._peek_byte());
+        match c {
+            0xc4 | 0xc5 | 0xc6 => {
+                try!(self._read_byte());
+                let len = match c {
+                    0xc4 => try!(self.rd.read_u8()) as usize,
+                    0xc5 => try!(self.rd.read_u16::<BigEndian>()) as usize,
+                    _    => try!(self.rd.read_u32::<BigEndian>()) as usize
+                };
+                Ok((len, true))
+            }
+            0xa0 ... 0xbf | 0xd9 | 0xda | 0xdb if self.strictness == Strictness::Lenient => {
+                try!(self._read_byte());
+                let len = match c {
+                    0xd9 => try!(self.rd.read_u8()) as usize,
+                    0xda => try!(self.rd.read_u16::<BigEndian>()) as usize,
+                    0xdb => try!(self.rd.read_u32::<BigEndian>()) as usize,
+                    _    => (c as usize) & 0x1F
+                };
+                Ok((len, true))
+            }
+            0x90 ... 0x9f | 0xdc | 0xdd if self.strictness == Strictness::Lenient => {
+                self._read_vec_len().map(|len| (len, false))
+            }
+            _ => Err(_invalid_input("Invalid byte code in _read_bin_len"))
+        }
+    }
+
+    /// Reads a msgpack `bin` value. In lenient mode, `str` values and arrays
+    /// of `u8` are accepted as well.
+    pub fn read_bin(&mut self) -> MsgpackResult<Vec<u8>> {
+        let (len, raw) = try!(self._read_bin_len());
+        if raw {
+            self._read_raw(len)
+        } else {
+            let mut v = Vec::with_capacity(cmp::min(len, MAX_PREALLOC));
+            for _ in 0 .. len {
+                v.push(try!(rustc_serialize::Decoder::read_u8(self)));
+            }
+            Ok(v)
+        }
+    }
+
     fn decode_array(&mut self, len: usize) -> MsgpackResult<Value> {
-        let mut v = Vec::with_capacity(len);
+        let mut v = Vec::with_capacity(cmp::min(len, MAX_PREALLOC));
         for _ in 0 .. len {
             v.push(try!(self.decode_value()));
         }
@@ -181,24 +488,94 @@ impl<'a, R: Read> Decoder<R> {
     }
 
     fn decode_map(&mut self, len: usize) -> MsgpackResult<Value> {
-        let mut v = Vec::with_capacity(len);
+        let mut v = Vec::with_capacity(cmp::min(len, MAX_PREALLOC));
+        // In canonical mode the keys are compared by the bytes they were
+        // read from, as `Ord` for `Value` treats e.g. `Float(0.5)` and
+        // `Double(0.5)` as equal, and canonical maps are sorted by them.
+        let mut prev: Option<Vec<u8>> = None;
         for _ in 0 .. len {
-            let a = try!(self.decode_value());
+            let a = if self.strictness == Strictness::Canonical {
+                let (a, key) = try!(self._read_copied(|d| d.decode_value()));
+                match prev {
+                    Some(ref p) if *p == key => return Err(_invalid_input("Duplicate map key")),
+                    Some(ref p) if *p > key  => return Err(_invalid_input("Unsorted map keys")),
+                    _                        => {}
+                }
+                prev = Some(key);
+                a
+            } else {
+                try!(self.decode_value())
+            };
             let b = try!(self.decode_value());
             v.push((a, b));
         }
         Ok(Value::Map(v))
     }
 
+    fn _read_ext_len(&mut self) -> MsgpackResult<usize> {
+        let c = try!(self._read_byte());
+        match c {
+            0xd4         => Ok(1),
+            0xd5         => Ok(2),
+            0xd6         => Ok(4),
+            0xd7         => Ok(8),
+            0xd8         => Ok(16),
+            0xc7         => self.rd.read_u8().map(|i| i as usize),
+            0xc8         => self.rd.read_u16::<BigEndian>().map(|i| i as usize),
+            0xc9         => self.rd.read_u32::<BigEndian>().map(|i| i as usize),
+            _            => Err(_invalid_input("Invalid byte code in _read_ext_len"))
+        }
+    }
+
     fn decode_ext(&mut self, len: usize) -> MsgpackResult<Value> {
+        let typ = try!(self._read_ext_type(len));
+        Ok(Value::Extended(typ, try!(self._read_raw(len))))
+    }
+
+    fn _read_ext_type(&mut self, len: usize) -> MsgpackResult<i8> {
         let typ = try!(self.rd.read_i8());
-        if typ < 0 {
-            return Err(_invalid_input("Reserved type"));
+        try!(_check_ext_type(typ, len));
+        Ok(typ)
+    }
+
+    // The variant of `Value` that the next marker starts.
+    fn _value_variant(&mut self) -> MsgpackResult<usize> {
+        match try!(self._peek_byte()) {
+            0xc0                        => Ok(0),
+            0xc2 | 0xc3                 => Ok(1),
+            0xd0 ... 0xd3 | 0xe0 ... 0xff => Ok(2),
+            0x00 ... 0x7f | 0xcc ... 0xcf => Ok(3),
+            0xca                        => Ok(4),
+            0xcb                        => Ok(5),
+            0x90 ... 0x9f | 0xdc | 0xdd => Ok(6),
+            0x80 ... 0x8f | 0xde | 0xdf => Ok(7),
+            0xa0 ... 0xbf | 0xd9 ... 0xdb => Ok(8),
+            0xc4 ... 0xc6               => Ok(9),
+            0xc7 ... 0xc9 | 0xd4 ... 0xd8 => Ok(10),
+            _                           => Err(_invalid_input("Reserved"))
         }
-        Ok(Value::Extended(typ, try!(self._read_raw(len))))
     }
 
-    fn decode_value(&mut self) -> MsgpackResult<Value> {
+    /// Decodes the next value, whatever its type.
+    pub fn decode_value(&mut self) -> MsgpackResult<Value> {
+        let c = try!(self._peek_byte());
+        let v = try!(self._decode_value());
+        if self.strictness == Strictness::Canonical {
+            try!(_check_canonical(c, &v));
+        }
+        Ok(v)
+    }
+
+    // Like `_decode_value`, but only reads the header of arrays and maps.
+    fn _read_token(&mut self) -> MsgpackResult<Token> {
+        match try!(self._peek_byte()) {
+            0x90 ... 0x9f | 0xdc | 0xdd => self._read_vec_len().map(Token::Array),
+            0x80 ... 0x8f | 0xde | 0xdf => self._read_map_len().map(Token::Map),
+            _ => self._decode_value().map(Token::Value)
+        }
+    }
+
+    fn _decode_value(&mut self) -> MsgpackResult<Value> {
         let c = try!(self._read_byte());
         match c {
             0xc0         => Ok(Value::Nil),
@@ -271,11 +648,53 @@ impl<'a, R: Read> Decoder<R> {
             0xc9         => { let l = try!(self.rd.read_u32::<BigEndian>()) as usize; self.decode_ext(l) },
 
             // XXX: This is only here to satify Rust's pattern checker.
-            _            => unreachable!()
+            _            => Err(_invalid_input("Invalid byte code in decode_value"))
         }
     }
 
 
+
+}
+
+// A value that is not an array or map, or the length of one whose elements
+// follow.
+enum Token {
+    Value(Value),
+    Array(usize),
+    Map(usize)
+}
+
+// Checks that `c`, the marker `v` was decoded from, is the one the encoder
+// functions would have chosen.
+fn _check_canonical(c: u8, v: &Value) -> MsgpackResult<()> {
+    let expected = match *v {
+        Value::Integer(n)       => encoder::marker(|w| encoder::encode_signed(w, n)),
+        Value::Unsigned(n)      => encoder::marker(|w| encoder::encode_unsigned(w, n)),
+        Value::Str(ref s)       => encoder::marker(|w| encoder::encode_str_len(w, s.len() as u32)),
+        Value::Binary(ref b)    => encoder::marker(|w| encoder::encode_bin_len(w, b.len() as u32)),
+        Value::Extended(t, ref d) => encoder::marker(|w| encoder::encode_ext_len(w, d.len() as u32, t)),
+        Value::Array(ref a)     => encoder::marker(|w| encoder::encode_vec_len(w, a.len() as u32)),
+        Value::Map(ref m)       => encoder::marker(|w| encoder::encode_map_len(w, m.len() as u32)),
+        _                       => c
+    };
+    if c == expected {
+        Ok(())
+    } else {
+        Err(_invalid_input("Non-canonical encoding"))
+    }
+}
+
+// -1 is the predefined timestamp type, which is 4, 8 or 12 bytes long. The
+// other negative types are reserved.
+fn _check_ext_type(typ: i8, len: usize) -> MsgpackResult<()> {
+    if typ == -1 {
+        if len != 4 && len != 8 && len != 12 {
+            return Err(_invalid_input("Invalid timestamp length"));
+        }
+    } else if typ < 0 {
+        return Err(_invalid_input("Reserved type"));
+    }
+    Ok(())
 }
 
 // Insipired by rust-serialize json code.
@@ -325,7 +744,19 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
     read_uprimitive! { read_usize, usize }
     read_uprimitive! { read_u32, u32 }
     read_uprimitive! { read_u16, u16 }
-    read_uprimitive! { read_u8, u8 }
+
+    #[inline]
+    fn read_u8(&mut self) -> MsgpackResult<u8> {
+        if self.raw {
+            return self._read_byte();
+        }
+        let v = try!(self._read_unsigned());
+        if v > std::u8::MAX as u64 {
+            Err(_invalid_input("value does not fit inside u8"))
+        } else {
+            Ok(v as u8)
+        }
+    }
 
     #[inline]
     fn read_i64(&mut self) -> MsgpackResult<i64> {
@@ -335,7 +766,20 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
     read_iprimitive! { read_isize, isize }
     read_iprimitive! { read_i32, i32 }
     read_iprimitive! { read_i16, i16 }
-    read_iprimitive! { read_i8, i8 }
+
+    #[inline]
+    fn read_i8(&mut self) -> MsgpackResult<i8> {
+        // the type of an `ext` was read with its header
+        if let Some(RawSeq::Ext(typ, _)) = self.bin {
+            return Ok(typ);
+        }
+        let v = try!(self._read_signed());
+        if v < std::i8::MIN as i64 || v > std::i8::MAX as i64 {
+            Err(_invalid_input("value does not fit inside i8"))
+        } else {
+            Ok(v as i8)
+        }
+    }
 
     #[inline]
     fn read_bool(&mut self) -> MsgpackResult<bool> {
@@ -348,16 +792,35 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
 
     #[inline]
     fn read_f64(&mut self) -> MsgpackResult<f64> {
-        match try!(self._read_byte()) {
-            0xcb => read_double(&mut self.rd),
-            _    => Err(_invalid_input("invalid f64"))
+        // Accepts the integer and f32 forms written by an `Encoder` with
+        // compact floats, as long as they convert to f64 without loss.
+        let c = try!(self._read_byte());
+        match c {
+            0xca | 0xcb  => self._read_float_marker(c),
+            0x00 ... 0x7f | 0xcc ... 0xcf |
+            0xd0 ... 0xd3 | 0xe0 ... 0xff => self._read_integer_as_f64(c),
+            _            => Err(_invalid_input("invalid f64"))
         }
     }
 
     #[inline]
     fn read_f32(&mut self) -> MsgpackResult<f32> {
-        match try!(self._read_byte()) {
+        let c = try!(self._read_byte());
+        match c {
             0xca => read_float(&mut self.rd),
+            0xcb | 0x00 ... 0x7f | 0xcc ... 0xcf |
+            0xd0 ... 0xd3 | 0xe0 ... 0xff if self._lenient() => {
+                let v = try!(if c == 0xcb {
+                    self._read_float_marker(c)
+                } else {
+                    self._read_integer_as_f64(c)
+                });
+                if v.is_nan() || (v as f32) as f64 == v {
+                    Ok(v as f32)
+                } else {
+                    Err(_invalid_input("value does not fit inside f32"))
+                }
+            }
             _    => Err(_invalid_input("invalid f32"))
         }
     }
@@ -372,32 +835,24 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
 
     #[inline]
     fn read_str(&mut self) -> MsgpackResult<String> {
-        let c = try!(self._read_byte());
-        match c {
-            0xa0 ... 0xbf => self._read_str((c as usize) & 0x1F),
-            0xd9         => {
-                let l = try!(self.rd.read_u8()) as usize;
-                self._read_str(l)
-            },
-            0xda         => {
-                let l = try!(self.rd.read_u16::<BigEndian>()) as usize;
-                self._read_str(l)
-            },
-            0xdb         => {
-                let l = try!(self.rd.read_u32::<BigEndian>()) as usize;
-                self._read_str(l)
-            },
-            _            => Err(_invalid_input("Invalid string"))
-        }
+        let len = try!(self._read_str_len());
+        self._read_str(len)
     }
 
-    fn read_enum<T,F>(&mut self, _name: &str, f: F) -> MsgpackResult<T>
+    fn read_enum<T,F>(&mut self, name: &str, f: F) -> MsgpackResult<T>
     where F: FnOnce(&mut Decoder<R>) -> MsgpackResult<T> {
+        self.value = name == bytes::VALUE_ENUM_NAME;
         f(self)
     }
 
     fn read_enum_variant<T,F>(&mut self, names: &[&str], mut f: F) -> MsgpackResult<T>
     where F: FnMut(&mut Decoder<R>, usize) -> MsgpackResult<T> {
+        if self.value {
+            // a `Value`, whose variant is given by the marker
+            self.value = false;
+            let idx = try!(self._value_variant());
+            return f(self, idx);
+        }
         let idx = try!(self.read_seq(|d, _len| {
             let name = try!(d.read_str());
             match names.iter().position(|n| &name[..] == *n) {
@@ -416,6 +871,17 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
     #[inline]
     fn read_seq<T,F>(&mut self, f: F) -> MsgpackResult<T>
     where F: FnOnce(&mut Decoder<R>, usize) -> MsgpackResult<T> {
+        if let Some(kind) = self.bin {
+            let (len, raw) = match kind {
+                RawSeq::Bin => try!(self._read_bin_len()),
+                RawSeq::Str => (try!(self._read_str_len()), true),
+                RawSeq::Ext(_, len) => (len, true)
+            };
+            self.raw = raw;
+            let res = f(self, len);
+            self.raw = false;
+            return res;
+        }
         let len = try!(self._read_vec_len());
         f(self, len)
     }
@@ -427,23 +893,61 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
     }
 
     #[inline]
-    fn read_struct<T,F>(&mut self, _name: &str, len: usize, f: F) -> MsgpackResult<T>
+    fn read_struct<T,F>(&mut self, name: &str, len: usize, f: F) -> MsgpackResult<T>
     where F: FnOnce(&mut Decoder<R>) -> MsgpackResult<T> {
-        if len != try!(self._read_map_len()) {
-            Err(_invalid_input("invalid length for struct"))
+        let kind = if name == bytes::BIN_STRUCT_NAME {
+            Some(RawSeq::Bin)
+        } else if name == bytes::STR_STRUCT_NAME {
+            Some(RawSeq::Str)
+        } else if name == bytes::EXT_STRUCT_NAME {
+            let len = try!(self._read_ext_len());
+            Some(RawSeq::Ext(try!(self._read_ext_type(len)), len))
         } else {
-            f(self)
+            None
+        };
+        if kind.is_some() {
+            self.bin = kind;
+            let res = f(self);
+            self.bin = None;
+            return res;
         }
+        if len != try!(self._read_map_len()) {
+            return Err(_invalid_input("invalid length for struct"));
+        }
+        self.structs.push(StructFields { remaining: len, ahead: None });
+        let res = f(self);
+        self.structs.pop();
+        res
     }
 
     #[inline]
     fn read_struct_field<T,F>(&mut self, name: &str, _idx: usize, f: F) -> MsgpackResult<T>
     where F: FnOnce(&mut Decoder<R>) -> MsgpackResult<T> {
-        if name != try!(self.read_str()) {
-            Err(_invalid_input("struct field name mismatch"))
-        } else {
-            f(self)
+        if self.bin.is_some() {
+            return f(self);
         }
+        if self.structs.last().map_or(true, |s| s.ahead.is_none()) {
+            let key = try!(self.read_str());
+            if key == name {
+                if let Some(s) = self.structs.last_mut() {
+                    s.remaining = s.remaining.saturating_sub(1);
+                }
+                return f(self);
+            }
+            try!(self._read_fields_ahead(key));
+        }
+        let bytes = match self.structs.last_mut().and_then(|s| s.ahead.as_mut()) {
+            Some(ahead) => match ahead.iter().position(|e| e.0 == name) {
+                Some(i) => ahead.swap_remove(i).1,
+                None    => return Err(_invalid_input("missing struct field"))
+            },
+            None => return Err(_invalid_input("struct field name mismatch"))
+        };
+        let depth = self.rd.ahead.len();
+        self.rd.ahead.push(io::Cursor::new(bytes));
+        let res = f(self);
+        self.rd.ahead.truncate(depth);
+        res
     }
 
     fn read_option<T,F>(&mut self, mut f: F) -> MsgpackResult<T>
@@ -490,7 +994,7 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
         if exp_len == len {
             f(self)
         } else {
-            panic!("Wrong tuple length") // XXX
+            Err(_invalid_input("Wrong tuple length"))
         }
     }
 
@@ -515,32 +1019,116 @@ impl<R: Read> rustc_serialize::Decoder for Decoder<R> {
         self.read_tuple_arg(idx, f)
     }
 
-    fn error(&mut self, _err: &str) -> byteorder::Error {
-        let err = io::Error::new(ErrorKind::InvalidInput,
-                                 "ApplicationError");
+    fn error(&mut self, err: &str) -> byteorder::Error {
+        let err = io::Error::new(ErrorKind::InvalidInput, err.to_string());
         byteorder::Error::Io(err)
     }
 }
 
-#[cfg(todo)]
+/// Only the msgpack `Decoder` can decode any `Value`, other decoders see an
+/// enum with the variants of `Value`.
 impl rustc_serialize::Decodable for Value {
-    fn decode<D, R: Read>(s: &mut D) -> Result<Self, D::Error>
-        where D: Decoder<R> {
-        s.decode_value()
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D) -> Result<Value, D::Error> {
+        d.read_enum(bytes::VALUE_ENUM_NAME, |d| {
+            d.read_enum_variant(&bytes::VALUE_VARIANTS, |d, idx| {
+                match idx {
+                    0 => d.read_nil().map(|_| Value::Nil),
+                    1 => d.read_bool().map(Value::Boolean),
+                    2 => d.read_i64().map(Value::Integer),
+                    3 => d.read_u64().map(Value::Unsigned),
+                    4 => d.read_f32().map(Value::Float),
+                    5 => d.read_f64().map(Value::Double),
+                    6 => d.read_seq(|d, len| {
+                        let mut v = Vec::with_capacity(cmp::min(len, MAX_PREALLOC));
+                        for i in 0 .. len {
+                            v.push(try!(d.read_seq_elt(i, Decodable::decode)));
+                        }
+                        Ok(Value::Array(v))
+                    }),
+                    7 => d.read_map(|d, len| {
+                        let mut v = Vec::with_capacity(cmp::min(len, MAX_PREALLOC));
+                        for i in 0 .. len {
+                            let key = try!(d.read_map_elt_key(i, Decodable::decode));
+                            let val = try!(d.read_map_elt_val(i, Decodable::decode));
+                            v.push((key, val));
+                        }
+                        Ok(Value::Map(v))
+                    }),
+                    8 => d.read_struct(bytes::STR_STRUCT_NAME, 1, |d| {
+                        d.read_struct_field("bytes", 0, Decodable::decode).map(Value::Str)
+                    }),
+                    9 => ByteBuf::decode(d).map(|b| Value::Binary(b.0)),
+                    _ => d.read_struct(bytes::EXT_STRUCT_NAME, 2, |d| {
+                        let typ = try!(d.read_struct_field("type", 0, |d| d.read_i8()));
+                        let data = try!(d.read_struct_field("data", 1, Decodable::decode));
+                        Ok(Value::Extended(typ, data))
+                    })
+                }
+            })
+        })
     }
 }
 
 
 /// A structure for implementing serialization to Msgpack.
 pub struct Encoder<'a> {
-    wr: &'a mut (Write + 'a)
+    wr: &'a mut (Write + 'a),
+    // set while encoding a `ByteBuf`, raw `Value::Str` or `Value::Extended`
+    raw: Option<Raw>,
+    compact_floats: bool,
+    canonical: bool,
+    // in canonical mode, map keys and values and struct fields are encoded
+    // into these buffers first, so that the entries of each open map or
+    // struct can be sorted
+    bufs: Vec<Vec<u8>>,
+    entries: Vec<Vec<(Vec<u8>, Vec<u8>)>>
+}
+
+#[derive(Clone, Copy)]
+enum Raw {
+    Bin,
+    Str,
+    Ext(i8)
 }
 
 impl<'a> Encoder<'a> {
     /// Creates a new Msgpack encoder whose output will be written to the writer
     /// specified.
     pub fn new(wr: &'a mut Write) -> Encoder<'a> {
-        Encoder { wr: wr }
+        Encoder {
+            wr: wr,
+            raw: None,
+            compact_floats: false,
+            canonical: false,
+            bufs: Vec::new(),
+            entries: Vec::new()
+        }
+    }
+
+    /// When enabled, an `f64` is written as an integer if it is integral and
+    /// in range, or as an `f32` if that represents it exactly. Otherwise it
+    /// is written as an `f64` as usual. The `Decoder` reads all of these
+    /// forms back into an `f64`.
+    pub fn set_compact_floats(&mut self, compact: bool) {
+        self.compact_floats = compact;
+    }
+
+    /// When enabled, the output only depends on the encoded data: map
+    /// entries are sorted by the encoded bytes of their keys, integers and
+    /// lengths always use the shortest format and NaNs are written as the
+    /// canonical quiet NaN. Struct fields are sorted like map keys, which
+    /// the `Decoder` reads back in any order.
+    ///
+    /// Non-negative signed integers are written in the unsigned formats,
+    /// which every `Strictness` reads back into signed types.
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    /// Emits `v` as a msgpack `bin` value.
+    pub fn emit_bin(&mut self, v: &[u8]) -> MsgpackResult<()> {
+        try!(self._emit_bin_len(v.len()));
+        self._write_raw(v)
     }
 
     pub fn to_msgpack<T: Encodable>(t: &T) -> MsgpackResult<Vec<u8>> {
@@ -552,26 +1140,81 @@ impl<'a> Encoder<'a> {
         Ok(m)
     }
 
+    /// Like `to_msgpack`, but with the canonical encoding described at
+    /// `set_canonical`.
+    pub fn to_msgpack_canonical<T: Encodable>(t: &T) -> MsgpackResult<Vec<u8>> {
+        let mut m = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut m as &mut Write);
+            encoder.set_canonical(true);
+            try!(t.encode(&mut encoder));
+        }
+        Ok(m)
+    }
+
+    // Where output goes: the innermost buffered map key or value, if any.
+    fn _wr(&mut self) -> &mut Write {
+        if self.bufs.is_empty() {
+            &mut *self.wr
+        } else {
+            self.bufs.last_mut().unwrap()
+        }
+    }
+
+    // Returns what `f` encodes instead of writing it.
+    fn _buffered<F>(&mut self, f: F) -> MsgpackResult<Vec<u8>>
+    where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        self.bufs.push(Vec::new());
+        let res = f(self);
+        let buf = self.bufs.pop().unwrap();
+        try!(res);
+        Ok(buf)
+    }
+
+    // Writes the entries of a map or struct that `f` adds to `entries`,
+    // sorted by their keys.
+    fn _emit_sorted_entries<F>(&mut self, len: usize, f: F) -> MsgpackResult<()>
+    where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        self.entries.push(Vec::with_capacity(cmp::min(len, MAX_PREALLOC)));
+        let res = f(self);
+        let mut entries = self.entries.pop().unwrap();
+        try!(res);
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        try!(self._emit_map_len(entries.len()));
+        for (key, val) in entries {
+            try!(self._write_raw(&key));
+            try!(self._write_raw(&val));
+        }
+        Ok(())
+    }
+
+    fn _write_raw(&mut self, v: &[u8]) -> MsgpackResult<()> {
+        match self._wr().write_all(v) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(byteorder::Error::Io(e))
+        }
+    }
+
     /// Emits the most efficient representation of the given unsigned integer
     fn _emit_unsigned(&mut self, v: u64) -> MsgpackResult<()> {
         if v <= 127 {
-            try!(self.wr.write_u8(v as u8));
+            try!(self._wr().write_u8(v as u8));
         }
         else if v <= std::u8::MAX as u64 {
-            try!(self.wr.write_u8(0xcc));
-            try!(self.wr.write_u8(v as u8));
+            try!(self._wr().write_u8(0xcc));
+            try!(self._wr().write_u8(v as u8));
         }
         else if v <= std::u16::MAX as u64 {
-            try!(self.wr.write_u8(0xcd));
-            try!(self.wr.write_u16::<BigEndian>(v as u16));
+            try!(self._wr().write_u8(0xcd));
+            try!(self._wr().write_u16::<BigEndian>(v as u16));
         }
         else if v <= std::u32::MAX as u64 {
-            try!(self.wr.write_u8(0xce));
-            try!(self.wr.write_u32::<BigEndian>(v as u32));
+            try!(self._wr().write_u8(0xce));
+            try!(self._wr().write_u32::<BigEndian>(v as u32));
         }
         else {
-            try!(self.wr.write_u8(0xcf));
-            try!(self.wr.write_u64::<BigEndian>(v));
+            try!(self._wr().write_u8(0xcf));
+            try!(self._wr().write_u64::<BigEndian>(v));
         }
 
         Ok(())
@@ -579,26 +1222,29 @@ impl<'a> Encoder<'a> {
 
     /// Emits the most efficient representation of the given signed integer
     fn _emit_signed(&mut self, v: i64) -> MsgpackResult<()> {
+        if self.canonical {
+            return encoder::encode_signed(&mut self._wr(), v);
+        }
         if v >= std::i8::MIN as i64 && v <= std::i8::MAX as i64 {
             let v = v as i8;
             if (v as u8) & 0xe0 != 0xe0 {
-                try!(self.wr.write_u8(0xd0));
+                try!(self._wr().write_u8(0xd0));
             }
-            try!(self.wr.write_u8(v as u8));
+            try!(self._wr().write_u8(v as u8));
         }
         else if v >= std::i16::MIN as i64 && v <= std::i16::MAX as i64 {
             let v = v as i16;
-            try!(self.wr.write_u8(0xd1));
-            try!(self.wr.write_i16::<BigEndian>(v));
+            try!(self._wr().write_u8(0xd1));
+            try!(self._wr().write_i16::<BigEndian>(v));
         }
         else if v >= std::i32::MIN as i64 && v <= std::i32::MAX as i64 {
             let v = v as i32;
-            try!(self.wr.write_u8(0xd2));
-            try!(self.wr.write_i32::<BigEndian>(v));
+            try!(self._wr().write_u8(0xd2));
+            try!(self._wr().write_i32::<BigEndian>(v));
         }
         else {
-            try!(self.wr.write_u8(0xd3));
-            try!(self.wr.write_i64::<BigEndian>(v));
+            try!(self._wr().write_u8(0xd3));
+            try!(self._wr().write_i64::<BigEndian>(v));
         }
 
         Ok(())
@@ -607,17 +1253,17 @@ impl<'a> Encoder<'a> {
     #[inline]
     fn _emit_len(&mut self, len: usize, (op1, sz1): (u8, usize), (op2, sz2): (u8, usize), op3: u8, op4: u8) -> MsgpackResult<()> {
         if len < sz1 {
-            try!(self.wr.write_u8(op1));
+            try!(self._wr().write_u8(op1));
         } else if len < sz2 {
-            try!(self.wr.write_u8(op2));
-            try!(self.wr.write_u8(len as u8));
+            try!(self._wr().write_u8(op2));
+            try!(self._wr().write_u8(len as u8));
         } else if len <= std::u16::MAX as usize {
-            try!(self.wr.write_u8(op3));
-            try!(self.wr.write_u16::<BigEndian>(len as u16));
+            try!(self._wr().write_u8(op3));
+            try!(self._wr().write_u16::<BigEndian>(len as u16));
         } else {
-            assert!(len <= std::u32::MAX as usize); // XXX
-            try!(self.wr.write_u8(op4));
-            try!(self.wr.write_u32::<BigEndian>(len as u32));
+            let len = try!(encoder::checked_len(len));
+            try!(self._wr().write_u8(op4));
+            try!(self._wr().write_u32::<BigEndian>(len));
         }
 
         Ok(())
@@ -638,6 +1284,11 @@ impl<'a> Encoder<'a> {
     }
 
 
+    fn _emit_ext_len(&mut self, len: usize, typ: i8) -> MsgpackResult<()> {
+        let len = try!(encoder::checked_len(len));
+        encoder::encode_ext_len(&mut self._wr(), len, typ)
+    }
+
     fn _emit_array_len(&mut self, len: usize) -> MsgpackResult<()> {
         self._emit_len(len, (0x90_u8 | (len & 15) as u8, 16),
         (0x00, 0),
@@ -656,7 +1307,7 @@ impl<'a> Encoder<'a> {
 impl<'a> rustc_serialize::Encoder for Encoder<'a> {
     type Error = byteorder::Error;
 
-    fn emit_nil(&mut self) -> MsgpackResult<()> { self.wr.write_u8(0xc0) }
+    fn emit_nil(&mut self) -> MsgpackResult<()> { self._wr().write_u8(0xc0) }
 
     #[inline]
     fn emit_usize(&mut self, v: usize) -> MsgpackResult<()> { self._emit_unsigned(v as u64) }
@@ -667,7 +1318,13 @@ impl<'a> rustc_serialize::Encoder for Encoder<'a> {
     #[inline]
     fn emit_u16(&mut self, v: u16) -> MsgpackResult<()>   { self._emit_unsigned(v as u64) }
     #[inline]
-    fn emit_u8(&mut self, v: u8) -> MsgpackResult<()>     { self._emit_unsigned(v as u64) }
+    fn emit_u8(&mut self, v: u8) -> MsgpackResult<()> {
+        if self.raw.is_some() {
+            self._wr().write_u8(v)
+        } else {
+            self._emit_unsigned(v as u64)
+        }
+    }
 
     #[inline]
     fn emit_isize(&mut self, v: isize) -> MsgpackResult<()>  { self._emit_signed(v as i64) }
@@ -678,23 +1335,47 @@ impl<'a> rustc_serialize::Encoder for Encoder<'a> {
     #[inline]
     fn emit_i16(&mut self, v: i16) -> MsgpackResult<()>  { self._emit_signed(v as i64) }
     #[inline]
-    fn emit_i8(&mut self,  v: i8) -> MsgpackResult<()>   { self._emit_signed(v as i64) }
+    fn emit_i8(&mut self,  v: i8) -> MsgpackResult<()> {
+        if let Some(Raw::Ext(_)) = self.raw {
+            // the type of a `Value::Extended`, written with its length
+            self.raw = Some(Raw::Ext(v));
+            Ok(())
+        } else {
+            self._emit_signed(v as i64)
+        }
+    }
 
     fn emit_f64(&mut self, v: f64) -> MsgpackResult<()> {
-        try!(self.wr.write_u8(0xcb));
-        unsafe { self.wr.write_u64::<BigEndian>(mem::transmute(v)) }
+        let v = if self.canonical && v.is_nan() { std::f64::NAN } else { v };
+        if self.compact_floats {
+            // -0.0 is integral too, but would lose its sign as an integer.
+            if v.is_finite() && v.trunc() == v && !(v == 0.0 && v.is_sign_negative()) {
+                if v >= 0.0 && v < TWO_POW_64 {
+                    return self._emit_unsigned(v as u64);
+                }
+                if v < 0.0 && v >= -TWO_POW_63 {
+                    return self._emit_signed(v as i64);
+                }
+            }
+            if (v as f32) as f64 == v {
+                return self.emit_f32(v as f32);
+            }
+        }
+        try!(self._wr().write_u8(0xcb));
+        unsafe { self._wr().write_u64::<BigEndian>(mem::transmute(v)) }
     }
 
     fn emit_f32(&mut self, v: f32) -> MsgpackResult<()> {
-        try!(self.wr.write_u8(0xca));
-        unsafe { self.wr.write_u32::<BigEndian>(mem::transmute(v)) }
+        let v = if self.canonical && v.is_nan() { std::f32::NAN } else { v };
+        try!(self._wr().write_u8(0xca));
+        unsafe { self._wr().write_u32::<BigEndian>(mem::transmute(v)) }
     }
 
     fn emit_bool(&mut self, v: bool) -> MsgpackResult<()> {
         if v {
-            self.wr.write_u8(0xc3)
+            self._wr().write_u8(0xc3)
         } else {
-            self.wr.write_u8(0xc2)
+            self._wr().write_u8(0xc2)
         }
     }
 
@@ -706,11 +1387,7 @@ impl<'a> rustc_serialize::Encoder for Encoder<'a> {
 
     fn emit_str(&mut self, v: &str) -> MsgpackResult<()> {
         try!(self._emit_str_len(v.len()));
-        // XXX There has to be a better way to do this...
-        match self.wr.write_all(v.as_bytes()) {
-            Ok(v) => Ok(v),
-            Err(e) => Err(byteorder::Error::Io(e))
-        }
+        self._write_raw(v.as_bytes())
     }
 
     fn emit_enum<F>(&mut self, _name: &str, f: F) -> MsgpackResult<()>
@@ -741,14 +1418,40 @@ impl<'a> rustc_serialize::Encoder for Encoder<'a> {
 
     // TODO: Option, to enable different ways to write out structs
     //       For example, to emit structs as maps/vectors.
-    fn emit_struct<F>(&mut self, _name: &str, len: usize, f: F)  -> MsgpackResult<()>
+    fn emit_struct<F>(&mut self, name: &str, len: usize, f: F)  -> MsgpackResult<()>
     where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        let raw = match name {
+            bytes::BIN_STRUCT_NAME => Some(Raw::Bin),
+            bytes::STR_STRUCT_NAME => Some(Raw::Str),
+            bytes::EXT_STRUCT_NAME => Some(Raw::Ext(0)),
+            _                      => None
+        };
+        if raw.is_some() {
+            self.raw = raw;
+            let res = f(self);
+            self.raw = None;
+            return res;
+        }
+        if self.canonical {
+            return self._emit_sorted_entries(len, f);
+        }
         try!(self._emit_map_len(len));
         f(self)
     }
 
     fn emit_struct_field<F>(&mut self, name: &str, _idx: usize, f: F)  -> MsgpackResult<()>
     where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        if self.raw.is_some() {
+            return f(self);
+        }
+        if self.canonical {
+            let key = try!(self._buffered(|e| e.emit_str(name)));
+            let val = try!(self._buffered(f));
+            return match self.entries.last_mut() {
+                Some(entries) => { entries.push((key, val)); Ok(()) }
+                None          => Err(_invalid_input("struct field outside of a struct"))
+            };
+        }
         try!(self.emit_str(name));
         f(self)
     }
@@ -786,7 +1489,12 @@ impl<'a> rustc_serialize::Encoder for Encoder<'a> {
 
     fn emit_seq<F>(&mut self, len: usize, f: F) -> MsgpackResult<()>
     where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
-        try!(self._emit_array_len(len));
+        match self.raw {
+            Some(Raw::Bin)    => try!(self._emit_bin_len(len)),
+            Some(Raw::Str)    => try!(self._emit_str_len(len)),
+            Some(Raw::Ext(t)) => try!(self._emit_ext_len(len, t)),
+            None              => try!(self._emit_array_len(len))
+        }
         f(self)
     }
 
@@ -797,24 +1505,38 @@ impl<'a> rustc_serialize::Encoder for Encoder<'a> {
 
     fn emit_map<F>(&mut self, len: usize, f: F) -> MsgpackResult<()>
      where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        if self.canonical {
+            return self._emit_sorted_entries(len, f);
+        }
         try!(self._emit_map_len(len));
         f(self)
     }
 
     fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> MsgpackResult<()>
     where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        if self.canonical && !self.entries.is_empty() {
+            let key = try!(self._buffered(f));
+            self.entries.last_mut().unwrap().push((key, Vec::new()));
+            return Ok(());
+        }
         f(self)
     }
 
     fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> MsgpackResult<()>
     where F: FnOnce(&mut Encoder<'a>) -> MsgpackResult<()> {
+        if self.canonical && !self.entries.is_empty() {
+            let val = try!(self._buffered(f));
+            return match self.entries.last_mut().unwrap().last_mut() {
+                Some(entry) => { entry.1 = val; Ok(()) }
+                None        => Err(_invalid_input("map value without key"))
+            };
+        }
         f(self)
     }
 }
 
-#[cfg(todo)]
-impl<E: rustc_serialize::Encoder<S>, S> rustc_serialize::Encodable<E, S> for Value {
-    fn encode(&self, e: &mut E) -> Result<(), S> {
+impl rustc_serialize::Encodable for Value {
+    fn encode<S: rustc_serialize::Encoder>(&self, e: &mut S) -> Result<(), S::Error> {
         match *self {
             Value::Nil => e.emit_nil(),
             Value::Boolean(b) => e.emit_bool(b),
@@ -839,9 +1561,12 @@ impl<E: rustc_serialize::Encoder<S>, S> rustc_serialize::Encodable<E, S> for Val
                     Ok(())
                 })
             }
-            Value::Str(ref str) => e.emit_str(from_utf8(str).unwrap()), // XXX
-            Value::Binary(_) => panic!(), // XXX
-            Value::Extended(_, _) => panic!() // XXX
+            Value::Str(ref str) => match from_utf8(str) {
+                Ok(s)  => e.emit_str(s),
+                Err(_) => bytes::encode_raw_str(str, e)
+            },
+            Value::Binary(ref bin) => Bytes(bin).encode(e),
+            Value::Extended(typ, ref data) => bytes::encode_ext(typ, data, e)
         }
 
     }
@@ -859,11 +1584,27 @@ pub fn encode_into<W:Write, T:Encodable>(wr: &mut W, t: &T) -> MsgpackResult<()>
     t.encode(&mut encoder)
 }
 
+/// Encodes `t` as a `Value`.
+pub fn to_value<T: Encodable>(t: &T) -> MsgpackResult<Value> {
+    let bytes = try!(Encoder::to_msgpack(t));
+    Decoder::new(&bytes[..]).decode_value()
+}
+
+/// Decodes a `T` from `value` as from its encoding, with
+/// `Strictness::Lenient`, as a `Value` does not record which of the
+/// compatible formats was used, e.g. for an `Integer` that fits a `u8`.
+pub fn from_value<T: Decodable>(value: &Value) -> MsgpackResult<T> {
+    let bytes = try!(Encoder::to_msgpack(value));
+    let mut decoder = Decoder::new(&bytes[..]);
+    decoder.set_strictness(Strictness::Lenient);
+    Decodable::decode(&mut decoder)
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
-    use super::{Encoder, from_msgpack};
-    use rustc_serialize::Encodable;
+    use super::{Encoder, Decoder, Strictness, ByteBuf, Bytes, from_msgpack};
+    use rustc_serialize::{Encodable, Decodable};
 
     macro_rules! assert_msgpack_circular(
         ($ty:ty, $inp:expr) => (
@@ -1008,4 +1749,389 @@ mod test {
         assert_msgpack_circular!(Animal, Animal::Dog);
         assert_msgpack_circular!(Animal, Animal::Frog("Henry".to_string(), 349));
     }
+
+    #[test]
+    fn test_reference_bin() {
+        assert_msgpack_reference!(ByteBuf, ByteBuf(vec![1, 2, 3]), &[0xc4, 3, 1, 2, 3]);
+        assert_msgpack_circular!(ByteBuf, ByteBuf(vec![]));
+        assert_msgpack_circular!(ByteBuf, ByteBuf(vec![0xff; 256]));
+        assert_msgpack_circular!(ByteBuf, ByteBuf(vec![7; 0x10000]));
+        assert_eq!(&[0xc4, 2, 0xff, 0][..], &Encoder::to_msgpack(&Bytes(&[0xff, 0])).unwrap()[..]);
+    }
+
+    #[test]
+    fn test_bin_in_struct_position() {
+        let v = (ByteBuf(vec![1]), vec![2u8], Some(ByteBuf(vec![3])));
+        let bytes = Encoder::to_msgpack(&v).unwrap();
+        assert_eq!(&[0x93, 0xc4, 1, 1, 0x91, 2, 0xc4, 1, 3][..], &bytes[..]);
+        assert_msgpack_circular!((ByteBuf, Vec<u8>, Option<ByteBuf>), v);
+    }
+
+    #[test]
+    fn test_lenient_bin() {
+        fn lenient(bytes: &[u8]) -> Result<ByteBuf, ::byteorder::Error> {
+            let mut decoder = Decoder::new(bytes);
+            decoder.set_strictness(Strictness::Lenient);
+            Decodable::decode(&mut decoder)
+        }
+        assert_eq!(ByteBuf(b"ab".to_vec()), lenient(&[0xa2, b'a', b'b']).unwrap());
+        assert_eq!(ByteBuf(vec![1, 200]), lenient(&[0x92, 1, 0xcc, 200]).unwrap());
+        assert!(lenient(&[0x91, 0xcd, 1, 0]).is_err());
+        assert!(from_msgpack::<ByteBuf>(&[0xa2, b'a', b'b']).is_err());
+        assert!(from_msgpack::<ByteBuf>(&[0x92, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_wrong_tuple_length() {
+        assert!(from_msgpack::<(u8, u8)>(&[0x93, 1, 2, 3]).is_err());
+        assert!(from_msgpack::<(u8, u8)>(&[0x91, 1]).is_err());
+        assert!(from_msgpack::<(u8, u8)>(&[0x82, 1, 2, 3, 4]).is_err());
+    }
+
+    #[test]
+    fn test_oversized_len() {
+        if ::std::mem::size_of::<usize>() > 4 {
+            let mut v = Vec::new();
+            let mut encoder = Encoder::new(&mut v);
+            assert!(encoder._emit_str_len(::std::u32::MAX as usize + 1).is_err());
+            assert!(encoder._emit_bin_len(::std::u32::MAX as usize + 1).is_err());
+            assert!(encoder._emit_array_len(::std::u32::MAX as usize + 1).is_err());
+            assert!(encoder._emit_map_len(::std::u32::MAX as usize + 1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decode_value_malformed() {
+        fn decode(bytes: &[u8]) -> Result<super::Value, ::byteorder::Error> {
+            Decoder::new(bytes).decode_value()
+        }
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0xc1]).is_err());
+        assert!(decode(&[0xcd, 0]).is_err());
+        assert!(decode(&[0xa3, b'a']).is_err());
+        assert!(decode(&[0xc6, 0xff, 0xff, 0xff, 0xff, 1]).is_err());
+        assert!(decode(&[0xdd, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(decode(&[0xdf, 0xff, 0xff, 0xff, 0xff, 1]).is_err());
+        assert!(decode(&[0xd4, 0xff, 1]).is_err());
+        assert!(decode(&[0xc9, 0, 0, 0, 2, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn test_ext_types() {
+        fn decode(bytes: &[u8]) -> Result<super::Value, ::byteorder::Error> {
+            Decoder::new(bytes).decode_value()
+        }
+        assert!(decode(&[0xd6, 0xff, 0, 0, 0, 1]).is_ok());
+        assert!(decode(&[0xd7, 0xff, 0, 0, 0, 0, 0, 0, 0, 1]).is_ok());
+        assert!(decode(&[0xc7, 12, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_ok());
+        assert!(decode(&[0xd5, 0xff, 0, 1]).is_err());
+        assert!(decode(&[0xd4, 0xfe, 1]).is_err());
+        assert!(decode(&[0xd4, 0x7f, 1]).is_ok());
+    }
+
+    #[test]
+    fn test_compact_floats() {
+        fn compact(v: f64) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            {
+                let mut encoder = Encoder::new(&mut bytes);
+                encoder.set_compact_floats(true);
+                v.encode(&mut encoder).unwrap();
+            }
+            let back: f64 = from_msgpack(&bytes[..]).unwrap();
+            assert!(back == v || (back.is_nan() && v.is_nan()));
+            assert_eq!(v.is_sign_negative(), back.is_sign_negative());
+            bytes
+        }
+        assert_eq!(vec![0x01], compact(1.0));
+        assert_eq!(vec![0x00], compact(0.0));
+        assert_eq!(vec![0xfd], compact(-3.0));
+        assert_eq!(vec![0xcd, 0x03, 0xe8], compact(1000.0));
+        assert_eq!(vec![0xca, 0x3f, 0x00, 0x00, 0x00], compact(0.5));
+        assert_eq!(vec![0xca, 0x80, 0x00, 0x00, 0x00], compact(-0.0));
+        assert_eq!(vec![0xca, 0x7f, 0x80, 0x00, 0x00], compact(::std::f64::INFINITY));
+        assert_eq!(9, compact(0.1).len());
+        assert_eq!(9, compact(::std::f64::NAN).len());
+        assert_eq!(9, compact(1e20).len());
+        assert_eq!(9, compact(-1e19).len());
+        assert_eq!(9, compact(-1.5e-300).len());
+    }
+
+    #[test]
+    fn test_read_f64_from_compact_forms() {
+        assert_eq!(5.0, from_msgpack::<f64>(&[0x05]).unwrap());
+        assert_eq!(-1.0, from_msgpack::<f64>(&[0xff]).unwrap());
+        assert_eq!(0.5, from_msgpack::<f64>(&[0xca, 0x3f, 0x00, 0x00, 0x00]).unwrap());
+        assert_eq!(9007199254740992.0, from_msgpack::<f64>(&[0xcf, 0, 0x20, 0, 0, 0, 0, 0, 0]).unwrap());
+        assert!(from_msgpack::<f64>(&[0xcf, 0, 0x20, 0, 0, 0, 0, 0, 1]).is_err());
+        assert!(from_msgpack::<f64>(&[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(from_msgpack::<f64>(&[0xd3, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert!(from_msgpack::<f64>(&[0xc0]).is_err());
+    }
+
+    #[test]
+    fn test_lenient_numbers() {
+        fn lenient<T: Decodable>(bytes: &[u8]) -> Result<T, ::byteorder::Error> {
+            let mut decoder = Decoder::new(bytes);
+            decoder.set_strictness(Strictness::Lenient);
+            Decodable::decode(&mut decoder)
+        }
+        // signed to unsigned
+        assert_eq!(5u32, lenient(&[0xd0, 5]).unwrap());
+        assert_eq!(300u64, lenient(&[0xd1, 1, 44]).unwrap());
+        assert!(lenient::<u32>(&[0xff]).is_err());
+        assert!(from_msgpack::<u32>(&[0xd0, 5]).is_err());
+
+        // unsigned to signed
+        assert_eq!(5i32, lenient(&[0x05]).unwrap());
+        assert_eq!(200i64, lenient(&[0xcc, 200]).unwrap());
+        assert!(lenient::<i8>(&[0xcc, 200]).is_err());
+        assert!(lenient::<i64>(&[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+        assert_eq!(5i32, from_msgpack(&[0x05]).unwrap());
+        assert!(from_msgpack::<i8>(&[0xcc, 200]).is_err());
+
+        // integer to float
+        assert_eq!(3.0f32, lenient(&[0x03]).unwrap());
+        assert_eq!(-2.0f32, lenient(&[0xfe]).unwrap());
+        assert_eq!(16777216.0f32, lenient(&[0xce, 1, 0, 0, 0]).unwrap());
+        assert!(lenient::<f32>(&[0xce, 1, 0, 0, 1]).is_err());
+        assert_eq!(0.5f32, lenient(&[0xcb, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0]).unwrap());
+        assert!(lenient::<f32>(&[0xcb, 0x3f, 0xb9, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a]).is_err());
+        assert!(from_msgpack::<f32>(&[0x03]).is_err());
+
+        // integral float to integer
+        assert_eq!(42u32, lenient(&[0xcb, 0x40, 0x45, 0, 0, 0, 0, 0, 0]).unwrap());
+        assert_eq!(-1i16, lenient(&[0xca, 0xbf, 0x80, 0, 0]).unwrap());
+        assert!(lenient::<u32>(&[0xca, 0x3f, 0x00, 0x00, 0x00]).is_err());
+        assert!(lenient::<u32>(&[0xca, 0xbf, 0x80, 0, 0]).is_err());
+        assert!(lenient::<u8>(&[0xcb, 0x40, 0x70, 0x00, 0, 0, 0, 0, 0]).is_err());
+        assert!(lenient::<i64>(&[0xca, 0x7f, 0xc0, 0x00, 0x00]).is_err());
+        assert!(from_msgpack::<u32>(&[0xcb, 0x40, 0x45, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_canonical_decode_value() {
+        fn canonical(bytes: &[u8]) -> Result<super::Value, ::byteorder::Error> {
+            let mut decoder = Decoder::new(bytes);
+            decoder.set_strictness(Strictness::Canonical);
+            decoder.decode_value()
+        }
+        assert!(canonical(&[0x05]).is_ok());
+        assert!(canonical(&[0xcd, 0x00, 0x05]).is_err());
+        assert!(canonical(&[0xcc, 0x05]).is_err());
+        assert!(canonical(&[0xcc, 0x80]).is_ok());
+        assert!(canonical(&[0xd0, 0x05]).is_err());
+        assert!(canonical(&[0xd0, 0xff]).is_err());
+        assert!(canonical(&[0xd0, 0xdf]).is_ok());
+        assert!(canonical(&[0xda, 0x00, 0x03, b'a', b'b', b'c']).is_err());
+        assert!(canonical(&[0xd9, 0x03, b'a', b'b', b'c']).is_err());
+        assert!(canonical(&[0xa3, b'a', b'b', b'c']).is_ok());
+        assert!(canonical(&[0xc5, 0x00, 0x01, 0x00]).is_err());
+        assert!(canonical(&[0xdc, 0x00, 0x01, 0x01]).is_err());
+        assert!(canonical(&[0x91, 0xcd, 0x00, 0x01]).is_err());
+        assert!(canonical(&[0xde, 0x00, 0x00]).is_err());
+        assert!(canonical(&[0xc7, 0x01, 0x01, 0x00]).is_err());
+        assert!(canonical(&[0xd4, 0x01, 0x00]).is_ok());
+        assert!(canonical(&[0x82, 0xa1, b'a', 0x01, 0xa1, b'a', 0x02]).is_err());
+        assert!(canonical(&[0x82, 0xa1, b'a', 0x01, 0xa1, b'b', 0x02]).is_ok());
+        assert!(canonical(&[0x82, 0xa1, b'b', 0x01, 0xa1, b'a', 0x02]).is_err());
+        // keys that are distinct, but equal as `Value`s
+        assert!(canonical(&[0x82, 0xca, 0x3f, 0x00, 0x00, 0x00, 0x01,
+                            0xcb, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0, 0x02]).is_ok());
+        assert!(canonical(&[0x82, 0xcb, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+                            0xcb, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x02]).is_ok());
+        assert!(canonical(&[0x82, 0xcb, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+                            0xcb, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]).is_err());
+
+        // other modes accept all of these
+        assert!(Decoder::new(&[0xcd, 0x00, 0x05][..]).decode_value().is_ok());
+        assert!(Decoder::new(&[0x82, 0xa1, b'a', 0x01, 0xa1, b'a', 0x02][..]).decode_value().is_ok());
+    }
+
+    #[test]
+    fn test_encode_value() {
+        use super::Value;
+        let v = Value::Array(vec![
+            Value::Nil, Value::Boolean(true), Value::Integer(-1), Value::Unsigned(300),
+            Value::Str(b"ab".to_vec()), Value::Str(vec![0xff]),
+            Value::Binary(vec![1, 2]), Value::Extended(5, vec![1, 2, 3]), Value::Extended(-1, vec![0; 4]),
+            Value::Map(vec![(Value::Unsigned(1), Value::Float(0.5))])]);
+        assert_eq!(vec![0x9a, 0xc0, 0xc3, 0xff, 0xcd, 0x01, 0x2c,
+                        0xa2, b'a', b'b', 0xa1, 0xff,
+                        0xc4, 2, 1, 2, 0xc7, 3, 5, 1, 2, 3, 0xd6, 0xff, 0, 0, 0, 0,
+                        0x81, 0x01, 0xca, 0x3f, 0, 0, 0],
+                   Encoder::to_msgpack(&v).unwrap());
+        let bytes = Encoder::to_msgpack(&v).unwrap();
+        let mut decoder = Decoder::new(&bytes[..]);
+        assert_eq!(v, decoder.decode_value().unwrap());
+    }
+
+    #[test]
+    fn test_decodable_value() {
+        use super::Value;
+        let v = Value::Array(vec![
+            Value::Nil, Value::Boolean(false), Value::Integer(-200), Value::Unsigned(1 << 40),
+            Value::Float(0.5), Value::Double(-0.25), Value::Str(b"ab".to_vec()), Value::Str(vec![0xff]),
+            Value::Binary(vec![1, 2]), Value::Extended(5, vec![1, 2, 3]), Value::Extended(-1, vec![0; 8]),
+            Value::Map(vec![(Value::Str(b"k".to_vec()), Value::Array(vec![]))])]);
+        let bytes = Encoder::to_msgpack(&v).unwrap();
+        let decoded: Value = from_msgpack(&bytes).unwrap();
+        assert_eq!(format!("{:?}", v), format!("{:?}", decoded));
+
+        // inside other types, and followed by more data
+        let pair: (Vec<Value>, String) = from_msgpack(&[0x92, 0x92, 0x01, 0xd4, 0x07, 0x08, 0xa1, b'x']).unwrap();
+        assert_eq!((vec![Value::Unsigned(1), Value::Extended(7, vec![8])], "x".to_string()), pair);
+
+        assert!(from_msgpack::<Value>(&[0xc1]).is_err());
+        assert!(from_msgpack::<Value>(&[0xd4, 0xfe, 0x00]).is_err());
+        assert!(from_msgpack::<Value>(&[0x92, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_to_from_value() {
+        use super::{Value, to_value, from_value};
+        let v = to_value(&(1u8, -2i64, "a", vec![true], ())).unwrap();
+        assert_eq!(Value::Array(vec![Value::Unsigned(1), Value::Integer(-2), Value::from("a"),
+                                     Value::Array(vec![Value::Boolean(true)]), Value::Nil]), v);
+        let t: (u32, i8, String, Vec<bool>, ()) = from_value(&v).unwrap();
+        assert_eq!((1, -2, "a".to_string(), vec![true], ()), t);
+        assert!(from_value::<(u8, i8)>(&v).is_err());
+        assert_eq!(2u8, from_value(&Value::Integer(2)).unwrap());
+        assert!(from_value::<u8>(&Value::Integer(-1)).is_err());
+    }
+
+    #[test]
+    fn test_canonical_map_order() {
+        let mut a = HashMap::new();
+        let mut b = HashMap::new();
+        for i in 0 .. 100u32 {
+            a.insert(format!("key{}", i), i);
+            b.insert(format!("key{}", 99 - i), 99 - i);
+        }
+        let bytes = Encoder::to_msgpack_canonical(&a).unwrap();
+        assert_eq!(bytes, Encoder::to_msgpack_canonical(&b).unwrap());
+
+        let mut decoder = Decoder::new(&bytes[..]);
+        decoder.set_strictness(Strictness::Canonical);
+        match decoder.decode_value().unwrap() {
+            super::Value::Map(entries) => {
+                assert_eq!(100, entries.len());
+                let keys: Vec<&super::Value> = entries.iter().map(|e| &e.0).collect();
+                for w in keys.windows(2) {
+                    match (w[0], w[1]) {
+                        // fixstr headers sort shorter keys first
+                        (&super::Value::Str(ref x), &super::Value::Str(ref y)) => {
+                            assert!((x.len(), x) < (y.len(), y))
+                        }
+                        _ => panic!()
+                    }
+                }
+            }
+            _ => panic!()
+        }
+    }
+
+    #[test]
+    fn test_canonical_value() {
+        use super::Value;
+        // keys are sorted by their encoding, also in nested maps
+        let v = Value::Array(vec![Value::Map(vec![
+            (Value::Str(b"b".to_vec()), Value::Integer(5)),
+            (Value::Unsigned(200), Value::Map(vec![
+                (Value::Integer(-1), Value::Nil),
+                (Value::Unsigned(1), Value::Nil)])),
+            (Value::Str(b"a".to_vec()), Value::Double(::std::f64::NAN))])]);
+        let bytes = Encoder::to_msgpack_canonical(&v).unwrap();
+        assert_eq!(vec![0x91, 0x83,
+                        0xa1, b'a', 0xcb, 0x7f, 0xf8, 0, 0, 0, 0, 0, 0,
+                        0xa1, b'b', 0x05,
+                        0xcc, 200, 0x82, 0x01, 0xc0, 0xff, 0xc0], bytes);
+        let mut decoder = Decoder::new(&bytes[..]);
+        decoder.set_strictness(Strictness::Canonical);
+        assert!(decoder.decode_value().is_ok());
+
+        // keys are compared by their bytes, also keys that are maps
+        for &(bytes, ok) in &[(&[0x82, 0x81, 0x01, 0xc0, 0xc0, 0x81, 0x02, 0xc0, 0xc0][..], true),
+                              (&[0x82, 0x81, 0x02, 0xc0, 0xc0, 0x81, 0x01, 0xc0, 0xc0][..], false),
+                              (&[0x81, 0x82, 0x02, 0xc0, 0x01, 0xc0, 0xc0][..], false),
+                              (&[0x82, 0xca, 0x3f, 0, 0, 0, 0xc0,
+                                 0xcb, 0x3f, 0xe0, 0, 0, 0, 0, 0, 0, 0xc0][..], true),
+                              (&[0x82, 0xa1, b'a', 0xc0, 0xa1, b'a', 0xc0][..], false)] {
+            let mut decoder = Decoder::new(bytes);
+            decoder.set_strictness(Strictness::Canonical);
+            assert_eq!(ok, decoder.decode_value().is_ok(), "{:?}", bytes);
+        }
+
+        let nan: f32 = unsafe { ::std::mem::transmute(0x7fc00001u32) };
+        assert_eq!(vec![0xca, 0x7f, 0xc0, 0, 0], Encoder::to_msgpack_canonical(&nan).unwrap());
+    }
+
+    #[test]
+    fn test_canonical_struct() {
+        #[derive(RustcEncodable,RustcDecodable,PartialEq,Debug)]
+        struct Inner { zeta: u8, beta: Option<String> }
+        #[derive(RustcEncodable,RustcDecodable,PartialEq,Debug)]
+        struct Person { name: String, age: u8, inner: Inner, tags: Vec<Inner> }
+
+        let p = Person { name: "A".to_string(), age: 2,
+                         inner: Inner { zeta: 3, beta: None },
+                         tags: vec![Inner { zeta: 4, beta: Some("b".to_string()) }] };
+        let bytes = Encoder::to_msgpack_canonical(&p).unwrap();
+        assert_eq!(&[0x84, 0xa3, b'a', b'g', b'e', 0x02, 0xa4, b'n', b'a', b'm', b'e', 0xa1, b'A'][..],
+                   &bytes[.. 13]);
+        assert_eq!(Ok(&[][..]), super::slice_reader::validate_canonical(&bytes));
+        let mut decoder = Decoder::new(&bytes[..]);
+        decoder.set_strictness(Strictness::Canonical);
+        assert!(decoder.decode_value().is_ok());
+
+        // the fields are read back in any order
+        assert_eq!(p, from_msgpack(&bytes).unwrap());
+        assert_eq!(p, from_msgpack(&Encoder::to_msgpack(&p).unwrap()).unwrap());
+        let bytes = Encoder::to_msgpack_canonical(&[&p]).unwrap();
+        assert_eq!(vec![p], from_msgpack::<Vec<Person>>(&bytes).unwrap());
+        assert!(from_msgpack::<Inner>(&[0x82, 0xa4, b'z', b'e', b't', b'a', 0x01, 0xa1, b'x', 0xc0]).is_err());
+    }
+
+    #[test]
+    fn test_canonical_signed() {
+        let bytes = Encoder::to_msgpack_canonical(&(5i32, -200i64)).unwrap();
+        assert_eq!(vec![0x92, 0x05, 0xd1, 0xff, 0x38], bytes);
+        let mut decoder = Decoder::new(&bytes[..]);
+        decoder.set_strictness(Strictness::Canonical);
+        let v: (i32, i64) = Decodable::decode(&mut decoder).unwrap();
+        assert_eq!((5, -200), v);
+
+        // a strict decoder reads the canonical encoding back as well
+        let v = (0i8, 127i8, -1i16, std::i32::MAX, std::i64::MAX);
+        let bytes = Encoder::to_msgpack_canonical(&v).unwrap();
+        assert_eq!(v, from_msgpack(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_content_hash() {
+        use std::hash::Hasher;
+        use super::{Value, HashingEncoder};
+        use super::hash::FnvHasher;
+
+        let a = Value::Map(vec![(Value::Unsigned(1), Value::Nil), (Value::Unsigned(2), Value::Nil)]);
+        let b = Value::Map(vec![(Value::Unsigned(2), Value::Nil), (Value::Unsigned(1), Value::Nil)]);
+        let c = Value::Map(vec![(Value::Unsigned(2), Value::Nil), (Value::Unsigned(3), Value::Nil)]);
+        assert_eq!(a.content_hash().unwrap(), b.content_hash().unwrap());
+        assert!(a.content_hash().unwrap() != c.content_hash().unwrap());
+
+        let mut hasher = FnvHasher::default();
+        hasher.write(&Encoder::to_msgpack_canonical(&a).unwrap());
+        assert_eq!(hasher.finish(), a.content_hash().unwrap());
+
+        // the FNV-1a reference value for "a"
+        let mut hasher = FnvHasher::default();
+        hasher.write(b"a");
+        assert_eq!(0xaf63dc4c8601ec8c, hasher.finish());
+
+        let mut encoder = HashingEncoder::new(FnvHasher::default());
+        encoder.encode(&ByteBuf(vec![b'a'])).unwrap();
+        let mut hasher = FnvHasher::default();
+        hasher.write(&[0xc4, 1, b'a']);
+        assert_eq!(hasher.finish(), encoder.finish());
+    }
 }
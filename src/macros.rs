@@ -0,0 +1,240 @@
+/// Builds a `Value` from a JSON-like literal.
+///
+/// ```
+/// # #[macro_use] extern crate msgpack;
+/// # fn main() {
+/// let name = "msgpack";
+/// let v = msgpack!({
+///     "a": 1,
+///     "b": [true, null, 2.5],
+///     "c": bin(b"\x00\x01"),
+///     "d": ext(5, [1, 2, 3]),
+///     "e": name,
+///     (name.len()): -1
+/// });
+/// assert_eq!(Some("msgpack"), v["e"].as_str());
+/// # }
+/// ```
+///
+/// `null`, `true` and `false` are the respective values. `bin(bytes)` and
+/// `ext(type, bytes)` take anything that can be sliced into `&[u8]`.
+/// Non-negative integer literals become `Unsigned` and negative ones
+/// `Integer`, like a `Decoder` reads them. Any other Rust expression is
+/// converted with `Value::from`; keys that are not a single token have to
+/// be put into parentheses.
+#[macro_export]
+macro_rules! msgpack {
+    //
+    // Array elements, accumulated in [...]
+    //
+
+    (@array [$($elems:expr,)*]) => { vec![$($elems,)*] };
+    (@array [$($elems:expr),*]) => { vec![$($elems),*] };
+    (@array [$($elems:expr,)*] null $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!(null)] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] true $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!(true)] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] false $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!(false)] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] bin ($($b:tt)*) $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!(bin($($b)*))] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] ext ($($e:tt)*) $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!(ext($($e)*))] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] [$($array:tt)*] $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!([$($array)*])] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] {$($map:tt)*} $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!({$($map)*})] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] - $next:literal, $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!(- $next),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] - $last:literal) => {
+        msgpack!(@array [$($elems,)* msgpack!(- $last)])
+    };
+    (@array [$($elems:expr,)*] $next:tt, $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!($next),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] $last:tt) => {
+        msgpack!(@array [$($elems,)* msgpack!($last)])
+    };
+    (@array [$($elems:expr,)*] $next:expr, $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)* msgpack!($next),] $($rest)*)
+    };
+    (@array [$($elems:expr,)*] $last:expr) => {
+        msgpack!(@array [$($elems,)* msgpack!($last)])
+    };
+    (@array [$($elems:expr),*] , $($rest:tt)*) => {
+        msgpack!(@array [$($elems,)*] $($rest)*)
+    };
+
+    //
+    // Map entries, accumulated in [...]
+    //
+
+    (@map [$($entries:expr,)*]) => { vec![$($entries,)*] };
+    (@map [$($entries:expr,)*] , $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)*] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : null $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(null)),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : true $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(true)),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : false $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(false)),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : bin ($($b:tt)*) $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(bin($($b)*))),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : ext ($($e:tt)*) $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(ext($($e)*))),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : [$($array:tt)*] $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!([$($array)*])),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : {$($map:tt)*} $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!({$($map)*})),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : - $value:literal, $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(- $value)),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : - $value:literal) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!(- $value)),])
+    };
+    (@map [$($entries:expr,)*] $key:tt : $value:tt, $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!($value)),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : $value:tt) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!($value)),])
+    };
+    (@map [$($entries:expr,)*] $key:tt : $value:expr, $($rest:tt)*) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!($value)),] $($rest)*)
+    };
+    (@map [$($entries:expr,)*] $key:tt : $value:expr) => {
+        msgpack!(@map [$($entries,)* (msgpack!($key), msgpack!($value)),])
+    };
+
+    //
+    // Values
+    //
+
+    (null) => { $crate::Value::Nil };
+    (true) => { $crate::Value::Boolean(true) };
+    (false) => { $crate::Value::Boolean(false) };
+    (bin($bytes:expr)) => { $crate::Value::Binary(($bytes)[..].to_vec()) };
+    (ext($typ:expr, $bytes:expr)) => { $crate::Value::Extended($typ, ($bytes)[..].to_vec()) };
+    ([]) => { $crate::Value::Array(vec![]) };
+    ([ $($tt:tt)+ ]) => { $crate::Value::Array(msgpack!(@array [] $($tt)+)) };
+    ({}) => { $crate::Value::Map(vec![]) };
+    ({ $($tt:tt)+ }) => { $crate::Value::Map(msgpack!(@map [] $($tt)+)) };
+    // a `literal` fragment does not fall through to the next rule for
+    // other expressions that start with `-`
+    (- $lit:literal) => { $crate::macros::Literal::into_value(- $lit) };
+    (- $($neg:tt)+) => { $crate::Value::from(- $($neg)+) };
+    ($lit:literal) => { $crate::macros::Literal::into_value($lit) };
+    ($other:expr) => { $crate::Value::from($other) };
+}
+
+/// Converts the literals in `msgpack!`. Unsuffixed integer literals are
+/// `i32`, so their sign decides between `Unsigned` and `Integer`.
+#[doc(hidden)]
+pub trait Literal {
+    fn into_value(self) -> Value;
+}
+
+macro_rules! signed_literal {
+    ($($ty:ty),*) => {
+        $(
+            impl Literal for $ty {
+                fn into_value(self) -> Value {
+                    if self >= 0 {
+                        Value::Unsigned(self as u64)
+                    } else {
+                        Value::Integer(self as i64)
+                    }
+                }
+            }
+        )*
+    }
+}
+
+macro_rules! other_literal {
+    ($($ty:ty),*) => {
+        $(
+            impl Literal for $ty {
+                fn into_value(self) -> Value { Value::from(self) }
+            }
+        )*
+    }
+}
+
+signed_literal! { i8, i16, i32, i64, isize }
+other_literal! { u8, u16, u32, u64, usize, f32, f64, &'static str }
+
+use super::Value;
+
+#[cfg(test)]
+mod test {
+    use super::super::Value;
+
+    fn s(v: &str) -> Value {
+        Value::Str(v.as_bytes().to_vec())
+    }
+
+    // `==` treats e.g. `Integer(1)` and `Unsigned(1)` as equal, so compare
+    // the variants too
+    fn check(expected: Value, v: Value) {
+        assert_eq!(format!("{:?}", expected), format!("{:?}", v));
+    }
+
+    #[test]
+    fn test_msgpack_macro() {
+        let v = msgpack!({"a": 1, "b": [true, null, 2.5], "c": bin(b"\x00\x01")});
+        check(Value::Map(vec![
+            (s("a"), Value::Unsigned(1)),
+            (s("b"), Value::Array(vec![Value::Boolean(true), Value::Nil, Value::Double(2.5)])),
+            (s("c"), Value::Binary(vec![0, 1]))]), v);
+    }
+
+    #[test]
+    fn test_msgpack_macro_literals() {
+        check(Value::Nil, msgpack!(null));
+        check(Value::Array(vec![]), msgpack!([]));
+        check(Value::Map(vec![]), msgpack!({}));
+        check(Value::Extended(5, vec![1, 2]), msgpack!(ext(5, [1, 2])));
+        check(Value::Integer(-1), msgpack!(-1));
+        check(Value::Unsigned(0), msgpack!(0));
+        check(Value::Unsigned(7), msgpack!(7i64));
+        check(Value::Float(1.5), msgpack!(1.5f32));
+        check(Value::Array(vec![Value::Integer(-1), Value::Unsigned(2), Value::Boolean(false)]),
+              msgpack!([-1, 2, false,]));
+        check(Value::Map(vec![(Value::Unsigned(1), Value::Unsigned(2)), (Value::Integer(-1), Value::Integer(-2))]),
+              msgpack!({1: 2, (-1): -2}));
+        check(Value::Array(vec![Value::Array(vec![]), Value::Map(vec![]), Value::Binary(vec![])]),
+              msgpack!([[], {}, bin([])]));
+        check(Value::Array(vec![Value::Extended(1, vec![9])]), msgpack!([ext(1, vec![9])]));
+    }
+
+    #[test]
+    fn test_msgpack_macro_interpolation() {
+        let name = "x";
+        let items = vec![1u8, 2];
+        let n = 5;
+        let v = msgpack!({
+            name: items.clone(),
+            (name.len()): {"nested": [name, (1 + 2), -n]},
+            "last": ext(2, items)
+        });
+        check(Value::Map(vec![
+            (s("x"), Value::Array(vec![Value::Unsigned(1), Value::Unsigned(2)])),
+            (Value::Unsigned(1), Value::Map(vec![(s("nested"), Value::Array(vec![s("x"), Value::Integer(3), Value::Integer(-5)]))])),
+            (s("last"), Value::Extended(2, vec![1, 2]))]), v);
+    }
+}
@@ -0,0 +1,424 @@
+//! A human readable, JSON-like notation for `Value`s.
+//!
+//! `Display` writes values in this notation and `parse` (or `str::parse`)
+//! reads them back:
+//!
+//! * `nil`, `true`, `false`
+//! * `5u` for `Unsigned`, `-5i` for `Integer`, `0.5f32` for `Float` and
+//!   `0.5f64` for `Double`. Special floats are written as `NaN`, `inf` and
+//!   `-inf` with the suffix.
+//! * `"text"` for `Str`, using the escapes `\"`, `\\`, `\n`, `\r`, `\t`,
+//!   `\u{7f}` for other control characters and `\xff` for bytes that are
+//!   not valid UTF-8.
+//! * `bin(01ff)` for `Binary` and `ext(5, 01ff)` for `Extended`, with the
+//!   data in hex.
+//! * `[1u, 2u]` for `Array` and `{"a": 1u}` for `Map`.
+//!
+//! Parsing also accepts numbers without a suffix: integers become
+//! `Unsigned` (or `Integer` if negative) and numbers with a fraction or
+//! exponent become `Double`. Every value round-trips exactly, except that
+//! NaN payloads are not preserved.
+
+use std::fmt;
+use std::str::{self, FromStr};
+use super::Value;
+
+fn write_hex(f: &mut fmt::Formatter, data: &[u8]) -> fmt::Result {
+    for b in data {
+        try!(write!(f, "{:02x}", b));
+    }
+    Ok(())
+}
+
+fn write_float<T: fmt::Debug>(f: &mut fmt::Formatter, v: T, is_nan: bool, suffix: &str) -> fmt::Result {
+    if is_nan {
+        write!(f, "NaN{}", suffix)
+    } else {
+        write!(f, "{:?}{}", v, suffix)
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, mut s: &[u8]) -> fmt::Result {
+    try!(f.write_str("\""));
+    while !s.is_empty() {
+        let (valid, invalid) = match str::from_utf8(s) {
+            Ok(valid) => (valid, &s[s.len() ..]),
+            Err(e) => {
+                let (valid, rest) = s.split_at(e.valid_up_to());
+                // always at least one invalid byte follows
+                (str::from_utf8(valid).unwrap(), &rest[.. e.error_len().unwrap_or(rest.len())])
+            }
+        };
+        for c in valid.chars() {
+            try!(match c {
+                '"'  => f.write_str("\\\""),
+                '\\' => f.write_str("\\\\"),
+                '\n' => f.write_str("\\n"),
+                '\r' => f.write_str("\\r"),
+                '\t' => f.write_str("\\t"),
+                c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32),
+                c => write!(f, "{}", c)
+            });
+        }
+        for b in invalid {
+            try!(write!(f, "\\x{:02x}", b));
+        }
+        s = &s[valid.len() + invalid.len() ..];
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => f.write_str("nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}i", i),
+            Value::Unsigned(u) => write!(f, "{}u", u),
+            Value::Float(v) => write_float(f, v, v.is_nan(), "f32"),
+            Value::Double(v) => write_float(f, v, v.is_nan(), "f64"),
+            Value::Array(ref a) => {
+                try!(f.write_str("["));
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_str(", "));
+                    }
+                    try!(write!(f, "{}", v));
+                }
+                f.write_str("]")
+            }
+            Value::Map(ref m) => {
+                try!(f.write_str("{"));
+                for (i, &(ref k, ref v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        try!(f.write_str(", "));
+                    }
+                    try!(write!(f, "{}: {}", k, v));
+                }
+                f.write_str("}")
+            }
+            Value::Str(ref s) => write_str(f, s),
+            Value::Binary(ref b) => {
+                try!(f.write_str("bin("));
+                try!(write_hex(f, b));
+                f.write_str(")")
+            }
+            Value::Extended(t, ref d) => {
+                try!(write!(f, "ext({}, ", t));
+                try!(write_hex(f, d));
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// Where and why parsing the notation failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset into the input
+    pub offset: usize,
+    pub message: &'static str
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+/// Parses a single value in the notation, surrounded by optional whitespace.
+pub fn parse(s: &str) -> Result<Value, ParseError> {
+    let mut p = Parser { s: s.as_bytes(), pos: 0 };
+    let v = try!(p.value());
+    p.skip_ws();
+    if p.pos < p.s.len() {
+        return Err(p.error("Trailing characters"));
+    }
+    Ok(v)
+}
+
+impl FromStr for Value {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Value, ParseError> {
+        parse(s)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError { offset: self.pos, message: message }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.s.len() && (self.s[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.s.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8, message: &'static str) -> Result<(), ParseError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    // A run of characters that can make up a keyword or number.
+    fn token(&mut self) -> &'a str {
+        self.skip_ws();
+        let start = self.pos;
+        while self.pos < self.s.len() {
+            match self.s[self.pos] {
+                b'a' ... b'z' | b'A' ... b'Z' | b'0' ... b'9' | b'.' | b'+' | b'-' | b'_' => self.pos += 1,
+                _ => break
+            }
+        }
+        // only ASCII was consumed
+        str::from_utf8(&self.s[start .. self.pos]).unwrap()
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            None => Err(self.error("Unexpected end of input")),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut a = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(a));
+                }
+                loop {
+                    a.push(try!(self.value()));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => { self.pos += 1; return Ok(Value::Array(a)) }
+                        _ => return Err(self.error("Expected ',' or ']'"))
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut m = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Map(m));
+                }
+                loop {
+                    let k = try!(self.value());
+                    try!(self.expect(b':', "Expected ':'"));
+                    let v = try!(self.value());
+                    m.push((k, v));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => { self.pos += 1; return Ok(Value::Map(m)) }
+                        _ => return Err(self.error("Expected ',' or '}'"))
+                    }
+                }
+            }
+            Some(b'"') => {
+                self.pos += 1;
+                self.string().map(Value::Str)
+            }
+            Some(_) => {
+                let start = self.pos;
+                let token = self.token();
+                match token {
+                    "" => Err(self.error("Unexpected character")),
+                    "nil" => Ok(Value::Nil),
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    "bin" => {
+                        try!(self.expect(b'(', "Expected '('"));
+                        let data = try!(self.hex());
+                        try!(self.expect(b')', "Expected ')'"));
+                        Ok(Value::Binary(data))
+                    }
+                    "ext" => {
+                        try!(self.expect(b'(', "Expected '('"));
+                        let typ_pos = self.pos;
+                        let typ = match self.token().parse::<i8>() {
+                            Ok(t) => t,
+                            Err(_) => return Err(ParseError { offset: typ_pos, message: "Invalid ext type" })
+                        };
+                        try!(self.expect(b',', "Expected ','"));
+                        let data = try!(self.hex());
+                        try!(self.expect(b')', "Expected ')'"));
+                        Ok(Value::Extended(typ, data))
+                    }
+                    _ => number(token).ok_or(ParseError { offset: start, message: "Invalid number" })
+                }
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<Vec<u8>, ParseError> {
+        self.skip_ws();
+        let mut data = Vec::new();
+        loop {
+            let hi = match self.s.get(self.pos).and_then(|&c| (c as char).to_digit(16)) {
+                Some(d) => d,
+                None => return Ok(data)
+            };
+            let lo = match self.s.get(self.pos + 1).and_then(|&c| (c as char).to_digit(16)) {
+                Some(d) => d,
+                None => return Err(self.error("Odd number of hex digits"))
+            };
+            data.push((hi * 16 + lo) as u8);
+            self.pos += 2;
+        }
+    }
+
+    // Parses the rest of a string, after the opening quote.
+    fn string(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut out = Vec::new();
+        loop {
+            let c = match self.s.get(self.pos) {
+                Some(&c) => c,
+                None => return Err(self.error("Unterminated string"))
+            };
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(out),
+                b'\\' => {
+                    let e = match self.s.get(self.pos) {
+                        Some(&e) => e,
+                        None => return Err(self.error("Unterminated string"))
+                    };
+                    self.pos += 1;
+                    match e {
+                        b'"' => out.push(b'"'),
+                        b'\\' => out.push(b'\\'),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'x' => {
+                            let digits = self.s.get(self.pos .. self.pos + 2)
+                                .and_then(|d| str::from_utf8(d).ok())
+                                .and_then(|d| u8::from_str_radix(d, 16).ok());
+                            match digits {
+                                Some(b) => { out.push(b); self.pos += 2 }
+                                None => return Err(self.error("Invalid \\x escape"))
+                            }
+                        }
+                        b'u' => {
+                            let end = self.s[self.pos ..].iter().position(|&c| c == b'}');
+                            let c = match end {
+                                Some(end) if self.s[self.pos] == b'{' => {
+                                    let digits = str::from_utf8(&self.s[self.pos + 1 .. self.pos + end]).ok();
+                                    self.pos += end + 1;
+                                    digits.and_then(|d| u32::from_str_radix(d, 16).ok())
+                                          .and_then(::std::char::from_u32)
+                                }
+                                _ => None
+                            };
+                            match c {
+                                Some(c) => {
+                                    let mut buf = [0; 4];
+                                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                                }
+                                None => return Err(self.error("Invalid \\u escape"))
+                            }
+                        }
+                        _ => return Err(self.error("Invalid escape"))
+                    }
+                }
+                c => out.push(c)
+            }
+        }
+    }
+}
+
+fn number(token: &str) -> Option<Value> {
+    if token.ends_with("f32") {
+        token[.. token.len() - 3].parse().ok().map(Value::Float)
+    } else if token.ends_with("f64") {
+        token[.. token.len() - 3].parse().ok().map(Value::Double)
+    } else if token.ends_with('u') {
+        token[.. token.len() - 1].parse().ok().map(Value::Unsigned)
+    } else if token.ends_with('i') {
+        token[.. token.len() - 1].parse().ok().map(Value::Integer)
+    } else if let Ok(u) = token.parse() {
+        Some(Value::Unsigned(u))
+    } else if let Ok(i) = token.parse() {
+        Some(Value::Integer(i))
+    } else if token.bytes().all(|c| c.is_ascii_digit() || c == b'.' || c == b'e' || c == b'E' || c == b'-' || c == b'+') {
+        token.parse().ok().map(Value::Double)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64;
+    use super::super::Value;
+    use super::{parse, ParseError};
+
+    fn round_trip(v: Value, text: &str) {
+        assert_eq!(text, v.to_string());
+        let back = parse(text).unwrap();
+        assert_eq!(v, back);
+        // `==` treats some distinct values as equal, the notation does not
+        assert_eq!(text, back.to_string());
+    }
+
+    #[test]
+    fn test_display_and_parse() {
+        round_trip(Value::Nil, "nil");
+        round_trip(Value::Boolean(true), "true");
+        round_trip(Value::Unsigned(18446744073709551615), "18446744073709551615u");
+        round_trip(Value::Integer(5), "5i");
+        round_trip(Value::Integer(-9223372036854775808), "-9223372036854775808i");
+        round_trip(Value::Float(0.1), "0.1f32");
+        round_trip(Value::Double(0.1), "0.1f64");
+        round_trip(Value::Double(-0.0), "-0.0f64");
+        round_trip(Value::Double(1e300), "1e300f64");
+        round_trip(Value::Double(f64::NEG_INFINITY), "-inff64");
+        round_trip(Value::Float(::std::f32::NAN), "NaNf32");
+        round_trip(Value::Str(b"a\"b\\\n\x01\xc3\xa4".to_vec()), "\"a\\\"b\\\\\\n\\u{1}\u{e4}\"");
+        round_trip(Value::Str(vec![b'a', 0xff, 0xc3, b'b']), "\"a\\xff\\xc3b\"");
+        round_trip(Value::Binary(vec![0, 1, 0xff]), "bin(0001ff)");
+        round_trip(Value::Binary(vec![]), "bin()");
+        round_trip(Value::Extended(-1, vec![0xab]), "ext(-1, ab)");
+        round_trip(Value::Array(vec![]), "[]");
+        round_trip(Value::Map(vec![]), "{}");
+        round_trip(msgpack!({"a": [1u8, null], 2u8: {"b": bin([1])}}),
+                   "{\"a\": [1u, nil], 2u: {\"b\": bin(01)}}");
+    }
+
+    #[test]
+    fn test_parse_lenient_input() {
+        assert_eq!(msgpack!([1u8, -2i8, 2.5f64, "x"]), parse(" [ 1 , -2,2.5 ,\"x\" ] ").unwrap());
+        assert_eq!(Value::Str(vec![0xe2, 0x82, 0xac]), "\"\\u{20ac}\"".parse().unwrap());
+        assert_eq!(Value::Binary(vec![0xab]), parse("bin( AB )").unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Err(ParseError { offset: 3, message: "Expected ',' or ']'" }), parse("[1u"));
+        assert_eq!(Err(ParseError { offset: 0, message: "Invalid number" }), parse("1x"));
+        assert_eq!(Err(ParseError { offset: 4, message: "Trailing characters" }), parse("nil nil"));
+        assert!(parse("").is_err());
+        assert!(parse("300u8").is_err());
+        assert!(parse("256i").is_ok());
+        assert!(parse("-1u").is_err());
+        assert!(parse("bin(abc)").is_err());
+        assert!(parse("ext(200, 00)").is_err());
+        assert!(parse("\"abc").is_err());
+        assert!(parse("\"\\q\"").is_err());
+        assert!(parse("\"\\u{d800}\"").is_err());
+        assert!(parse("{1u 2u}").is_err());
+        assert!(parse("@").is_err());
+    }
+}
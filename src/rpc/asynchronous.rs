@@ -0,0 +1,829 @@
+//! msgpack-rpc on `std::future`, independent of any executor.
+//!
+//! A `Connection` is a future that does all the I/O of one end of a
+//! connection, and that has to be spawned on an executor. It is created
+//! together with an `AsyncClient` to call the peer, whose calls are futures
+//! as well. Requests from the peer are handled by an `AsyncServer`, whose
+//! handlers may return futures, so that one thread can serve many
+//! connections and many requests on each of them.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::{self, Future};
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use super::{RpcMessage, RpcError, RpcCodec, closed};
+use super::super::Value;
+
+/// A byte stream that does not block. Instead, it returns `Poll::Pending`
+/// and wakes the task of `cx` once it can make progress.
+pub trait AsyncTransport {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+}
+
+/// Values that become available one after the other, the asynchronous
+/// version of an `Iterator`.
+pub trait Stream {
+    type Item;
+
+    /// Returns `Poll::Ready(None)` at the end of the stream.
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Self::Item>>;
+
+    /// The next value as a future.
+    fn next(&mut self) -> Next<Self> where Self: Sized {
+        Next { stream: self }
+    }
+}
+
+/// The future returned by `Stream::next`.
+pub struct Next<'a, S: 'a> {
+    stream: &'a mut S
+}
+
+impl<'a, S: Stream> Future for Next<'a, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        self.stream.poll_next(cx)
+    }
+}
+
+/// The result of an asynchronous handler.
+pub type HandlerFuture = Pin<Box<Future<Output = Result<Value, Value>> + Send>>;
+
+pub type AsyncHandler = Box<Fn(Vec<Value>) -> HandlerFuture + Send + Sync>;
+
+/// Dispatches requests and notifications by method name like an
+/// `RpcServer`, to handlers that return futures.
+///
+/// The handlers of one connection run concurrently, so a request can be
+/// answered while an earlier one still waits, and the handlers of
+/// notifications finish in no particular order.
+pub struct AsyncServer {
+    handlers: HashMap<String, AsyncHandler>
+}
+
+impl AsyncServer {
+    pub fn new() -> AsyncServer {
+        AsyncServer { handlers: HashMap::new() }
+    }
+
+    /// Registers a handler that returns its result right away, see
+    /// `RpcServer::register`.
+    pub fn register<F>(&mut self, method: &str, handler: F)
+        where F: Fn(Vec<Value>) -> Result<Value, Value> + Send + Sync + 'static {
+        self.register_async(method, move |params| future::ready(handler(params)));
+    }
+
+    /// Registers a handler that returns the future of its result.
+    pub fn register_async<F, R>(&mut self, method: &str, handler: F)
+        where F: Fn(Vec<Value>) -> R + Send + Sync + 'static,
+              R: Future<Output = Result<Value, Value>> + Send + 'static {
+        self.handlers.insert(method.to_string(), Box::new(move |params| Box::pin(handler(params)) as HandlerFuture));
+    }
+
+    /// Calls the handler for `method`.
+    pub fn dispatch(&self, method: &str, params: Vec<Value>) -> HandlerFuture {
+        match self.handlers.get(method) {
+            Some(handler) => handler(params),
+            None => Box::pin(future::ready(Err(Value::from(format!("Unknown method: {}", method)))))
+        }
+    }
+}
+
+impl Default for AsyncServer {
+    fn default() -> AsyncServer {
+        AsyncServer::new()
+    }
+}
+
+// The state of a connection shared with its clients and calls.
+struct Shared {
+    // encoded messages to send
+    outgoing: Vec<u8>,
+    next_msgid: u32,
+    calls: HashMap<u32, CallState>,
+    notifications: VecDeque<(String, Vec<Value>)>,
+    listening: bool,
+    notifications_waker: Option<Waker>,
+    connection_waker: Option<Waker>,
+    // the number of `AsyncClient`s and `Notifications`
+    handles: usize,
+    closed: bool
+}
+
+enum CallState {
+    Waiting(Option<Waker>),
+    Done(Result<Value, RpcError>)
+}
+
+impl Shared {
+    fn send(&mut self, msg: &RpcMessage) -> Result<(), RpcError> {
+        if self.closed {
+            return Err(closed());
+        }
+        try!(RpcCodec::encode(msg, &mut self.outgoing));
+        Ok(())
+    }
+
+    // Marks the connection as closed and returns the tasks to wake, which
+    // are woken after unlocking.
+    fn close(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        self.closed = true;
+        for state in self.calls.values_mut() {
+            if let CallState::Waiting(ref mut waker) = *state {
+                wakers.extend(waker.take());
+            }
+            if let CallState::Waiting(_) = *state {
+                *state = CallState::Done(Err(closed()));
+            }
+        }
+        wakers.extend(self.notifications_waker.take());
+        wakers
+    }
+}
+
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+/// One end of a connection, a future that reads and writes `transport`
+/// and runs the handlers of the requests from the peer.
+///
+/// A connection without an `AsyncServer` finishes once its `AsyncClient`s
+/// and `Notifications` are dropped and all calls are answered. Every
+/// connection finishes when the peer closes the transport, which fails all
+/// pending calls. The result is an error if the transport failed, or if
+/// the peer sent something that is not a message.
+pub struct Connection<T> {
+    transport: T,
+    shared: Arc<Mutex<Shared>>,
+    server: Option<Arc<AsyncServer>>,
+    codec: RpcCodec,
+    // taken from `Shared::outgoing`, to write without holding the lock
+    writing: Vec<u8>,
+    // the handlers being run, with the msgid of the request
+    running: Vec<(Option<u32>, HandlerFuture)>,
+    eof: bool,
+    // the peer closed the transport before all was written
+    broken: bool
+}
+
+impl<T: AsyncTransport + Unpin> Connection<T> {
+    /// Starts a connection without handlers. Requests from the peer are
+    /// answered with an error, and notifications from the peer are passed
+    /// to the returned `Notifications`.
+    pub fn new(transport: T) -> (Connection<T>, AsyncClient, Notifications) {
+        let connection = Connection::start(transport, None, 2);
+        let notifications = Notifications { shared: connection.shared.clone() };
+        let client = AsyncClient { shared: connection.shared.clone() };
+        (connection, client, notifications)
+    }
+
+    /// Starts a connection on which the requests and notifications from
+    /// the peer are handled by `server`. It runs until the peer closes the
+    /// transport.
+    pub fn with_server(transport: T, server: Arc<AsyncServer>) -> (Connection<T>, AsyncClient) {
+        let connection = Connection::start(transport, Some(server), 1);
+        let client = AsyncClient { shared: connection.shared.clone() };
+        (connection, client)
+    }
+
+    fn start(transport: T, server: Option<Arc<AsyncServer>>, handles: usize) -> Connection<T> {
+        let shared = Shared {
+            outgoing: Vec::new(),
+            next_msgid: 0,
+            calls: HashMap::new(),
+            notifications: VecDeque::new(),
+            listening: server.is_none(),
+            notifications_waker: None,
+            connection_waker: None,
+            handles: handles,
+            closed: false
+        };
+        Connection {
+            transport: transport,
+            shared: Arc::new(Mutex::new(shared)),
+            server: server,
+            codec: RpcCodec::new(),
+            writing: Vec::new(),
+            running: Vec::new(),
+            eof: false,
+            broken: false
+        }
+    }
+
+    // Makes as much progress as possible, and returns whether the
+    // connection is finished.
+    fn run(&mut self, cx: &mut Context) -> Result<bool, RpcError> {
+        // before anything else, so that no wake up is missed
+        self.shared.lock().unwrap().connection_waker = Some(cx.waker().clone());
+        let mut buf = [0; 4096];
+        loop {
+            let mut progress = false;
+
+            let mut i = 0;
+            while i < self.running.len() {
+                let result = match self.running[i].1.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                progress = true;
+                if let (Some(msgid), _) = self.running.swap_remove(i) {
+                    let (error, result) = match result {
+                        Ok(result) => (Value::Nil, result),
+                        Err(error) => (error, Value::Nil)
+                    };
+                    let response = RpcMessage::RpcResponse { msgid: msgid, error: error, result: result };
+                    // once closed, there is nobody to answer
+                    let _ = self.shared.lock().unwrap().send(&response);
+                }
+            }
+
+            if self.writing.is_empty() {
+                mem::swap(&mut self.writing, &mut self.shared.lock().unwrap().outgoing);
+            }
+            while !self.writing.is_empty() && !self.broken {
+                match self.transport.poll_write(cx, &self.writing) {
+                    Poll::Ready(Ok(0)) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Transport closed").into()),
+                    Poll::Ready(Ok(n)) => {
+                        self.writing.drain(.. n);
+                        progress = true;
+                    }
+                    Poll::Ready(Err(ref e)) if e.kind() == io::ErrorKind::BrokenPipe ||
+                                               e.kind() == io::ErrorKind::ConnectionReset => {
+                        // like the end of the input, this is how the peer
+                        // closes the connection
+                        self.eof = true;
+                        self.broken = true;
+                        progress = true;
+                        let wakers = self.shared.lock().unwrap().close();
+                        wake_all(wakers);
+                    }
+                    Poll::Ready(Err(e)) => return Err(e.into()),
+                    Poll::Pending => break
+                }
+            }
+            if self.broken {
+                self.writing.clear();
+                self.shared.lock().unwrap().outgoing.clear();
+            }
+
+            if !self.eof {
+                match self.transport.poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(0)) => {
+                        self.eof = true;
+                        progress = true;
+                        let wakers = self.shared.lock().unwrap().close();
+                        wake_all(wakers);
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        self.codec.feed(&buf[.. n]);
+                        while let Some(msg) = try!(self.codec.decode()) {
+                            self.receive(msg);
+                        }
+                        progress = true;
+                    }
+                    Poll::Ready(Err(e)) => return Err(e.into()),
+                    Poll::Pending => {}
+                }
+            }
+
+            if !progress {
+                break;
+            }
+        }
+
+        let shared = self.shared.lock().unwrap();
+        if !self.running.is_empty() || !self.writing.is_empty() || !shared.outgoing.is_empty() {
+            return Ok(false);
+        }
+        let unused = self.server.is_none() && shared.handles == 0 && shared.calls.is_empty();
+        Ok(self.eof || unused)
+    }
+
+    fn receive(&mut self, msg: RpcMessage) {
+        match msg {
+            RpcMessage::RpcResponse {msgid, error, result} => {
+                let mut shared = self.shared.lock().unwrap();
+                // otherwise the call was dropped, or never made
+                if let Some(&CallState::Waiting(_)) = shared.calls.get(&msgid) {
+                    let result = match error {
+                        Value::Nil => Ok(result),
+                        error => Err(RpcError::Remote(error))
+                    };
+                    if let Some(CallState::Waiting(Some(waker))) = shared.calls.insert(msgid, CallState::Done(result)) {
+                        drop(shared);
+                        waker.wake();
+                    }
+                }
+            }
+            RpcMessage::RpcRequest {msgid, method, params} => {
+                let handler = match self.server {
+                    Some(ref server) => server.dispatch(&method, params),
+                    None => Box::pin(future::ready(Err(Value::from("Requests to a client are not supported"))))
+                };
+                self.running.push((Some(msgid), handler));
+            }
+            RpcMessage::RpcNotification {method, params} => {
+                if let Some(ref server) = self.server {
+                    self.running.push((None, server.dispatch(&method, params)));
+                    return;
+                }
+                let mut shared = self.shared.lock().unwrap();
+                if shared.listening {
+                    shared.notifications.push_back((method, params));
+                    let waker = shared.notifications_waker.take();
+                    drop(shared);
+                    wake_all(waker.into_iter().collect());
+                }
+            }
+        }
+    }
+}
+
+impl<T: AsyncTransport + Unpin> Future for Connection<T> {
+    type Output = Result<(), RpcError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), RpcError>> {
+        let result = match self.run(cx) {
+            Ok(false) => return Poll::Pending,
+            Ok(true) => Ok(()),
+            Err(e) => Err(e)
+        };
+        let wakers = self.shared.lock().unwrap().close();
+        wake_all(wakers);
+        Poll::Ready(result)
+    }
+}
+
+/// Calls the peer of a `Connection`. Clones call the same peer.
+///
+/// Unlike the other clients, it has no middlewares, and neither has an
+/// `AsyncServer`.
+pub struct AsyncClient {
+    shared: Arc<Mutex<Shared>>
+}
+
+impl AsyncClient {
+    /// Calls `method` on the peer. The request is sent by the connection
+    /// right away, in the order of the calls, and not only once the result
+    /// is awaited. Dropping the future cancels the call, and its response
+    /// is discarded.
+    pub fn call(&self, method: &str, params: Vec<Value>) -> Call {
+        let mut shared = self.shared.lock().unwrap();
+        let msgid = shared.next_msgid;
+        shared.next_msgid = shared.next_msgid.wrapping_add(1);
+        let request = RpcMessage::RpcRequest { msgid: msgid, method: method.to_string(), params: params };
+        let state = match shared.send(&request) {
+            Ok(()) => CallState::Waiting(None),
+            Err(e) => CallState::Done(Err(e))
+        };
+        shared.calls.insert(msgid, state);
+        let waker = shared.connection_waker.take();
+        drop(shared);
+        wake_all(waker.into_iter().collect());
+        Call { shared: self.shared.clone(), msgid: msgid }
+    }
+
+    /// Sends a notification, which the peer does not answer.
+    pub fn notify(&self, method: &str, params: Vec<Value>) -> Result<(), RpcError> {
+        let mut shared = self.shared.lock().unwrap();
+        try!(shared.send(&RpcMessage::RpcNotification { method: method.to_string(), params: params }));
+        let waker = shared.connection_waker.take();
+        drop(shared);
+        wake_all(waker.into_iter().collect());
+        Ok(())
+    }
+
+    /// Whether the connection was closed.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().closed
+    }
+}
+
+impl Clone for AsyncClient {
+    fn clone(&self) -> AsyncClient {
+        self.shared.lock().unwrap().handles += 1;
+        AsyncClient { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for AsyncClient {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.handles -= 1;
+        let waker = shared.connection_waker.take();
+        drop(shared);
+        wake_all(waker.into_iter().collect());
+    }
+}
+
+/// The future of the result of `AsyncClient::call`.
+pub struct Call {
+    shared: Arc<Mutex<Shared>>,
+    msgid: u32
+}
+
+impl Future for Call {
+    type Output = Result<Value, RpcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Value, RpcError>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.calls.remove(&self.msgid) {
+            Some(CallState::Done(result)) => Poll::Ready(result),
+            Some(CallState::Waiting(_)) => {
+                shared.calls.insert(self.msgid, CallState::Waiting(Some(cx.waker().clone())));
+                Poll::Pending
+            }
+            None => Poll::Ready(Err(closed()))
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.calls.remove(&self.msgid).is_some() {
+            // the connection may have nothing left to do
+            let waker = shared.connection_waker.take();
+            drop(shared);
+            wake_all(waker.into_iter().collect());
+        }
+    }
+}
+
+/// The notifications from the peer of a `Connection` without a server, in
+/// the order they arrived. The stream ends when the connection does.
+pub struct Notifications {
+    shared: Arc<Mutex<Shared>>
+}
+
+impl Stream for Notifications {
+    type Item = (String, Vec<Value>);
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<(String, Vec<Value>)>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(notification) = shared.notifications.pop_front() {
+            return Poll::Ready(Some(notification));
+        }
+        if shared.closed {
+            return Poll::Ready(None);
+        }
+        shared.notifications_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notifications {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.listening = false;
+        shared.notifications.clear();
+        shared.handles -= 1;
+        let waker = shared.connection_waker.take();
+        drop(shared);
+        wake_all(waker.into_iter().collect());
+    }
+}
+
+/// One end of an in-memory transport, see `duplex`.
+pub struct Duplex {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>
+}
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>
+}
+
+/// Two connected in-memory transports, each buffering up to `capacity`
+/// bytes for the other. Dropping one end closes the connection.
+pub fn duplex(capacity: usize) -> (Duplex, Duplex) {
+    let pipe = || Arc::new(Mutex::new(Pipe {
+        buf: VecDeque::new(),
+        capacity: capacity,
+        closed: false,
+        reader: None,
+        writer: None
+    }));
+    let (a, b) = (pipe(), pipe());
+    (Duplex { read: a.clone(), write: b.clone() }, Duplex { read: b, write: a })
+}
+
+impl AsyncTransport for Duplex {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() {
+            if pipe.closed {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = ::std::cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(.. n)) {
+            *dst = src;
+        }
+        let waker = pipe.writer.take();
+        drop(pipe);
+        wake_all(waker.into_iter().collect());
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+        if pipe.closed {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "Transport closed")));
+        }
+        let n = ::std::cmp::min(buf.len(), pipe.capacity - pipe.buf.len());
+        if n == 0 {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        pipe.buf.extend(&buf[.. n]);
+        let waker = pipe.reader.take();
+        drop(pipe);
+        wake_all(waker.into_iter().collect());
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl Drop for Duplex {
+    fn drop(&mut self) {
+        let mut wakers = Vec::new();
+        for pipe in &[&self.read, &self.write] {
+            let mut pipe = pipe.lock().unwrap();
+            pipe.closed = true;
+            wakers.extend(pipe.reader.take());
+            wakers.extend(pipe.writer.take());
+        }
+        wake_all(wakers);
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<ThreadWaker>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` on the current thread until it completes, e.g. to wait for
+/// a call from synchronous code while the connection runs elsewhere.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, JoinHandle};
+    use super::{AsyncServer, Connection, Stream, block_on, duplex};
+    use super::super::RpcError;
+    use super::super::super::Value;
+
+    type Task = Pin<Box<Future<Output = ()> + Send>>;
+
+    struct Queued {
+        id: usize,
+        queue: Arc<(Mutex<Vec<usize>>, Condvar)>
+    }
+
+    impl Wake for Queued {
+        fn wake(self: Arc<Queued>) {
+            self.queue.0.lock().unwrap().push(self.id);
+            self.queue.1.notify_one();
+        }
+    }
+
+    // A single threaded executor running `tasks` until all are finished.
+    fn spawn_executor(tasks: Vec<Task>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let queue = Arc::new((Mutex::new((0 .. tasks.len()).collect::<Vec<_>>()), Condvar::new()));
+            let mut remaining = tasks.len();
+            let mut tasks: Vec<_> = tasks.into_iter().map(Some).collect();
+            while remaining > 0 {
+                let id = {
+                    let mut ready = queue.0.lock().unwrap();
+                    while ready.is_empty() {
+                        ready = queue.1.wait(ready).unwrap();
+                    }
+                    ready.pop().unwrap()
+                };
+                let done = match tasks[id] {
+                    Some(ref mut task) => {
+                        let waker = Waker::from(Arc::new(Queued { id: id, queue: queue.clone() }));
+                        task.as_mut().poll(&mut Context::from_waker(&waker)).is_ready()
+                    }
+                    None => false
+                };
+                if done {
+                    tasks[id] = None;
+                    remaining -= 1;
+                }
+            }
+        })
+    }
+
+    fn task<F>(connection: F) -> Task where F: Future<Output = Result<(), RpcError>> + Send + 'static {
+        Box::pin(Unwrap(Box::pin(connection)))
+    }
+
+    struct Unwrap<F>(Pin<Box<F>>);
+
+    impl<F: Future<Output = Result<(), RpcError>>> Future for Unwrap<F> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            self.0.as_mut().poll(cx).map(|result| result.unwrap())
+        }
+    }
+
+    fn server() -> Arc<AsyncServer> {
+        let mut server = AsyncServer::new();
+        server.register("add", |params| {
+            Ok(Value::from(params.iter().map(|p| p.as_u64().unwrap()).sum::<u64>()))
+        });
+        server.register("fail", |_| Err(Value::from("failed")));
+        Arc::new(server)
+    }
+
+    fn assert_closed(result: Result<Value, RpcError>) {
+        match result {
+            Err(RpcError::Transport(::byteorder::Error::Io(ref e))) if e.kind() == io::ErrorKind::NotConnected => {}
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_calls() {
+        // a small buffer, so that messages are written in parts
+        let (a, b) = duplex(16);
+        let (client_connection, client, _notifications) = Connection::new(a);
+        let (server_connection, _) = Connection::with_server(b, server());
+        let executor = spawn_executor(vec![task(client_connection), task(server_connection)]);
+
+        assert_eq!(Value::from(3), block_on(client.call("add", vec![Value::from(1), Value::from(2)])).unwrap());
+        match block_on(client.call("fail", vec![])) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("failed"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        match block_on(client.call("sub", vec![])) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Unknown method: sub"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+
+        // pipelined, and awaited in reverse order
+        let calls: Vec<_> = (0 .. 50u64).map(|i| (i, client.call("add", vec![Value::from(i), Value::from(i)]))).collect();
+        drop(client.call("add", vec![Value::from(1)]));
+        for (i, call) in calls.into_iter().rev() {
+            assert_eq!(Value::from(2 * i), block_on(call).unwrap());
+        }
+
+        // both connections finish once the client is gone
+        drop(client);
+        drop(_notifications);
+        executor.join().unwrap();
+    }
+
+    // A handler result that is only available once `release` was called.
+    struct Released(Arc<Mutex<(bool, Option<Waker>)>>);
+
+    impl Future for Released {
+        type Output = Result<Value, Value>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Value, Value>> {
+            let mut state = self.0.lock().unwrap();
+            if state.0 {
+                return Poll::Ready(Ok(Value::from("released")));
+            }
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_async_handlers() {
+        let state = Arc::new(Mutex::new((false, None::<Waker>)));
+        let mut server = AsyncServer::new();
+        let wait_state = state.clone();
+        server.register_async("wait", move |_| Released(wait_state.clone()));
+        server.register("release", move |_| {
+            let mut state = state.lock().unwrap();
+            state.0 = true;
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+            Ok(Value::Nil)
+        });
+        let (a, b) = duplex(1024);
+        let (client_connection, client, notifications) = Connection::new(a);
+        let (server_connection, _) = Connection::with_server(b, Arc::new(server));
+        let executor = spawn_executor(vec![task(client_connection), task(server_connection)]);
+
+        // the waiting handler does not block the connection
+        let wait = client.call("wait", vec![]);
+        assert_eq!(Value::Nil, block_on(client.call("release", vec![])).unwrap());
+        assert_eq!(Value::from("released"), block_on(wait).unwrap());
+
+        drop((client, notifications));
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_notifications() {
+        let (a, b) = duplex(1024);
+        let (a_connection, a_client, a_notifications) = Connection::new(a);
+        let (b_connection, b_client, mut b_notifications) = Connection::new(b);
+        let executor = spawn_executor(vec![task(a_connection), task(b_connection)]);
+
+        for i in 0 .. 10 {
+            a_client.notify("event", vec![Value::from(i)]).unwrap();
+        }
+        for i in 0 .. 10 {
+            assert_eq!(Some(("event".to_string(), vec![Value::from(i)])), block_on(b_notifications.next()));
+        }
+        // neither end has a server
+        match block_on(b_client.call("x", vec![])) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Requests to a client are not supported"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+
+        // when one end is done, the other end is closed
+        drop((a_client, a_notifications));
+        assert_eq!(None, block_on(b_notifications.next()));
+        assert!(b_client.is_closed());
+        assert_closed(block_on(b_client.call("x", vec![])));
+        assert!(b_client.notify("x", vec![]).is_err());
+        executor.join().unwrap();
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (a, b) = duplex(1024);
+        let (connection, client, notifications) = Connection::new(a);
+        let executor = spawn_executor(vec![task(connection)]);
+        // nobody answers on the other end
+        let calls: Vec<_> = (0 .. 3).map(|_| client.call("x", vec![])).collect();
+        drop(b);
+        for call in calls {
+            assert_closed(block_on(call));
+        }
+        executor.join().unwrap();
+        drop((client, notifications));
+    }
+
+    #[test]
+    fn test_many_connections() {
+        let server = server();
+        let mut tasks = Vec::new();
+        let mut clients = Vec::new();
+        for _ in 0 .. 50 {
+            let (a, b) = duplex(64);
+            let (client_connection, client, _) = Connection::new(a);
+            let (server_connection, _) = Connection::with_server(b, server.clone());
+            tasks.push(task(client_connection));
+            tasks.push(task(server_connection));
+            clients.push(client);
+        }
+        // all connections on one thread
+        let executor = spawn_executor(tasks);
+
+        let calls: Vec<_> = clients.iter().enumerate().flat_map(|(i, client)| {
+            (0 .. 5u64).map(move |j| (i as u64 + j, client.call("add", vec![Value::from(i), Value::from(j)])))
+        }).collect();
+        for (sum, call) in calls {
+            assert_eq!(Value::from(sum), block_on(call).unwrap());
+        }
+        drop(clients);
+        executor.join().unwrap();
+    }
+}
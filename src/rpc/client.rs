@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::time::{Duration, Instant};
+use byteorder;
+use rustc_serialize::{Encodable, Decodable};
+use super::{RpcMessage, RpcError, RpcCodec, Middleware, ReadTimeout, write_message, to_params, timed_out,
+            log_discarded};
+use super::middleware::Chain;
+use super::super::{Value, from_value};
+
+/// A msgpack-rpc client on a single connection, e.g. a `TcpStream`.
+///
+/// `call` blocks until the matching response arrives; `call_timeout` gives
+/// up after a while on transports whose reads can time out. Notifications
+/// that the peer sends in the meantime are queued and can be fetched with
+/// `next_notification`. Responses to unknown msgids, including those that
+/// arrive after their call timed out, are passed to the handler set with
+/// `on_discarded`, which by default logs them to standard error. Requests
+/// from the peer are answered with an error. For calls from several
+/// threads, use an `RpcSession`.
+pub struct RpcClient<T: Read + Write> {
+    io: T,
+    // holds the start of a message whose read timed out
+    codec: RpcCodec,
+    next_msgid: u32,
+    notifications: VecDeque<(String, Vec<Value>)>,
+    middlewares: Chain,
+    discarded: Box<FnMut(&RpcMessage) + Send>
+}
+
+impl<T: Read + Write> RpcClient<T> {
+    pub fn new(transport: T) -> RpcClient<T> {
+        RpcClient {
+            io: transport,
+            codec: RpcCodec::new(),
+            next_msgid: 0,
+            notifications: VecDeque::new(),
+            middlewares: Chain::new(),
+            discarded: Box::new(log_discarded)
+        }
+    }
+
+    /// Sets the handler of responses that match no call, e.g. because the
+    /// call timed out.
+    pub fn on_discarded<F>(&mut self, handler: F) where F: FnMut(&RpcMessage) + Send + 'static {
+        self.discarded = Box::new(handler);
+    }
+
+    /// Adds a middleware that sees every call, after the middlewares added
+    /// before. Calls it rejects fail with `RpcError::Rejected`.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Calls `method` and waits for the result.
+    pub fn call(&mut self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        self.call_with(method, params, &mut |_| Ok(()))
+    }
+
+    // `before_read` is called before each read from the transport.
+    fn call_with(&mut self, method: &str, params: Vec<Value>,
+                 before_read: &mut FnMut(&T) -> Result<(), RpcError>) -> Result<Value, RpcError> {
+        // out of the way of the borrow of `self` by the call
+        let middlewares = mem::replace(&mut self.middlewares, Chain::new());
+        let result = middlewares.run_call(method, params, |params| self.call_peer(method, params, before_read));
+        self.middlewares = middlewares;
+        result
+    }
+
+    fn call_peer(&mut self, method: &str, params: Vec<Value>,
+                 before_read: &mut FnMut(&T) -> Result<(), RpcError>) -> Result<Value, RpcError> {
+        let msgid = self.next_msgid;
+        self.next_msgid = self.next_msgid.wrapping_add(1);
+        try!(write_message(&mut self.io, &RpcMessage::RpcRequest {
+            msgid: msgid,
+            method: method.to_string(),
+            params: params
+        }));
+        loop {
+            let msg = try!(self.receive(before_read));
+            match msg {
+                RpcMessage::RpcResponse {msgid: id, ..} if id != msgid => (self.discarded)(&msg),
+                RpcMessage::RpcResponse {error, result, ..} => {
+                    return match error {
+                        Value::Nil => Ok(result),
+                        error => Err(RpcError::Remote(error))
+                    };
+                }
+                RpcMessage::RpcNotification {method, params} => {
+                    self.notifications.push_back((method, params));
+                }
+                RpcMessage::RpcRequest {msgid: id, ..} => try!(self.reject_request(id))
+            }
+        }
+    }
+
+    // Reads until a message is complete. A read that times out fails with
+    // `timed_out`, and the bytes read so far are kept for the next call.
+    fn receive(&mut self, before_read: &mut FnMut(&T) -> Result<(), RpcError>) -> Result<RpcMessage, RpcError> {
+        let mut buf = [0; 4096];
+        loop {
+            if let Some(msg) = try!(self.codec.decode()) {
+                return Ok(msg);
+            }
+            try!(before_read(&self.io));
+            match self.io.read(&mut buf) {
+                Ok(0) => return Err(RpcError::Transport(byteorder::Error::UnexpectedEOF)),
+                Ok(n) => self.codec.feed(&buf[.. n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    return Err(timed_out());
+                }
+                Err(e) => return Err(RpcError::from(e))
+            }
+        }
+    }
+
+    /// Calls `method` with the elements of the tuple `args` as params and
+    /// decodes the result as an `R`, e.g.
+    /// `client.call_typed::<_, u32>("add", (1, 2))`. Fails with
+    /// `RpcError::Type` if the result is not an `R`.
+    pub fn call_typed<A: Encodable, R: Decodable>(&mut self, method: &str, args: A) -> Result<R, RpcError> {
+        let params = try!(to_params(&args).map_err(RpcError::Type));
+        let result = try!(self.call(method, params));
+        from_value(&result).map_err(RpcError::Type)
+    }
+
+    /// Sends a notification, which the peer does not answer.
+    pub fn notify(&mut self, method: &str, params: Vec<Value>) -> Result<(), RpcError> {
+        try!(write_message(&mut self.io, &RpcMessage::RpcNotification {
+            method: method.to_string(),
+            params: params
+        }));
+        Ok(())
+    }
+
+    /// Returns the oldest notification received during a `call`, if any.
+    pub fn next_notification(&mut self) -> Option<(String, Vec<Value>)> {
+        self.notifications.pop_front()
+    }
+
+    /// Blocks until the peer sends a notification. Responses and requests
+    /// are handled as during `call`.
+    pub fn wait_notification(&mut self) -> Result<(String, Vec<Value>), RpcError> {
+        loop {
+            if let Some(n) = self.notifications.pop_front() {
+                return Ok(n);
+            }
+            let msg = try!(self.receive(&mut |_| Ok(())));
+            match msg {
+                RpcMessage::RpcNotification {method, params} => return Ok((method, params)),
+                RpcMessage::RpcResponse {..} => (self.discarded)(&msg),
+                RpcMessage::RpcRequest {msgid: id, ..} => try!(self.reject_request(id))
+            }
+        }
+    }
+
+    fn reject_request(&mut self, msgid: u32) -> Result<(), RpcError> {
+        try!(write_message(&mut self.io, &RpcMessage::RpcResponse {
+            msgid: msgid,
+            error: Value::from("Requests to a client are not supported"),
+            result: Value::Nil
+        }));
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+}
+
+impl<T: Read + Write + ReadTimeout> RpcClient<T> {
+    /// Like `call`, but fails with an `io::ErrorKind::TimedOut` error if the
+    /// response does not arrive within `timeout`. Uses the read timeout of
+    /// the transport, which is cleared again afterwards; failing to clear it
+    /// only fails calls that succeeded.
+    pub fn call_timeout(&mut self, method: &str, params: Vec<Value>, timeout: Duration) -> Result<Value, RpcError> {
+        let deadline = Instant::now() + timeout;
+        let result = self.call_with(method, params, &mut |io| {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(timed_out());
+            }
+            try!(io.set_read_timeout(Some(deadline - now)));
+            Ok(())
+        });
+        let cleared = self.io.set_read_timeout(None);
+        if result.is_ok() {
+            try!(cleared);
+        }
+        result
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::{self, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use super::RpcClient;
+    use super::super::{RpcMessage, RpcError, RpcCodec, read_message, write_message};
+    use super::super::super::Value;
+
+    fn request(rd: &mut BufReader<UnixStream>) -> (u32, String, Vec<Value>) {
+        match read_message(rd).unwrap() {
+            RpcMessage::RpcRequest {msgid, method, params} => (msgid, method, params),
+            msg => panic!("unexpected {:?}", msg)
+        }
+    }
+
+    fn respond(wr: &mut UnixStream, msgid: u32, error: Value, result: Value) {
+        write_message(wr, &RpcMessage::RpcResponse {msgid: msgid, error: error, result: result}).unwrap();
+    }
+
+    #[test]
+    fn test_call() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let peer = thread::spawn(move || {
+            let mut wr = b.try_clone().unwrap();
+            let mut rd = BufReader::new(b);
+            for expected in 0 .. 2 {
+                let (msgid, method, params) = request(&mut rd);
+                assert_eq!(expected, msgid);
+                assert_eq!("add", method);
+                let sum = params.iter().map(|p| p.as_u64().unwrap()).sum::<u64>();
+                respond(&mut wr, msgid, Value::Nil, Value::from(sum));
+            }
+            let (msgid, _, _) = request(&mut rd);
+            respond(&mut wr, msgid, Value::from("boom"), Value::Nil);
+        });
+
+        let mut client = RpcClient::new(a);
+        assert_eq!(Value::Unsigned(3), client.call("add", vec![Value::from(1), Value::from(2)]).unwrap());
+        assert_eq!(Value::Unsigned(0), client.call("add", vec![]).unwrap());
+        match client.call("fail", vec![]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("boom"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        peer.join().unwrap();
+
+        // the peer is gone
+        match client.call("add", vec![]) {
+            Err(RpcError::Transport(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_interleaved_messages() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let peer = thread::spawn(move || {
+            let mut wr = b.try_clone().unwrap();
+            let mut rd = BufReader::new(b);
+            match read_message(&mut rd).unwrap() {
+                RpcMessage::RpcNotification {method, params} => {
+                    assert_eq!("hello", method);
+                    assert_eq!(vec![Value::Nil], params);
+                }
+                msg => panic!("unexpected {:?}", msg)
+            }
+            let (msgid, _, _) = request(&mut rd);
+            write_message(&mut wr, &RpcMessage::RpcNotification {method: "n1".to_string(), params: vec![]}).unwrap();
+            // a stale response and a request to the client
+            respond(&mut wr, msgid + 100, Value::Nil, Value::from("stale"));
+            write_message(&mut wr, &RpcMessage::RpcRequest {msgid: 9, method: "x".to_string(), params: vec![]}).unwrap();
+            match read_message(&mut rd).unwrap() {
+                RpcMessage::RpcResponse {msgid: 9, error, result: Value::Nil} => assert!(!error.is_nil()),
+                msg => panic!("unexpected {:?}", msg)
+            }
+            write_message(&mut wr, &RpcMessage::RpcNotification {method: "n2".to_string(), params: vec![]}).unwrap();
+            respond(&mut wr, msgid, Value::Nil, Value::from("ok"));
+            write_message(&mut wr, &RpcMessage::RpcNotification {method: "n3".to_string(), params: vec![]}).unwrap();
+        });
+
+        let mut client = RpcClient::new(a);
+        let (discarded, stale) = mpsc::channel();
+        client.on_discarded(move |msg| discarded.send(msg.clone()).unwrap());
+        client.notify("hello", vec![Value::Nil]).unwrap();
+        assert_eq!(Value::from("ok"), client.call("get", vec![]).unwrap());
+        assert_eq!(Some(("n1".to_string(), vec![])), client.next_notification());
+        assert_eq!(Some(("n2".to_string(), vec![])), client.next_notification());
+        assert_eq!(None, client.next_notification());
+        assert_eq!(("n3".to_string(), vec![]), client.wait_notification().unwrap());
+        peer.join().unwrap();
+        match stale.try_recv().unwrap() {
+            RpcMessage::RpcResponse {result, ..} => assert_eq!(Value::from("stale"), result),
+            msg => panic!("unexpected {:?}", msg)
+        }
+        assert!(stale.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_call_timeout() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let peer = thread::spawn(move || {
+            let mut wr = b.try_clone().unwrap();
+            let mut rd = BufReader::new(b);
+            let (msgid, _, _) = request(&mut rd);
+            // the late response arrives in two pieces, the first before the
+            // call times out
+            let mut late = Vec::new();
+            RpcCodec::encode(&RpcMessage::RpcResponse {msgid: msgid, error: Value::Nil, result: Value::from("late")},
+                             &mut late).unwrap();
+            wr.write_all(&late[.. 3]).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            wr.write_all(&late[3 ..]).unwrap();
+            let (msgid, _, _) = request(&mut rd);
+            respond(&mut wr, msgid, Value::Nil, Value::from("fast"));
+        });
+
+        let mut client = RpcClient::new(a);
+        let (discarded, late) = mpsc::channel();
+        client.on_discarded(move |msg| discarded.send(msg.clone()).unwrap());
+        match client.call_timeout("slow", vec![], Duration::from_millis(30)) {
+            Err(RpcError::Transport(::byteorder::Error::Io(ref e))) if e.kind() == io::ErrorKind::TimedOut => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        assert_eq!(Value::from("fast"), client.call_timeout("fast", vec![], Duration::from_secs(5)).unwrap());
+        peer.join().unwrap();
+        assert_eq!(None, client.get_ref().read_timeout().unwrap());
+        match late.try_recv().unwrap() {
+            RpcMessage::RpcResponse {msgid: 0, result, ..} => assert_eq!(Value::from("late"), result),
+            msg => panic!("unexpected {:?}", msg)
+        }
+    }
+}
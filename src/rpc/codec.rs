@@ -0,0 +1,219 @@
+use byteorder;
+use super::{RpcMessage, read_message};
+use super::super::{Encoder, MsgpackResult, _invalid_input};
+
+/// Splits a stream of bytes that arrive in arbitrary pieces into
+/// `RpcMessage`s, for transports that cannot block until a message is
+/// complete.
+///
+/// Received bytes are added with `feed`, and `decode` returns the messages
+/// once they are complete. Each `decode` continues the scan of an
+/// incomplete message where the previous one stopped, so a message that
+/// arrives in many small pieces is not parsed from its start every time.
+pub struct RpcCodec {
+    buf: Vec<u8>,
+    // the length of the values of the next message seen so far, and for
+    // each array and map they are in, the number of values still to come
+    scanned: usize,
+    open: Vec<u64>,
+    // the number of elements of the message checked by decoding them
+    checked: usize,
+    max_frame: Option<usize>
+}
+
+impl RpcCodec {
+    pub fn new() -> RpcCodec {
+        RpcCodec { buf: Vec::new(), scanned: 0, open: Vec::new(), checked: 0, max_frame: None }
+    }
+
+    /// Limits the size of a message in bytes. A longer message fails to
+    /// decode as soon as its headers show that it is too long, rather than
+    /// after all of it was buffered. The default `None` means no limit.
+    pub fn set_max_frame(&mut self, max_frame: Option<usize>) {
+        self.max_frame = max_frame;
+    }
+
+    /// Adds received bytes.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The number of received bytes that are not decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Decodes the next message, or returns `None` if it was not received
+    /// completely yet. Fails if the bytes received are no message, or a
+    /// message longer than the maximum frame size, after which the stream
+    /// cannot be decoded any further.
+    pub fn decode(&mut self) -> MsgpackResult<Option<RpcMessage>> {
+        loop {
+            let (size, values) = match try!(measure(&self.buf[self.scanned ..])) {
+                Some(next) => next,
+                None => return Ok(None)
+            };
+            let end = self.scanned as u64 + size;
+            if self.max_frame.map_or(false, |max| end > max as u64) {
+                return Err(_invalid_input("Message longer than the maximum frame size"));
+            }
+            if end > self.buf.len() as u64 {
+                return Ok(None);
+            }
+            self.scanned = end as usize;
+            if values > 0 {
+                self.open.push(values);
+                continue;
+            }
+            // the value is complete, and so are the arrays and maps it ends
+            while let Some(mut remaining) = self.open.pop() {
+                remaining -= 1;
+                if remaining > 0 {
+                    self.open.push(remaining);
+                    break;
+                }
+            }
+            if self.open.is_empty() {
+                break;
+            }
+            // the type and the msgid or method are decoded once they are
+            // complete, so that an invalid message fails early
+            if self.open.len() == 1 && self.checked < 2 {
+                self.checked += 1;
+                match read_message(&mut &self.buf[.. self.scanned]) {
+                    Err(byteorder::Error::UnexpectedEOF) => {}
+                    Err(e) => return Err(e),
+                    Ok(_) => unreachable!()
+                }
+            }
+        }
+        let msg = try!(read_message(&mut &self.buf[.. self.scanned]));
+        self.buf.drain(.. self.scanned);
+        self.scanned = 0;
+        self.checked = 0;
+        Ok(Some(msg))
+    }
+
+    /// Appends the encoding of `msg` to `buf`.
+    pub fn encode(msg: &RpcMessage, buf: &mut Vec<u8>) -> MsgpackResult<()> {
+        buf.extend_from_slice(&try!(Encoder::to_msgpack(msg)));
+        Ok(())
+    }
+}
+
+// The size of the value at the start of `data` without the values nested in
+// it, and the number of those. `None` if `data` ends within the length of
+// the value.
+fn measure(data: &[u8]) -> MsgpackResult<Option<(u64, u64)>> {
+    let marker = match data.first() {
+        Some(&marker) => marker,
+        None => return Ok(None)
+    };
+    let size = match marker {
+        0x80 ... 0x8f => return Ok(Some((1, 2 * (marker & 0x0f) as u64))),
+        0x90 ... 0x9f => return Ok(Some((1, (marker & 0x0f) as u64))),
+        0xa0 ... 0xbf => 1 + (marker & 0x1f) as u64,
+        0xc1 => return Err(_invalid_input("Invalid marker")),
+        0xcc | 0xd0 => 2,
+        0xcd | 0xd1 => 3,
+        0xca | 0xce | 0xd2 => 5,
+        0xcb | 0xcf | 0xd3 => 9,
+        0xd4 ... 0xd8 => 2 + (1 << (marker - 0xd4)),
+        0xc4 ... 0xc9 | 0xd9 ... 0xdf => 0,
+        _ => 1
+    };
+    if size > 0 {
+        return Ok(Some((size, 0)));
+    }
+    let len_size = match marker {
+        0xc4 | 0xc7 | 0xd9 => 1,
+        0xc5 | 0xc8 | 0xda | 0xdc | 0xde => 2,
+        _ => 4
+    };
+    if data.len() < 1 + len_size {
+        return Ok(None);
+    }
+    let len = data[1 .. 1 + len_size].iter().fold(0, |len, &b| len << 8 | b as u64);
+    let header = 1 + len_size as u64;
+    Ok(Some(match marker {
+        0xdc | 0xdd => (header, len),
+        0xde | 0xdf => (header, 2 * len),
+        // the type of an ext value
+        0xc7 ... 0xc9 => (header + 1 + len, 0),
+        _ => (header + len, 0)
+    }))
+}
+
+impl Default for RpcCodec {
+    fn default() -> RpcCodec {
+        RpcCodec::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RpcCodec;
+    use super::super::RpcMessage;
+    use super::super::super::Value;
+
+    #[test]
+    fn test_partial_messages() {
+        let messages = vec![
+            RpcMessage::RpcRequest { msgid: 1, method: "add".to_string(), params: vec![Value::from(1), Value::from(2)] },
+            RpcMessage::RpcNotification { method: "log".to_string(), params: vec![Value::from(vec![0u8; 300])] },
+            RpcMessage::RpcNotification { method: "x".repeat(40), params: vec![
+                Value::Map(vec![(Value::from("k"), Value::Extended(5, vec![1, 2, 3])),
+                                (Value::from(-200), Value::Extended(6, vec![0; 16]))]),
+                Value::Extended(7, vec![0; 300]), Value::from(1.5f64), Value::from(70000)] },
+            RpcMessage::RpcResponse { msgid: 1, error: Value::Nil, result: Value::from(3) }];
+        let mut bytes = Vec::new();
+        for msg in &messages {
+            RpcCodec::encode(msg, &mut bytes).unwrap();
+        }
+
+        // a byte at a time
+        let mut codec = RpcCodec::new();
+        let mut decoded = Vec::new();
+        for b in &bytes {
+            codec.feed(&[*b]);
+            while let Some(msg) = codec.decode().unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert_eq!(messages, decoded);
+        assert_eq!(0, codec.buffered());
+
+        // all at once
+        codec.feed(&bytes);
+        for msg in &messages {
+            assert_eq!(Some(msg.clone()), codec.decode().unwrap());
+        }
+        assert_eq!(None, codec.decode().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_message() {
+        let mut codec = RpcCodec::new();
+        codec.feed(&[0x94, 0x00]);
+        assert_eq!(None, codec.decode().unwrap());
+        codec.feed(&[0xc3]);
+        assert!(codec.decode().is_err());
+    }
+
+    #[test]
+    fn test_max_frame() {
+        let msg = RpcMessage::RpcNotification { method: "log".to_string(), params: vec![Value::Binary(vec![0; 300])] };
+        let mut bytes = Vec::new();
+        RpcCodec::encode(&msg, &mut bytes).unwrap();
+
+        let mut codec = RpcCodec::new();
+        codec.set_max_frame(Some(bytes.len()));
+        codec.feed(&bytes);
+        assert_eq!(Some(msg), codec.decode().unwrap());
+
+        // fails as soon as the length of the binary is known
+        codec.set_max_frame(Some(bytes.len() - 1));
+        codec.feed(&bytes[.. 10]);
+        assert!(codec.decode().is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use rustc_serialize::Decodable;
+use super::{RpcError, from_params};
+use super::super::Value;
+
+/// Observes and intercepts the calls of an `RpcServer`, an `RpcClient` or
+/// an `RpcSession`, see their `add_middleware`.
+///
+/// For each call, `before_call` of the middlewares is called in the order
+/// they were added, then the handler or the peer, and then `after_call` in
+/// the reverse order. The server calls the hooks for notifications as
+/// well, whose results are dropped.
+pub trait Middleware: Send + Sync {
+    /// Called before the call. An error ends the call with this error
+    /// instead, and only the middlewares before this one see `after_call`.
+    fn before_call(&self, _method: &str, _params: &[Value]) -> Result<(), Value> {
+        Ok(())
+    }
+
+    /// Called with the result of the call and how long the handler or the
+    /// peer took, which is zero for calls that a middleware rejected.
+    /// Errors of the transport are passed as their description.
+    fn after_call(&self, _method: &str, _params: &[Value], _duration: Duration, _result: &Result<Value, Value>) {}
+}
+
+impl<M: Middleware> Middleware for Arc<M> {
+    fn before_call(&self, method: &str, params: &[Value]) -> Result<(), Value> {
+        (**self).before_call(method, params)
+    }
+
+    fn after_call(&self, method: &str, params: &[Value], duration: Duration, result: &Result<Value, Value>) {
+        (**self).after_call(method, params, duration, result)
+    }
+}
+
+/// The middlewares of a server or client.
+#[derive(Default, Clone)]
+pub struct Chain {
+    middlewares: Vec<Arc<Middleware>>,
+    // whether any of them might implement `after_call`, so that the params
+    // need to be kept for it. A `Validator` only checks calls.
+    after_call: bool
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain { middlewares: Vec::new(), after_call: false }
+    }
+
+    pub fn push<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.after_call |= TypeId::of::<M>() != TypeId::of::<Validator>();
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    /// Runs `call` with `params` between the hooks. Returns its result, or
+    /// the error of the middleware that rejected the call.
+    pub fn run<F>(&self, method: &str, params: Vec<Value>, call: F) -> Result<Value, Value>
+        where F: FnOnce(Vec<Value>) -> Result<Value, Value> {
+        if self.middlewares.is_empty() {
+            return call(params);
+        }
+        let mut passed = 0;
+        let mut rejected = None;
+        for middleware in &self.middlewares {
+            if let Err(e) = middleware.before_call(method, &params) {
+                rejected = Some(e);
+                break;
+            }
+            passed += 1;
+        }
+        let (result, duration) = match rejected {
+            Some(e) => (Err(e), Duration::from_secs(0)),
+            None => {
+                if !self.after_call {
+                    return call(params);
+                }
+                let start = Instant::now();
+                let result = call(params.clone());
+                (result, start.elapsed())
+            }
+        };
+        for middleware in self.middlewares[.. passed].iter().rev() {
+            middleware.after_call(method, &params, duration, &result);
+        }
+        result
+    }
+
+    /// Like `run`, for the calls of a client: `call` sends the call to the
+    /// peer, and a rejected call fails with `RpcError::Rejected`.
+    pub fn run_call<F>(&self, method: &str, params: Vec<Value>, call: F) -> Result<Value, RpcError>
+        where F: FnOnce(Vec<Value>) -> Result<Value, RpcError> {
+        if self.middlewares.is_empty() {
+            return call(params);
+        }
+        let mut error = None;
+        let result = self.run(method, params, |params| {
+            call(params).map_err(|e| {
+                let value = e.to_value();
+                error = Some(e);
+                value
+            })
+        });
+        // without an error of the call, a middleware rejected it
+        result.map_err(|rejected| error.unwrap_or(RpcError::Rejected(rejected)))
+    }
+}
+
+/// Writes a line for each call to a log, e.g.
+/// `add([1u, 2u]) -> 3u in 52.1µs` or `add([]) -> error "Too few" in 7µs`.
+pub struct Logger {
+    log: Mutex<Box<Write + Send>>
+}
+
+impl Logger {
+    pub fn new<W: Write + Send + 'static>(log: W) -> Logger {
+        Logger { log: Mutex::new(Box::new(log)) }
+    }
+
+    /// Logs to standard error.
+    pub fn stderr() -> Logger {
+        Logger::new(io::stderr())
+    }
+}
+
+impl Middleware for Logger {
+    fn after_call(&self, method: &str, params: &[Value], duration: Duration, result: &Result<Value, Value>) {
+        let params = Value::Array(params.to_vec());
+        let mut log = self.log.lock().unwrap();
+        // a failing log must not fail the calls
+        let _ = match *result {
+            Ok(ref result) => writeln!(log, "{}({}) -> {} in {:?}", method, params, result, duration),
+            Err(ref error) => writeln!(log, "{}({}) -> error {} in {:?}", method, params, error, duration)
+        };
+    }
+}
+
+/// The calls of one method recorded by `Metrics`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MethodStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration
+}
+
+impl MethodStats {
+    /// The average duration of a call.
+    pub fn mean(&self) -> Duration {
+        if self.calls == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_nanos((self.total.as_nanos() / self.calls as u128) as u64)
+    }
+}
+
+/// Records the number of calls, errors and their latency per method. Add
+/// it as an `Arc` to read the numbers while it is in use.
+pub struct Metrics {
+    methods: Mutex<HashMap<String, MethodStats>>
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { methods: Mutex::new(HashMap::new()) }
+    }
+
+    /// The numbers for `method`, if it was called.
+    pub fn get(&self, method: &str) -> Option<MethodStats> {
+        self.methods.lock().unwrap().get(method).cloned()
+    }
+
+    /// The numbers of all methods called so far.
+    pub fn snapshot(&self) -> HashMap<String, MethodStats> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    pub fn reset(&self) {
+        self.methods.lock().unwrap().clear();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Middleware for Metrics {
+    fn after_call(&self, method: &str, _params: &[Value], duration: Duration, result: &Result<Value, Value>) {
+        let mut methods = self.methods.lock().unwrap();
+        let stats = methods.entry(method.to_string()).or_insert(MethodStats {
+            calls: 0,
+            errors: 0,
+            total: Duration::from_secs(0),
+            min: duration,
+            max: duration
+        });
+        stats.calls += 1;
+        if result.is_err() {
+            stats.errors += 1;
+        }
+        stats.total += duration;
+        if duration < stats.min {
+            stats.min = duration;
+        }
+        if duration > stats.max {
+            stats.max = duration;
+        }
+    }
+}
+
+/// A check of the params of a method, see `Validator::check`.
+pub type Check = Box<Fn(&[Value]) -> Result<(), Value> + Send + Sync>;
+
+/// Rejects calls whose params fail the checks registered for their method.
+/// Methods without checks are not restricted.
+pub struct Validator {
+    checks: HashMap<String, Vec<Check>>
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator { checks: HashMap::new() }
+    }
+
+    /// Adds a check for `method`, whose error rejects the call.
+    pub fn check<F>(mut self, method: &str, check: F) -> Validator
+        where F: Fn(&[Value]) -> Result<(), Value> + Send + Sync + 'static {
+        self.checks.entry(method.to_string()).or_insert_with(Vec::new).push(Box::new(check));
+        self
+    }
+
+    /// Requires exactly `count` params.
+    pub fn arity(self, method: &str, count: usize) -> Validator {
+        let name = method.to_string();
+        self.check(method, move |params| {
+            if params.len() == count {
+                Ok(())
+            } else {
+                Err(Value::from(format!("Invalid params for {}: expected {} arguments, got {}", name, count,
+                                        params.len())))
+            }
+        })
+    }
+
+    /// Requires the params to decode as the tuple `A`, like the arguments
+    /// of a handler registered with `RpcServer::register_typed`.
+    pub fn typed<A: Decodable + 'static>(self, method: &str) -> Validator {
+        let name = method.to_string();
+        self.check(method, move |params| {
+            match from_params::<A>(params.to_vec()) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(Value::from(format!("Invalid params for {}: {}", name, e)))
+            }
+        })
+    }
+}
+
+impl Default for Validator {
+    fn default() -> Validator {
+        Validator::new()
+    }
+}
+
+impl Middleware for Validator {
+    fn before_call(&self, method: &str, params: &[Value]) -> Result<(), Value> {
+        if let Some(checks) = self.checks.get(method) {
+            for check in checks {
+                try!(check(params));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use super::{Middleware, Logger, Metrics, Validator};
+    use super::super::{RpcServer, RpcError};
+    use super::super::super::Value;
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Records the hooks it sees.
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Trace {
+        fn before_call(&self, method: &str, params: &[Value]) -> Result<(), Value> {
+            self.1.lock().unwrap().push(format!("{} before {}({})", self.0, method, params.len()));
+            Ok(())
+        }
+
+        fn after_call(&self, method: &str, _params: &[Value], _duration: Duration, result: &Result<Value, Value>) {
+            self.1.lock().unwrap().push(format!("{} after {} {}", self.0, method, result.is_ok()));
+        }
+    }
+
+    fn server() -> RpcServer {
+        let mut server = RpcServer::new();
+        server.register_typed("add", |(a, b): (u64, u64)| Ok(a + b));
+        server.register("fail", |_| Err(Value::from("failed")));
+        server
+    }
+
+    #[test]
+    fn test_order() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let mut server = server();
+        server.add_middleware(Trace("outer", trace.clone()));
+        server.add_middleware(Validator::new().arity("add", 2));
+        server.add_middleware(Trace("inner", trace.clone()));
+
+        assert_eq!(Ok(Value::from(3)), server.dispatch("add", vec![Value::from(1), Value::from(2)]));
+        assert_eq!(Err(Value::from("Invalid params for add: expected 2 arguments, got 1")),
+                   server.dispatch("add", vec![Value::from(1)]));
+        assert_eq!(vec!["outer before add(2)", "inner before add(2)", "inner after add true", "outer after add true",
+                        // the validator rejected the call
+                        "outer before add(1)", "outer after add false"],
+                   *trace.lock().unwrap());
+    }
+
+    #[test]
+    fn test_logger_and_metrics() {
+        let log = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let metrics = Arc::new(Metrics::new());
+        let mut server = server();
+        server.add_middleware(Logger::new(log.clone()));
+        server.add_middleware(metrics.clone());
+
+        server.dispatch("add", vec![Value::from(1), Value::from(2)]).unwrap();
+        server.dispatch("add", vec![Value::from(3), Value::from(4)]).unwrap();
+        server.dispatch("fail", vec![Value::Nil]).unwrap_err();
+        server.dispatch("nothing", vec![]).unwrap_err();
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = log.lines().map(|l| &l[.. l.rfind(" in ").unwrap()]).collect();
+        assert_eq!(vec!["add([1i, 2i]) -> 3u", "add([3i, 4i]) -> 7u", "fail([nil]) -> error \"failed\"",
+                        "nothing([]) -> error \"Unknown method: nothing\""], lines);
+
+        let add = metrics.get("add").unwrap();
+        assert_eq!((2, 0), (add.calls, add.errors));
+        assert!(add.min <= add.mean() && add.mean() <= add.max && add.max <= add.total);
+        assert_eq!((1, 1), (metrics.get("fail").unwrap().calls, metrics.get("fail").unwrap().errors));
+        assert_eq!(3, metrics.snapshot().len());
+        assert_eq!(None, metrics.get("sub"));
+        metrics.reset();
+        assert_eq!(None, metrics.get("add"));
+    }
+
+    // Records the durations it sees, and takes its time to let calls pass.
+    struct Durations(Duration, Arc<Mutex<Vec<Duration>>>);
+
+    impl Middleware for Durations {
+        fn before_call(&self, _method: &str, _params: &[Value]) -> Result<(), Value> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn after_call(&self, _method: &str, _params: &[Value], duration: Duration, _result: &Result<Value, Value>) {
+            self.1.lock().unwrap().push(duration);
+        }
+    }
+
+    #[test]
+    fn test_duration() {
+        let durations = Arc::new(Mutex::new(Vec::new()));
+        let mut server = server();
+        server.register("sleep", |_| {
+            thread::sleep(Duration::from_millis(50));
+            Ok(Value::Nil)
+        });
+        server.add_middleware(Durations(Duration::from_millis(200), durations.clone()));
+        server.add_middleware(Validator::new().arity("sleep", 0));
+
+        server.dispatch("sleep", vec![]).unwrap();
+        server.dispatch("sleep", vec![Value::Nil]).unwrap_err();
+        let durations = durations.lock().unwrap();
+        // only the handler is timed, not the sleep in `before_call`
+        assert!(durations[0] >= Duration::from_millis(50) && durations[0] < Duration::from_millis(250));
+        // the validator rejected the call
+        assert_eq!(Duration::from_secs(0), durations[1]);
+    }
+
+    #[test]
+    fn test_validator() {
+        let validator = Validator::new()
+            .typed::<(u32, String)>("store")
+            .check("store", |params| match params[0].as_u64() {
+                Some(0) => Err(Value::from("Zero is reserved")),
+                _ => Ok(())
+            });
+        let mut server = RpcServer::new();
+        server.register("store", |_| Ok(Value::Boolean(true)));
+        server.add_middleware(validator);
+
+        assert_eq!(Ok(Value::Boolean(true)), server.dispatch("store", vec![Value::from(1), Value::from("a")]));
+        assert_eq!(Err(Value::from("Zero is reserved")), server.dispatch("store", vec![Value::from(0), Value::from("a")]));
+        for params in vec![vec![Value::from(1)], vec![Value::from("a"), Value::from(1)], vec![Value::from(-1), Value::from("a")]] {
+            match server.dispatch("store", params) {
+                Err(Value::Str(e)) => assert!(e.starts_with(b"Invalid params for store: ")),
+                r => panic!("unexpected {:?}", r)
+            }
+        }
+        // other methods are not checked
+        assert!(server.dispatch("other", vec![Value::from(0)]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_client() {
+        use std::os::unix::net::UnixStream;
+        use super::super::RpcClient;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let server = server();
+        let handle = thread::spawn(move || server.serve(b).unwrap());
+
+        let metrics = Arc::new(Metrics::new());
+        let mut client = RpcClient::new(a);
+        client.add_middleware(Validator::new().arity("add", 2));
+        client.add_middleware(metrics.clone());
+        assert_eq!(Value::from(5), client.call("add", vec![Value::from(2), Value::from(3)]).unwrap());
+        assert_eq!(5, client.call_typed::<_, u32>("add", (1, 4)).unwrap());
+        match client.call("add", vec![]) {
+            Err(RpcError::Rejected(e)) => assert_eq!(Value::from("Invalid params for add: expected 2 arguments, got 0"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        match client.call("fail", vec![]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("failed"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        // the rejected call was not counted
+        assert_eq!((2, 0), (metrics.get("add").unwrap().calls, metrics.get("add").unwrap().errors));
+        assert_eq!(1, metrics.get("fail").unwrap().errors);
+
+        drop(client);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! msgpack-rpc messages, see
+//! https://github.com/msgpack-rpc/msgpack-rpc/blob/master/spec.md
+
+use std::io::{self, Read, Write};
+use byteorder;
+use super::{Value, MsgpackResult, to_value, from_value, _invalid_input};
+use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+
+pub use self::client::RpcClient;
+pub use self::codec::RpcCodec;
+pub use self::middleware::{Middleware, Logger, Metrics, MethodStats, Validator};
+pub use self::record::{Recorder, Recording, ReplayTransport, LogEntry, Direction, read_log};
+pub use self::server::{RpcServer, Handler};
+pub use self::session::{RpcSession, RpcPeer};
+pub use self::transport::{RpcListener, Listener, ReadTimeout, connect_tcp, stdio, spawn, StdioTransport, ChildTransport};
+#[cfg(unix)]
+pub use self::transport::connect_unix;
+
+pub mod asynchronous;
+mod client;
+mod codec;
+mod middleware;
+mod record;
+mod server;
+mod session;
+mod transport;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcMessage {
+    RpcRequest      {msgid: u32, method: String, params: Vec<Value>}, // 0
+    RpcResponse     {msgid: u32, error: Value, result: Value}, // 1
+    RpcNotification {method: String, params: Vec<Value>} // 2
+}
+
+impl Encodable for RpcMessage {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        match *self {
+            RpcMessage::RpcRequest {msgid, ref method, ref params} => {
+                (0usize, msgid, method, params).encode(s)
+            }
+            RpcMessage::RpcResponse {msgid, ref error, ref result} => {
+                (1usize, msgid, error, result).encode(s)
+            }
+            RpcMessage::RpcNotification {ref method, ref params} => {
+                (2usize, method, params).encode(s)
+            }
+        }
+    }
+}
+
+impl Decodable for RpcMessage {
+    fn decode<D: Decoder>(d: &mut D) -> Result<RpcMessage, D::Error> {
+        d.read_seq(|d, len| {
+            if len == 0 { return Err(d.error("Invalid msgpack-rpc message array length")) }
+            let ty: usize = try!(d.read_seq_elt(0, Decodable::decode));
+
+            match (ty, len) {
+                (0, 4) => {
+                    let msgid = try!(d.read_seq_elt(1, Decodable::decode));
+                    let method = try!(d.read_seq_elt(2, Decodable::decode));
+                    let params = try!(d.read_seq_elt(3, Decodable::decode));
+                    Ok(RpcMessage::RpcRequest {msgid: msgid, method: method, params: params})
+                }
+                (1, 4) => {
+                    let msgid = try!(d.read_seq_elt(1, Decodable::decode));
+                    let error = try!(d.read_seq_elt(2, Decodable::decode));
+                    let result = try!(d.read_seq_elt(3, Decodable::decode));
+                    Ok(RpcMessage::RpcResponse {msgid: msgid, error: error, result: result})
+                }
+                (2, 3) => {
+                    let method = try!(d.read_seq_elt(1, Decodable::decode));
+                    let params = try!(d.read_seq_elt(2, Decodable::decode));
+                    Ok(RpcMessage::RpcNotification {method: method, params: params})
+                }
+                (0, _) | (1, _) | (2, _) => {
+                    Err(d.error("Invalid msgpack-rpc message array length"))
+                }
+                _ => {
+                    Err(d.error("Invalid msgpack-rpc message type"))
+                }
+            }
+        })
+    }
+}
+
+/// Why a call failed.
+#[derive(Debug)]
+pub enum RpcError {
+    /// The error value of the response
+    Remote(Value),
+    /// Encoding or decoding a message, or the transport, failed
+    Transport(byteorder::Error),
+    /// The arguments or the result of a typed call do not match their types
+    Type(byteorder::Error),
+    /// A middleware of the client rejected the call, which was not sent
+    Rejected(Value)
+}
+
+impl RpcError {
+    /// The error as the error value of a response, for handlers that pass
+    /// on errors of their own calls.
+    pub fn into_value(self) -> Value {
+        match self {
+            RpcError::Remote(v) | RpcError::Rejected(v) => v,
+            RpcError::Transport(e) => Value::from(format!("{:?}", e)),
+            RpcError::Type(e) => Value::from(e.to_string())
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        match *self {
+            RpcError::Remote(ref v) | RpcError::Rejected(ref v) => v.clone(),
+            RpcError::Transport(ref e) => Value::from(format!("{:?}", e)),
+            RpcError::Type(ref e) => Value::from(e.to_string())
+        }
+    }
+}
+
+/// The error of calls on a connection that was closed.
+fn closed() -> RpcError {
+    RpcError::Transport(byteorder::Error::Io(io::Error::new(io::ErrorKind::NotConnected, "Connection closed")))
+}
+
+/// The error of calls whose response did not arrive in time.
+fn timed_out() -> RpcError {
+    RpcError::Transport(byteorder::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "Call timed out")))
+}
+
+/// The default handler of responses that match no pending call.
+fn log_discarded(msg: &RpcMessage) {
+    if let RpcMessage::RpcResponse {msgid, ..} = *msg {
+        let _ = writeln!(io::stderr(), "msgpack-rpc: discarding response to msgid {} without pending call", msgid);
+    }
+}
+
+impl From<byteorder::Error> for RpcError {
+    fn from(err: byteorder::Error) -> RpcError {
+        RpcError::Transport(err)
+    }
+}
+
+impl From<io::Error> for RpcError {
+    fn from(err: io::Error) -> RpcError {
+        RpcError::Transport(byteorder::Error::Io(err))
+    }
+}
+
+/// Writes `msg` with a single write and flushes `wr`.
+pub fn write_message<W: Write>(wr: &mut W, msg: &RpcMessage) -> MsgpackResult<()> {
+    let buf = try!(super::Encoder::to_msgpack(msg));
+    try!(wr.write_all(&buf));
+    try!(wr.flush());
+    Ok(())
+}
+
+/// Reads the next message from `rd`, which should be buffered.
+pub fn read_message<R: Read>(rd: &mut R) -> MsgpackResult<RpcMessage> {
+    Decodable::decode(&mut super::Decoder::new(rd))
+}
+
+/// Encodes the arguments of a typed call, a tuple or `()`, as params.
+fn to_params<A: Encodable>(args: &A) -> MsgpackResult<Vec<Value>> {
+    match try!(to_value(args)) {
+        Value::Array(params) => Ok(params),
+        Value::Nil => Ok(Vec::new()),
+        _ => Err(_invalid_input("Arguments must be a tuple"))
+    }
+}
+
+/// Decodes params as the arguments of a typed handler.
+fn from_params<A: Decodable>(params: Vec<Value>) -> MsgpackResult<A> {
+    let empty = params.is_empty();
+    match from_value(&Value::Array(params)) {
+        Err(_) if empty => from_value(&Value::Nil),
+        args => args
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RpcMessage;
+    use super::super::{Encoder, Value, from_msgpack};
+
+    fn round_trip(msg: RpcMessage, bytes: &[u8]) {
+        assert_eq!(bytes, &Encoder::to_msgpack(&msg).unwrap()[..]);
+        assert_eq!(msg, from_msgpack(bytes).unwrap());
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        format!("{:?}", from_msgpack::<RpcMessage>(bytes).unwrap_err())
+    }
+
+    #[test]
+    fn test_request() {
+        round_trip(RpcMessage::RpcRequest {msgid: 7, method: "add".to_string(),
+                                           params: vec![Value::Unsigned(1), Value::Str(b"x".to_vec())]},
+                   &[0x94, 0x00, 0x07, 0xa3, b'a', b'd', b'd', 0x92, 0x01, 0xa1, b'x']);
+        round_trip(RpcMessage::RpcRequest {msgid: 0xffffffff, method: String::new(), params: vec![]},
+                   &[0x94, 0x00, 0xce, 0xff, 0xff, 0xff, 0xff, 0xa0, 0x90]);
+    }
+
+    #[test]
+    fn test_response() {
+        round_trip(RpcMessage::RpcResponse {msgid: 7, error: Value::Nil, result: Value::Array(vec![Value::Boolean(true)])},
+                   &[0x94, 0x01, 0x07, 0xc0, 0x91, 0xc3]);
+        round_trip(RpcMessage::RpcResponse {msgid: 8, error: Value::Str(b"e".to_vec()), result: Value::Nil},
+                   &[0x94, 0x01, 0x08, 0xa1, b'e', 0xc0]);
+    }
+
+    #[test]
+    fn test_notification() {
+        round_trip(RpcMessage::RpcNotification {method: "n".to_string(), params: vec![Value::Binary(vec![1])]},
+                   &[0x93, 0x02, 0xa1, b'n', 0x91, 0xc4, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn test_malformed_length() {
+        assert!(error(&[0x90]).contains("array length"));
+        for &(ty, ok) in &[(0u8, 4u8), (1, 4), (2, 3)] {
+            for len in 1 .. 6 {
+                if len == ok { continue }
+                // the elements after the type are never read
+                let e = error(&[0x90 | len, ty, 0xc0, 0xc0, 0xc0, 0xc0]);
+                assert!(e.contains("Invalid msgpack-rpc message array length"), "{} {}: {}", ty, len, e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        assert!(error(&[0x94, 0x03, 0x01, 0xa0, 0x90]).contains("Invalid msgpack-rpc message type"));
+        // not an array, type not an integer, wrong field types, truncated
+        assert!(from_msgpack::<RpcMessage>(&[0x80]).is_err());
+        assert!(from_msgpack::<RpcMessage>(&[0x94, 0xa0, 0x01, 0xa0, 0x90]).is_err());
+        assert!(from_msgpack::<RpcMessage>(&[0x94, 0x00, 0xa0, 0xa0, 0x90]).is_err());
+        assert!(from_msgpack::<RpcMessage>(&[0x94, 0x00, 0x01, 0x01, 0x90]).is_err());
+        assert!(from_msgpack::<RpcMessage>(&[0x94, 0x00, 0x01, 0xa0, 0xc0]).is_err());
+        assert!(from_msgpack::<RpcMessage>(&[0x94, 0x00, 0x01, 0xa0]).is_err());
+        assert!(from_msgpack::<RpcMessage>(&[0x93, 0x02, 0xa1, b'n']).is_err());
+    }
+}
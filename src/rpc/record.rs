@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, ReadBytesExt};
+use rustc_serialize::Decodable;
+use super::{RpcMessage, RpcCodec};
+use super::super::{Decoder, Encoder, Value, MsgpackResult, _invalid_input};
+use super::super::encoder;
+
+/// Whether a logged message was sent or received by the recorded side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received
+}
+
+/// A message in the log written by a `Recorder`.
+///
+/// Each entry is written as the msgpack array
+/// `["sent" or "received", timestamp, message]`, where the timestamp is of
+/// the predefined timestamp ext type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub direction: Direction,
+    pub time: SystemTime,
+    pub message: RpcMessage
+}
+
+impl LogEntry {
+    pub fn write<W: Write>(&self, wr: &mut W) -> MsgpackResult<()> {
+        let (secs, nsecs) = match self.time.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+            Err(e) => {
+                let d = e.duration();
+                match d.subsec_nanos() {
+                    0 => (-(d.as_secs() as i64), 0),
+                    nsecs => (-(d.as_secs() as i64) - 1, 1_000_000_000 - nsecs)
+                }
+            }
+        };
+        let mut buf = Vec::new();
+        try!(encoder::encode_vec_len(&mut buf, 3));
+        try!(encoder::encode_str(&mut buf, match self.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received"
+        }));
+        try!(encoder::encode_timestamp(&mut buf, secs, nsecs));
+        buf.extend_from_slice(&try!(Encoder::to_msgpack(&self.message)));
+        // a single write, so that entries of several threads do not mix
+        try!(wr.write_all(&buf));
+        Ok(())
+    }
+
+    pub fn read<R: Read>(rd: &mut R) -> MsgpackResult<LogEntry> {
+        let (direction, time, message): (String, Value, RpcMessage) = try!(Decodable::decode(&mut Decoder::new(rd)));
+        let direction = match &direction[..] {
+            "sent" => Direction::Sent,
+            "received" => Direction::Received,
+            _ => return Err(_invalid_input("Invalid log entry direction"))
+        };
+        let time = match time {
+            Value::Extended(-1, ref data) => try!(read_timestamp(data)),
+            _ => return Err(_invalid_input("Invalid log entry timestamp"))
+        };
+        Ok(LogEntry { direction: direction, time: time, message: message })
+    }
+}
+
+fn read_timestamp(mut data: &[u8]) -> MsgpackResult<SystemTime> {
+    let (secs, nsecs) = match data.len() {
+        4 => (try!(data.read_u32::<BigEndian>()) as i64, 0),
+        8 => {
+            let v = try!(data.read_u64::<BigEndian>());
+            ((v & 0x3_ffff_ffff) as i64, (v >> 34) as u32)
+        }
+        _ => {
+            let nsecs = try!(data.read_u32::<BigEndian>());
+            (try!(data.read_i64::<BigEndian>()), nsecs)
+        }
+    };
+    if nsecs >= 1_000_000_000 {
+        return Err(_invalid_input("Invalid timestamp nanoseconds"));
+    }
+    let nsecs = Duration::new(0, nsecs);
+    if secs >= 0 {
+        Ok(UNIX_EPOCH + Duration::from_secs(secs as u64) + nsecs)
+    } else {
+        Ok(UNIX_EPOCH - Duration::from_secs(secs.wrapping_neg() as u64) + nsecs)
+    }
+}
+
+/// Reads all entries of a log written by a `Recorder`.
+pub fn read_log<R: Read>(rd: R) -> MsgpackResult<Vec<LogEntry>> {
+    let mut rd = BufReader::new(rd);
+    let mut entries = Vec::new();
+    while !try!(rd.fill_buf()).is_empty() {
+        entries.push(try!(LogEntry::read(&mut rd)));
+    }
+    Ok(entries)
+}
+
+/// Writes the messages sent and received through the transports it wraps
+/// to a log, which can be read with `read_log` and replayed with a
+/// `ReplayTransport`.
+///
+/// Clones write to the same log, e.g. for both halves of an `RpcSession`.
+#[derive(Clone)]
+pub struct Recorder {
+    log: Arc<Mutex<Box<Write + Send>>>
+}
+
+impl Recorder {
+    pub fn new<W: Write + Send + 'static>(log: W) -> Recorder {
+        Recorder { log: Arc::new(Mutex::new(Box::new(log))) }
+    }
+
+    /// Records to a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Recorder> {
+        Ok(Recorder::new(try!(File::create(path))))
+    }
+
+    /// Wraps `transport` to record the messages read from and written to
+    /// it. Failing to write the log fails the read or write, and so do bytes
+    /// that are not msgpack-rpc messages, along with all later reads or
+    /// writes, so that the log does not end unnoticed.
+    pub fn wrap<T>(&self, transport: T) -> Recording<T> {
+        Recording {
+            inner: transport,
+            recorder: self.clone(),
+            sent: Some(RpcCodec::new()),
+            received: Some(RpcCodec::new())
+        }
+    }
+
+    fn record(&self, direction: Direction, codec: &mut Option<RpcCodec>, bytes: &[u8]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if let Some(ref mut c) = *codec {
+            c.feed(bytes);
+        }
+        loop {
+            let message = match codec.as_mut().map(|c| c.decode()) {
+                Some(Ok(Some(message))) => message,
+                Some(Ok(None)) => return Ok(()),
+                // once the bytes are not a message, nothing more is recorded
+                Some(Err(_)) | None => {
+                    *codec = None;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a msgpack-rpc message, not recorded"));
+                }
+            };
+            let entry = LogEntry { direction: direction, time: SystemTime::now(), message: message };
+            try!(entry.write(&mut *log));
+            try!(log.flush());
+        }
+    }
+}
+
+/// A transport wrapped by `Recorder::wrap`.
+pub struct Recording<T> {
+    inner: T,
+    recorder: Recorder,
+    sent: Option<RpcCodec>,
+    received: Option<RpcCodec>
+}
+
+impl<T> Recording<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Read> Read for Recording<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        try!(self.recorder.record(Direction::Received, &mut self.received, &buf[.. n]));
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Recording<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        try!(self.recorder.record(Direction::Sent, &mut self.sent, &buf[.. n]));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A mock peer that answers with the messages of a log written by a
+/// `Recorder`.
+///
+/// Each request written to it is matched with the first unused recorded
+/// request with the same method and params, and answered with the
+/// recorded response, whose msgid is replaced by the one of the request.
+/// Requests that were not recorded are answered with the error
+/// `"No recorded call: <method>(<params>)"`. Notifications and requests
+/// the recorded side received are sent in the order they were logged,
+/// once the message logged before them was sent to the replay. Messages
+/// are sent right away, regardless of the timestamps.
+///
+/// Reading blocks until there is something to read. Once all recorded
+/// messages were sent to the replay and all of its messages were read, it
+/// reads as closed. Clones share the replay, e.g. for both halves of an
+/// `RpcSession`.
+#[derive(Clone)]
+pub struct ReplayTransport {
+    state: Arc<(Mutex<Replay>, Condvar)>
+}
+
+struct Replay {
+    // the messages sent by the recorded side, with the messages to answer
+    // each of them with
+    sent: Vec<(RpcMessage, Vec<RpcMessage>, bool)>,
+    input: RpcCodec,
+    output: Vec<u8>
+}
+
+impl ReplayTransport {
+    pub fn new(entries: Vec<LogEntry>) -> ReplayTransport {
+        let mut initial = Vec::new();
+        let mut sent: Vec<(RpcMessage, Vec<RpcMessage>, bool)> = Vec::new();
+        // the index in `sent` of the pending requests by msgid
+        let mut requests = HashMap::new();
+        for entry in entries {
+            match (entry.direction, entry.message) {
+                (Direction::Sent, message) => {
+                    if let RpcMessage::RpcRequest {msgid, ..} = message {
+                        requests.insert(msgid, sent.len());
+                    }
+                    sent.push((message, Vec::new(), false));
+                }
+                (Direction::Received, message) => {
+                    let request = match message {
+                        RpcMessage::RpcResponse {msgid, ..} => requests.remove(&msgid),
+                        _ => None
+                    };
+                    match request.or(sent.len().checked_sub(1)) {
+                        Some(i) => sent[i].1.push(message),
+                        None => initial.push(message)
+                    }
+                }
+            }
+        }
+        let mut replay = Replay { sent: sent, input: RpcCodec::new(), output: Vec::new() };
+        replay.send(initial, None);
+        ReplayTransport { state: Arc::new((Mutex::new(replay), Condvar::new())) }
+    }
+
+    /// Replays the log at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> MsgpackResult<ReplayTransport> {
+        Ok(ReplayTransport::new(try!(read_log(try!(File::open(path))))))
+    }
+
+    /// The number of recorded messages that were not sent to the replay
+    /// yet.
+    pub fn remaining(&self) -> usize {
+        self.state.0.lock().unwrap().sent.iter().filter(|s| !s.2).count()
+    }
+}
+
+impl Replay {
+    // Queues `messages`, with the msgid of responses replaced by `msgid`.
+    fn send(&mut self, messages: Vec<RpcMessage>, msgid: Option<u32>) {
+        for message in messages {
+            let message = match (message, msgid) {
+                (RpcMessage::RpcResponse {error, result, ..}, Some(msgid)) => {
+                    RpcMessage::RpcResponse { msgid: msgid, error: error, result: result }
+                }
+                (message, _) => message
+            };
+            // cannot fail, the message was decoded
+            let _ = RpcCodec::encode(&message, &mut self.output);
+        }
+    }
+
+    fn receive(&mut self, message: RpcMessage) {
+        let found = self.sent.iter().position(|&(ref recorded, _, used)| !used && match (recorded, &message) {
+            (&RpcMessage::RpcRequest {method: ref m1, params: ref p1, ..},
+             &RpcMessage::RpcRequest {method: ref m2, params: ref p2, ..}) => m1 == m2 && p1 == p2,
+            (&RpcMessage::RpcNotification {..}, &RpcMessage::RpcNotification {..}) => *recorded == message,
+            (&RpcMessage::RpcResponse {msgid: id1, ..}, &RpcMessage::RpcResponse {msgid: id2, ..}) => id1 == id2,
+            _ => false
+        });
+        let msgid = match message {
+            RpcMessage::RpcRequest {msgid, ..} => Some(msgid),
+            _ => None
+        };
+        match (found, message) {
+            (Some(i), _) => {
+                self.sent[i].2 = true;
+                let replies = self.sent[i].1.clone();
+                self.send(replies, msgid);
+            }
+            (None, RpcMessage::RpcRequest {msgid, method, params}) => {
+                let error = format!("No recorded call: {}({})", method, Value::Array(params));
+                self.send(vec![RpcMessage::RpcResponse { msgid: msgid, error: Value::from(error), result: Value::Nil }],
+                          None);
+            }
+            // other messages do not need an answer
+            (None, _) => {}
+        }
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (ref lock, ref cvar) = *self.state;
+        let mut replay = lock.lock().unwrap();
+        while replay.output.is_empty() {
+            if replay.sent.iter().all(|s| s.2) {
+                return Ok(0);
+            }
+            replay = cvar.wait(replay).unwrap();
+        }
+        let n = ::std::cmp::min(buf.len(), replay.output.len());
+        buf[.. n].copy_from_slice(&replay.output[.. n]);
+        replay.output.drain(.. n);
+        Ok(n)
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (ref lock, ref cvar) = *self.state;
+        let mut replay = lock.lock().unwrap();
+        replay.input.feed(buf);
+        loop {
+            match replay.input.decode() {
+                Ok(Some(message)) => replay.receive(message),
+                Ok(None) => break,
+                Err(e) => return Err(e.into())
+            }
+        }
+        cvar.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::process;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use super::{Direction, LogEntry, Recorder, ReplayTransport, read_log};
+    use super::super::{RpcClient, RpcMessage, RpcError, RpcSession};
+    use super::super::super::Value;
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entry(direction: Direction, message: RpcMessage) -> LogEntry {
+        LogEntry { direction: direction, time: UNIX_EPOCH, message: message }
+    }
+
+    fn request(msgid: u32, method: &str, params: Vec<Value>) -> RpcMessage {
+        RpcMessage::RpcRequest { msgid: msgid, method: method.to_string(), params: params }
+    }
+
+    fn response(msgid: u32, error: Value, result: Value) -> RpcMessage {
+        RpcMessage::RpcResponse { msgid: msgid, error: error, result: result }
+    }
+
+    fn notification(method: &str, params: Vec<Value>) -> RpcMessage {
+        RpcMessage::RpcNotification { method: method.to_string(), params: params }
+    }
+
+    #[test]
+    fn test_log_entries() {
+        let times = vec![UNIX_EPOCH, UNIX_EPOCH + Duration::new(1, 0), UNIX_EPOCH + Duration::new(1 << 40, 5),
+                         UNIX_EPOCH - Duration::new(1, 500), SystemTime::now()];
+        let mut log = Vec::new();
+        let entries: Vec<_> = times.into_iter().map(|time| {
+            LogEntry { direction: Direction::Received, time: time, message: notification("n", vec![Value::Nil]) }
+        }).collect();
+        for entry in &entries {
+            entry.write(&mut log).unwrap();
+        }
+        assert_eq!(entries, read_log(&log[..]).unwrap());
+
+        let mut log = Vec::new();
+        entries[1].write(&mut log).unwrap();
+        assert_eq!(&[0x93, 0xa8][..], &log[.. 2]);
+        // truncated
+        assert!(read_log(&log[.. log.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_record_invalid() {
+        let log = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let recorder = Recorder::new(log.clone());
+        let mut bytes = Vec::new();
+        super::super::write_message(&mut bytes, &notification("n", vec![])).unwrap();
+        let mut wr = recorder.wrap(Vec::new());
+        wr.write_all(&bytes).unwrap();
+        // a message of type 3
+        match wr.write(&[0x93, 0x03, 0xc0, 0xc0]) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        // nor are the messages after it recorded
+        assert!(wr.write(&bytes).is_err());
+        let entries = read_log(&log.0.lock().unwrap()[..]).unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(notification("n", vec![]), entries[0].message);
+
+        let mut rd = recorder.wrap(&[0xc1, 0x00][..]);
+        assert!(rd.read(&mut [0; 4]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_record_and_replay() {
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+        use super::super::RpcServer;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut server = RpcServer::new();
+        server.register_typed("add", |(x, y): (u32, u32)| Ok(x + y));
+        let handle = thread::spawn(move || server.serve(b).unwrap());
+
+        let log = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let start = SystemTime::now();
+        let mut client = RpcClient::new(Recorder::new(log.clone()).wrap(a));
+        assert_eq!(3, client.call_typed::<_, u32>("add", (1, 2)).unwrap());
+        client.notify("hello", vec![]).unwrap();
+        assert!(client.call("sub", vec![]).is_err());
+        assert_eq!(7, client.call_typed::<_, u32>("add", (3, 4)).unwrap());
+        drop(client);
+        handle.join().unwrap();
+
+        let entries = read_log(&log.0.lock().unwrap()[..]).unwrap();
+        let messages: Vec<_> = entries.iter().map(|e| (e.direction, e.message.clone())).collect();
+        assert_eq!(vec![
+            (Direction::Sent, request(0, "add", vec![Value::from(1), Value::from(2)])),
+            (Direction::Received, response(0, Value::Nil, Value::from(3u32))),
+            (Direction::Sent, notification("hello", vec![])),
+            (Direction::Sent, request(1, "sub", vec![])),
+            (Direction::Received, response(1, Value::from("Unknown method: sub"), Value::Nil)),
+            (Direction::Sent, request(2, "add", vec![Value::from(3), Value::from(4)])),
+            (Direction::Received, response(2, Value::Nil, Value::from(7u32)))], messages);
+        assert!(entries.iter().all(|e| e.time >= start && e.time <= SystemTime::now()));
+
+        // the replay answers in another order and with other msgids
+        let replay = ReplayTransport::new(entries);
+        let mut client = RpcClient::new(replay.clone());
+        match client.call("mul", vec![Value::from(2)]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("No recorded call: mul([2i])"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        assert_eq!(7, client.call_typed::<_, u32>("add", (3, 4)).unwrap());
+        assert_eq!(3, client.call_typed::<_, u32>("add", (1, 2)).unwrap());
+        // recorded calls are answered once
+        assert!(client.call_typed::<_, u32>("add", (1, 2)).is_err());
+        assert_eq!(2, replay.remaining());
+        client.notify("hello", vec![]).unwrap();
+        assert!(client.call("sub", vec![]).is_err());
+        assert_eq!(0, replay.remaining());
+        // the replay is over
+        assert!(client.call("add", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_replay_peer_messages() {
+        let replay = ReplayTransport::new(vec![
+            entry(Direction::Received, notification("ready", vec![])),
+            entry(Direction::Sent, request(5, "subscribe", vec![])),
+            entry(Direction::Received, notification("tick", vec![Value::from(1)])),
+            entry(Direction::Received, response(5, Value::Nil, Value::Boolean(true))),
+            entry(Direction::Received, notification("tick", vec![Value::from(2)])),
+            // a request of the peer, and the answer of the recorded side
+            entry(Direction::Received, request(9, "ping", vec![])),
+            entry(Direction::Sent, response(9, Value::Nil, Value::from("pong"))),
+            entry(Direction::Received, notification("bye", vec![]))]);
+
+        let session = RpcSession::new(replay.clone(), replay.clone());
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        for method in &["ready", "tick", "bye"] {
+            let tx = Mutex::new(tx.lock().unwrap().clone());
+            let method = method.to_string();
+            session.register(&method.clone(), move |params| {
+                tx.lock().unwrap().send((method.clone(), params)).unwrap();
+                Ok(Value::Nil)
+            });
+        }
+        session.register("ping", |_| Ok(Value::from("pong")));
+        // logged before anything was sent, so it is sent right away
+        assert_eq!(("ready".to_string(), vec![]), rx.recv().unwrap());
+        assert_eq!(Value::Boolean(true), session.call("subscribe", vec![]).unwrap());
+        assert_eq!(("tick".to_string(), vec![Value::from(1)]), rx.recv().unwrap());
+        assert_eq!(("tick".to_string(), vec![Value::from(2)]), rx.recv().unwrap());
+        // sent once the session answered the ping
+        assert_eq!(("bye".to_string(), vec![]), rx.recv().unwrap());
+        assert_eq!(0, replay.remaining());
+    }
+
+    #[test]
+    fn test_log_file() {
+        let path = env::temp_dir().join(format!("msgpack-rpc-test-{}.log", process::id()));
+        {
+            let recorder = Recorder::create(&path).unwrap();
+            let mut wr = recorder.wrap(Vec::new());
+            // written in pieces
+            let mut bytes = Vec::new();
+            super::super::write_message(&mut bytes, &request(0, "get", vec![Value::from("k")])).unwrap();
+            for b in &bytes {
+                wr.write_all(&[*b]).unwrap();
+            }
+            assert_eq!(bytes, *wr.get_ref());
+        }
+        let mut client = RpcClient::new(ReplayTransport::open(&path).unwrap());
+        match client.call("get", vec![Value::from("k")]) {
+            // the response was not recorded, so the replay ends
+            Err(RpcError::Transport(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use rustc_serialize::{Encodable, Decodable};
+use super::{RpcMessage, RpcError, Middleware, read_message, write_message, from_params};
+use super::middleware::Chain;
+use super::super::{Value, to_value};
+
+/// A handler gets the params of a request and returns its result, or the
+/// error value of the response.
+pub type Handler = Arc<Fn(Vec<Value>) -> Result<Value, Value> + Send + Sync>;
+
+/// Dispatches requests and notifications to handlers registered by method
+/// name.
+///
+/// Requests for methods without a handler are answered with the error
+/// `"Unknown method: <method>"`. Notifications are never answered, so the
+/// results of their handlers are dropped.
+///
+/// Clones share the handlers and middlewares registered so far.
+#[derive(Clone)]
+pub struct RpcServer {
+    handlers: HashMap<String, Handler>,
+    middlewares: Chain
+}
+
+impl RpcServer {
+    pub fn new() -> RpcServer {
+        RpcServer { handlers: HashMap::new(), middlewares: Chain::new() }
+    }
+
+    /// Registers `handler` for `method`, replacing any previous one.
+    pub fn register<F>(&mut self, method: &str, handler: F)
+        where F: Fn(Vec<Value>) -> Result<Value, Value> + Send + Sync + 'static {
+        self.handlers.insert(method.to_string(), Arc::new(handler));
+    }
+
+    /// Registers a handler that takes the params as a tuple of arguments,
+    /// or `()` for none. Params that do not decode as `A` are answered with
+    /// the error `"Invalid params for <method>: <reason>"`.
+    pub fn register_typed<A, R, F>(&mut self, method: &str, handler: F)
+        where A: Decodable + 'static, R: Encodable + 'static,
+              F: Fn(A) -> Result<R, Value> + Send + Sync + 'static {
+        let name = method.to_string();
+        self.register(method, move |params| {
+            let args = try!(from_params(params).map_err(|e| {
+                Value::from(format!("Invalid params for {}: {}", name, e))
+            }));
+            let result = try!(handler(args));
+            to_value(&result).map_err(|e| Value::from(format!("Invalid result of {}: {}", name, e)))
+        });
+    }
+
+    /// Adds a middleware that sees every request and notification, after
+    /// the middlewares added before.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middlewares.push(middleware);
+    }
+
+    /// Calls the handler for `method`, between the hooks of the
+    /// middlewares.
+    pub fn dispatch(&self, method: &str, params: Vec<Value>) -> Result<Value, Value> {
+        self.middlewares.run(method, params, |params| {
+            match self.handlers.get(method) {
+                Some(handler) => handler(params),
+                None => Err(Value::from(format!("Unknown method: {}", method)))
+            }
+        })
+    }
+
+    /// Handles one message and returns the response to send, if any.
+    /// Responses are ignored, a server does not make calls.
+    pub fn handle(&self, msg: RpcMessage) -> Option<RpcMessage> {
+        match msg {
+            RpcMessage::RpcRequest {msgid, method, params} => {
+                let (error, result) = match self.dispatch(&method, params) {
+                    Ok(result) => (Value::Nil, result),
+                    Err(error) => (error, Value::Nil)
+                };
+                Some(RpcMessage::RpcResponse {msgid: msgid, error: error, result: result})
+            }
+            RpcMessage::RpcNotification {method, params} => {
+                let _ = self.dispatch(&method, params);
+                None
+            }
+            RpcMessage::RpcResponse {..} => None
+        }
+    }
+
+    /// Serves requests on `transport` until the peer closes it. Fails if
+    /// the peer sends something that is not a message.
+    pub fn serve<T: Read + Write>(&self, transport: T) -> Result<(), RpcError> {
+        let mut io = BufReader::new(transport);
+        loop {
+            if try!(io.fill_buf()).is_empty() {
+                return Ok(());
+            }
+            let msg = try!(read_message(&mut io));
+            if let Some(response) = self.handle(msg) {
+                try!(write_message(io.get_mut(), &response));
+            }
+        }
+    }
+}
+
+impl Default for RpcServer {
+    fn default() -> RpcServer {
+        RpcServer::new()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::{BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use super::RpcServer;
+    use super::super::{RpcClient, RpcMessage, RpcError, read_message, write_message};
+    use super::super::super::Value;
+
+    fn server() -> (RpcServer, Arc<Mutex<Vec<Vec<Value>>>>) {
+        let logged = Arc::new(Mutex::new(Vec::new()));
+        let mut server = RpcServer::new();
+        server.register("add", |params| {
+            let mut sum = 0;
+            for p in &params {
+                sum += try!(p.as_u64().ok_or(Value::from("Expected unsigned integers")));
+            }
+            Ok(Value::from(sum))
+        });
+        let log = logged.clone();
+        server.register("log", move |params| {
+            log.lock().unwrap().push(params);
+            Ok(Value::Nil)
+        });
+        (server, logged)
+    }
+
+    #[test]
+    fn test_serve() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (server, logged) = server();
+        let handle = thread::spawn(move || server.serve(b).unwrap());
+
+        let mut client = RpcClient::new(a);
+        assert_eq!(Value::Unsigned(6), client.call("add", vec![Value::from(1), Value::from(5)]).unwrap());
+        match client.call("add", vec![Value::from("x")]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Expected unsigned integers"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        match client.call("sub", vec![]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Unknown method: sub"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        client.notify("log", vec![Value::from(1)]).unwrap();
+        client.notify("unknown", vec![]).unwrap();
+        assert_eq!(Value::Unsigned(0), client.call("add", vec![]).unwrap());
+        // nothing but the call results were sent back
+        assert_eq!(None, client.next_notification());
+        assert_eq!(vec![vec![Value::Unsigned(1)]], *logged.lock().unwrap());
+
+        drop(client);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_serve_raw_messages() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let (server, _) = server();
+        let handle = thread::spawn(move || server.serve(b));
+
+        // responses and notifications are not answered, requests are in order
+        write_message(&mut a, &RpcMessage::RpcResponse {msgid: 1, error: Value::Nil, result: Value::Nil}).unwrap();
+        write_message(&mut a, &RpcMessage::RpcNotification {method: "add".to_string(), params: vec![]}).unwrap();
+        for msgid in 10 .. 13 {
+            write_message(&mut a, &RpcMessage::RpcRequest {msgid: msgid, method: "add".to_string(),
+                                                            params: vec![Value::from(msgid)]}).unwrap();
+        }
+        let mut rd = BufReader::new(a.try_clone().unwrap());
+        for msgid in 10 .. 13 {
+            assert_eq!(RpcMessage::RpcResponse {msgid: msgid, error: Value::Nil, result: Value::from(msgid)},
+                       read_message(&mut rd).unwrap());
+        }
+
+        // garbage ends the connection with an error
+        a.write_all(&[0x94, 0x07]).unwrap();
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_typed() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut server = RpcServer::new();
+        server.register_typed("add", |(x, y): (u32, u32)| Ok(x + y));
+        server.register_typed("repeat", |(s, n): (String, usize)| {
+            if n > 3 {
+                return Err(Value::from("Too many"));
+            }
+            Ok(vec![s; n])
+        });
+        server.register_typed("version", |()| Ok((1u8, "beta".to_string())));
+        server.register_typed("negate", |(x,): (i64,)| Ok(-x));
+        let handle = thread::spawn(move || server.serve(b).unwrap());
+
+        let mut client = RpcClient::new(a);
+        assert_eq!(5, client.call_typed::<_, u32>("add", (2u32, 3u32)).unwrap());
+        assert_eq!(vec!["ab".to_string(); 2], client.call_typed::<_, Vec<String>>("repeat", ("ab", 2)).unwrap());
+        assert_eq!((1, "beta".to_string()), client.call_typed::<_, (u8, String)>("version", ()).unwrap());
+        assert_eq!(-4, client.call_typed::<_, i64>("negate", (4,)).unwrap());
+        // typed handlers can be called with plain values
+        assert_eq!(Value::from(7), client.call("add", vec![Value::from(3), Value::from(4)]).unwrap());
+
+        match client.call_typed::<_, Vec<String>>("repeat", ("ab", 4)) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Too many"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        for result in vec![client.call_typed::<_, u32>("add", (1,)),
+                           client.call_typed::<_, u32>("add", ("1", 2)),
+                           client.call_typed::<_, u32>("add", (1, -2)),
+                           client.call_typed::<_, u32>("version", (1,))] {
+            match result {
+                Err(RpcError::Remote(Value::Str(e))) => assert!(e.starts_with(b"Invalid params for ")),
+                r => panic!("unexpected {:?}", r)
+            }
+        }
+        // the result does not decode as the requested type
+        match client.call_typed::<_, String>("add", (1, 2)) {
+            Err(RpcError::Type(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        match client.call_typed::<_, u32>("add", 1) {
+            Err(RpcError::Type(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+
+        drop(client);
+        handle.join().unwrap();
+    }
+}
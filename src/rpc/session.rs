@@ -0,0 +1,888 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Weak, Mutex, RwLock, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use rustc_serialize::{Encodable, Decodable};
+use super::{RpcMessage, RpcError, RpcServer, Middleware, read_message, write_message, to_params, closed, timed_out,
+            log_discarded};
+use super::middleware::Chain;
+use super::super::{Value, from_value};
+
+/// Both ends of a connection on which each side can call the other, as
+/// done by Neovim.
+///
+/// A reader thread routes responses to the pending calls. Requests are
+/// handled by the registered handlers on a pool of threads, so that
+/// handlers can call back into the peer. At most `set_max_handlers`
+/// requests are handled at a time. Notifications are handled one after the
+/// other, in the order they arrive, on another thread.
+///
+/// Any number of threads can call the peer at the same time; the calls are
+/// sent without waiting for earlier ones to be answered. Calls can time out,
+/// see `set_timeout`, and the number of calls waiting for a response can be
+/// limited with `set_max_pending`. Responses that arrive too late, or to
+/// requests that were never made, are passed to the handler set with
+/// `on_discarded`, which by default logs them to standard error.
+///
+/// The session ends with `close`, or when the last clone of it is dropped.
+/// When the connection is closed or broken, all pending and future calls
+/// fail. Handlers that call the peer should capture an `RpcPeer` rather
+/// than a clone of the session, as the session lives as long as a handler
+/// referring to it.
+#[derive(Clone)]
+pub struct RpcSession {
+    inner: Arc<Inner>
+}
+
+/// A handle to call the peer of an `RpcSession` that does not keep the
+/// session alive.
+#[derive(Clone)]
+pub struct RpcPeer {
+    inner: Weak<Inner>
+}
+
+const DEFAULT_MAX_HANDLERS: usize = 32;
+
+// The threads only hold a `Weak` reference while they wait, so that
+// dropping the last `RpcSession` closes it.
+struct Inner {
+    // `None` once closed
+    writer: Mutex<Option<Box<Write + Send>>>,
+    pending: Mutex<Pending>,
+    // signalled whenever a call stops pending or the connection closes
+    slots: Condvar,
+    next_msgid: AtomicUsize,
+    // cloned out of the lock for each message, so that handlers can
+    // register others while they run
+    server: RwLock<Arc<RpcServer>>,
+    // of the calls to the peer, cloned out of the lock likewise
+    middlewares: RwLock<Arc<Chain>>,
+    discarded: RwLock<Box<Fn(&RpcMessage) + Send + Sync>>,
+    workers: Mutex<Workers>,
+    on_close: Mutex<Option<Box<FnOnce() + Send>>>
+}
+
+struct Pending {
+    calls: HashMap<u32, Sender<Result<Value, RpcError>>>,
+    closed: bool,
+    timeout: Option<Duration>,
+    max_pending: Option<usize>
+}
+
+// The threads handling requests, which take them from `requests` one at a
+// time. `idle` of them are waiting for one.
+struct Workers {
+    // `None` once closed, which ends the idle threads
+    queue: Option<Sender<RpcMessage>>,
+    requests: Arc<Mutex<Receiver<RpcMessage>>>,
+    threads: usize,
+    idle: usize,
+    max: usize
+}
+
+impl RpcSession {
+    /// Starts a session reading from `reader` and writing to `writer`,
+    /// usually both halves of the same connection, e.g. a `TcpStream` and
+    /// its `try_clone`. See `on_close` for how to stop the reader.
+    pub fn new<R, W>(reader: R, writer: W) -> RpcSession
+        where R: Read + Send + 'static, W: Write + Send + 'static {
+        RpcSession::with_server(reader, writer, RpcServer::new())
+    }
+
+    /// Like `new`, with the handlers of `server`.
+    pub fn with_server<R, W>(reader: R, writer: W, server: RpcServer) -> RpcSession
+        where R: Read + Send + 'static, W: Write + Send + 'static {
+        let (queue, requests) = mpsc::channel();
+        let inner = Arc::new(Inner {
+            writer: Mutex::new(Some(Box::new(writer))),
+            pending: Mutex::new(Pending {
+                calls: HashMap::new(),
+                closed: false,
+                timeout: None,
+                max_pending: None
+            }),
+            slots: Condvar::new(),
+            next_msgid: AtomicUsize::new(0),
+            server: RwLock::new(Arc::new(server)),
+            middlewares: RwLock::new(Arc::new(Chain::new())),
+            discarded: RwLock::new(Box::new(log_discarded)),
+            workers: Mutex::new(Workers {
+                queue: Some(queue),
+                requests: Arc::new(Mutex::new(requests)),
+                threads: 0,
+                idle: 0,
+                max: DEFAULT_MAX_HANDLERS
+            }),
+            on_close: Mutex::new(None)
+        });
+        let session = Arc::downgrade(&inner);
+        thread::spawn(move || read_loop(session, BufReader::new(reader)));
+        RpcSession { inner: inner }
+    }
+
+    /// Starts a session on a TCP connection, which is shut down on close.
+    pub fn tcp(stream: TcpStream) -> io::Result<RpcSession> {
+        let reader = try!(stream.try_clone());
+        let closer = try!(stream.try_clone());
+        let session = RpcSession::new(reader, stream);
+        session.on_close(move || { let _ = closer.shutdown(Shutdown::Both); });
+        Ok(session)
+    }
+
+    /// Starts a session on a Unix socket, which is shut down on close.
+    #[cfg(unix)]
+    pub fn unix(stream: UnixStream) -> io::Result<RpcSession> {
+        let reader = try!(stream.try_clone());
+        let closer = try!(stream.try_clone());
+        let session = RpcSession::new(reader, stream);
+        session.on_close(move || { let _ = closer.shutdown(Shutdown::Both); });
+        Ok(session)
+    }
+
+    /// Registers a handler for requests and notifications from the peer,
+    /// see `RpcServer::register`. Handlers that are running already are
+    /// not affected.
+    pub fn register<F>(&self, method: &str, handler: F)
+        where F: Fn(Vec<Value>) -> Result<Value, Value> + Send + Sync + 'static {
+        let mut server = self.inner.server.write().unwrap();
+        Arc::make_mut(&mut *server).register(method, handler);
+    }
+
+    /// Like `register`, see `RpcServer::register_typed`.
+    pub fn register_typed<A, R, F>(&self, method: &str, handler: F)
+        where A: Decodable + 'static, R: Encodable + 'static,
+              F: Fn(A) -> Result<R, Value> + Send + Sync + 'static {
+        let mut server = self.inner.server.write().unwrap();
+        Arc::make_mut(&mut *server).register_typed(method, handler);
+    }
+
+    /// Adds a middleware that sees the requests and notifications from the
+    /// peer, see `RpcServer::add_middleware`.
+    pub fn add_handler_middleware<M: Middleware + 'static>(&self, middleware: M) {
+        let mut server = self.inner.server.write().unwrap();
+        Arc::make_mut(&mut *server).add_middleware(middleware);
+    }
+
+    /// Adds a middleware that sees the calls to the peer, including those
+    /// of its `RpcPeer`s, see `RpcClient::add_middleware`. Calls that are
+    /// running already are not affected.
+    pub fn add_middleware<M: Middleware + 'static>(&self, middleware: M) {
+        let mut middlewares = self.inner.middlewares.write().unwrap();
+        Arc::make_mut(&mut *middlewares).push(middleware);
+    }
+
+    pub fn peer(&self) -> RpcPeer {
+        RpcPeer { inner: Arc::downgrade(&self.inner) }
+    }
+
+    /// Calls `method` on the peer and waits for the result, or until the
+    /// timeout set with `set_timeout` expires.
+    pub fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        self.inner.call(method, params)
+    }
+
+    /// Like `call`, but fails with an `io::ErrorKind::TimedOut` error if
+    /// the result did not arrive within `timeout`.
+    pub fn call_timeout(&self, method: &str, params: Vec<Value>, timeout: Duration) -> Result<Value, RpcError> {
+        self.inner.call_deadline(method, params, Some(Instant::now() + timeout))
+    }
+
+    /// Like `call`, see `RpcClient::call_typed`.
+    pub fn call_typed<A: Encodable, R: Decodable>(&self, method: &str, args: A) -> Result<R, RpcError> {
+        self.inner.call_typed(method, args)
+    }
+
+    pub fn notify(&self, method: &str, params: Vec<Value>) -> Result<(), RpcError> {
+        self.inner.send(&RpcMessage::RpcNotification { method: method.to_string(), params: params })
+    }
+
+    /// Sets the timeout of `call`, `call_typed` and of the calls of the
+    /// `RpcPeer`s of this session. The default `None` waits forever.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        self.inner.pending.lock().unwrap().timeout = timeout;
+    }
+
+    /// Limits the number of calls waiting for a response. Further calls
+    /// wait until one of them is answered or times out, which counts
+    /// towards their own timeout. The default `None` means no limit.
+    pub fn set_max_pending(&self, max_pending: Option<usize>) {
+        self.inner.pending.lock().unwrap().max_pending = max_pending;
+        self.inner.slots.notify_all();
+    }
+
+    /// Limits the number of requests from the peer that are handled at the
+    /// same time, 32 by default. Further requests are answered with the
+    /// error `"Too many concurrent requests"` right away, as waiting for a
+    /// running handler could wait for a call back into this session.
+    pub fn set_max_handlers(&self, max: usize) {
+        self.inner.workers.lock().unwrap().max = max;
+    }
+
+    /// The number of calls waiting for a response.
+    pub fn pending(&self) -> usize {
+        self.inner.pending.lock().unwrap().calls.len()
+    }
+
+    /// Sets the handler of responses that match no pending call, e.g.
+    /// because the call timed out.
+    pub fn on_discarded<F>(&self, handler: F) where F: Fn(&RpcMessage) + Send + Sync + 'static {
+        *self.inner.discarded.write().unwrap() = Box::new(handler);
+    }
+
+    /// Sets a function that shuts down the connection when the session is
+    /// closed, to end a reader thread that waits for data. Without one, the
+    /// thread only ends once the peer closes its end as well. `tcp` and
+    /// `unix` set one. Runs `f` right away if the session is closed.
+    pub fn on_close<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        let mut on_close = self.inner.on_close.lock().unwrap();
+        if !self.is_closed() {
+            *on_close = Some(Box::new(f));
+            return;
+        }
+        drop(on_close);
+        f();
+    }
+
+    /// Closes the connection: pending and future calls fail, the function
+    /// set with `on_close` is called and the writer is dropped. Handlers
+    /// that are running finish, but their responses are dropped.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    /// Whether the connection was closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.pending.lock().unwrap().closed
+    }
+}
+
+fn read_loop<R: Read>(session: Weak<Inner>, mut rd: BufReader<R>) {
+    let (notifications, queue) = mpsc::channel::<(String, Vec<Value>)>();
+    let handler_session = session.clone();
+    thread::spawn(move || {
+        for (method, params) in queue {
+            match handler_session.upgrade() {
+                Some(inner) => { let _ = inner.server().dispatch(&method, params); }
+                None => return
+            }
+        }
+    });
+
+    loop {
+        let msg = match rd.fill_buf() {
+            Ok(buf) if buf.is_empty() => break,
+            Ok(_) => match read_message(&mut rd) {
+                Ok(msg) => msg,
+                Err(_) => break
+            },
+            Err(_) => break
+        };
+        let inner = match session.upgrade() {
+            Some(inner) => inner,
+            None => return
+        };
+        match msg {
+            RpcMessage::RpcResponse {msgid, error, result} => {
+                let mut pending = inner.pending.lock().unwrap();
+                match pending.calls.remove(&msgid) {
+                    Some(call) => {
+                        // sent before the lock is released, so that a call
+                        // that times out meanwhile finds either itself still
+                        // pending or the response
+                        let _ = call.send(match error {
+                            Value::Nil => Ok(result),
+                            error => Err(RpcError::Remote(error))
+                        });
+                        drop(pending);
+                        inner.slots.notify_one();
+                    }
+                    None => {
+                        drop(pending);
+                        let msg = RpcMessage::RpcResponse { msgid: msgid, error: error, result: result };
+                        (inner.discarded.read().unwrap())(&msg);
+                    }
+                }
+            }
+            RpcMessage::RpcNotification {method, params} => {
+                let _ = notifications.send((method, params));
+            }
+            request => inner.start_handler(&session, request)
+        }
+    }
+
+    if let Some(inner) = session.upgrade() {
+        inner.close();
+    }
+}
+
+// Handles requests until the session is closed.
+fn worker(session: Weak<Inner>, requests: Arc<Mutex<Receiver<RpcMessage>>>) {
+    loop {
+        let request = match requests.lock().unwrap().recv() {
+            Ok(request) => request,
+            Err(_) => return
+        };
+        let inner = match session.upgrade() {
+            Some(inner) => inner,
+            None => return
+        };
+        if let Some(response) = inner.server().handle(request) {
+            let _ = inner.send(&response);
+        }
+        inner.workers.lock().unwrap().idle += 1;
+    }
+}
+
+impl RpcPeer {
+    /// Like `RpcSession::call`, fails if the session is gone.
+    pub fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        match self.inner.upgrade() {
+            Some(inner) => inner.call(method, params),
+            None => Err(closed())
+        }
+    }
+
+    pub fn call_typed<A: Encodable, R: Decodable>(&self, method: &str, args: A) -> Result<R, RpcError> {
+        match self.inner.upgrade() {
+            Some(inner) => inner.call_typed(method, args),
+            None => Err(closed())
+        }
+    }
+
+    pub fn notify(&self, method: &str, params: Vec<Value>) -> Result<(), RpcError> {
+        match self.inner.upgrade() {
+            Some(inner) => inner.send(&RpcMessage::RpcNotification { method: method.to_string(), params: params }),
+            None => Err(closed())
+        }
+    }
+}
+
+impl Inner {
+    fn send(&self, msg: &RpcMessage) -> Result<(), RpcError> {
+        match *self.writer.lock().unwrap() {
+            Some(ref mut writer) => {
+                try!(write_message(writer, msg));
+                Ok(())
+            }
+            None => Err(closed())
+        }
+    }
+
+    fn server(&self) -> Arc<RpcServer> {
+        self.server.read().unwrap().clone()
+    }
+
+    // Passes `request` to an idle worker, or to a new one if there are
+    // less than the maximum, or answers it with an error.
+    fn start_handler(&self, session: &Weak<Inner>, request: RpcMessage) {
+        let mut workers = self.workers.lock().unwrap();
+        let queue = match workers.queue {
+            Some(ref queue) => queue.clone(),
+            None => return
+        };
+        if workers.idle > 0 {
+            workers.idle -= 1;
+        } else if workers.threads < workers.max {
+            workers.threads += 1;
+            let session = session.clone();
+            let requests = workers.requests.clone();
+            thread::spawn(move || worker(session, requests));
+        } else {
+            drop(workers);
+            if let RpcMessage::RpcRequest {msgid, ..} = request {
+                let error = Value::from("Too many concurrent requests");
+                let _ = self.send(&RpcMessage::RpcResponse { msgid: msgid, error: error, result: Value::Nil });
+            }
+            return;
+        }
+        let _ = queue.send(request);
+    }
+
+    fn close(&self) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.closed = true;
+            // dropping the senders fails the pending calls
+            pending.calls.clear();
+        }
+        self.slots.notify_all();
+        let on_close = self.on_close.lock().unwrap().take();
+        if let Some(on_close) = on_close {
+            on_close();
+        }
+        self.workers.lock().unwrap().queue = None;
+        *self.writer.lock().unwrap() = None;
+    }
+
+    fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, RpcError> {
+        let timeout = self.pending.lock().unwrap().timeout;
+        self.call_deadline(method, params, timeout.map(|t| Instant::now() + t))
+    }
+
+    fn call_deadline(&self, method: &str, params: Vec<Value>, deadline: Option<Instant>) -> Result<Value, RpcError> {
+        let middlewares = self.middlewares.read().unwrap().clone();
+        middlewares.run_call(method, params, |params| self.call_peer(method, params, deadline))
+    }
+
+    fn call_peer(&self, method: &str, params: Vec<Value>, deadline: Option<Instant>) -> Result<Value, RpcError> {
+        let msgid = self.next_msgid.fetch_add(1, Ordering::SeqCst) as u32;
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            loop {
+                if pending.closed {
+                    return Err(closed());
+                }
+                if pending.max_pending.map_or(true, |max| pending.calls.len() < max) {
+                    break;
+                }
+                pending = match deadline {
+                    None => self.slots.wait(pending).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(timed_out());
+                        }
+                        self.slots.wait_timeout(pending, deadline - now).unwrap().0
+                    }
+                };
+            }
+            pending.calls.insert(msgid, tx);
+        }
+        let request = RpcMessage::RpcRequest { msgid: msgid, method: method.to_string(), params: params };
+        if let Err(e) = self.send(&request) {
+            self.forget(msgid);
+            return Err(e);
+        }
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return rx.recv().unwrap_or_else(|_| Err(closed()))
+        };
+        let now = Instant::now();
+        let timeout = if deadline > now { deadline - now } else { Duration::from_millis(0) };
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Disconnected) => Err(closed()),
+            Err(RecvTimeoutError::Timeout) => {
+                if self.forget(msgid) {
+                    return Err(timed_out());
+                }
+                // the reader took the call, and sent the response before
+                // releasing the lock, unless the session was closed
+                rx.try_recv().unwrap_or_else(|_| Err(closed()))
+            }
+        }
+    }
+
+    // Returns whether the call was still pending.
+    fn forget(&self, msgid: u32) -> bool {
+        let forgotten = self.pending.lock().unwrap().calls.remove(&msgid).is_some();
+        if forgotten {
+            self.slots.notify_one();
+        }
+        forgotten
+    }
+
+    fn call_typed<A: Encodable, R: Decodable>(&self, method: &str, args: A) -> Result<R, RpcError> {
+        let params = try!(to_params(&args).map_err(RpcError::Type));
+        let result = try!(self.call(method, params));
+        from_value(&result).map_err(RpcError::Type)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::RpcSession;
+    use super::super::{RpcError, RpcMessage, Metrics, Validator};
+    use super::super::super::Value;
+
+    fn pair() -> (RpcSession, RpcSession, UnixStream) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let a2 = a.try_clone().unwrap();
+        let b2 = b.try_clone().unwrap();
+        (RpcSession::new(a, a2.try_clone().unwrap()), RpcSession::new(b, b2), a2)
+    }
+
+    #[test]
+    fn test_calls_in_both_directions() {
+        let (a, b, _) = pair();
+        a.register("name", |_| Ok(Value::from("a")));
+        b.register("name", |_| Ok(Value::from("b")));
+        b.register("fail", |params| Err(params.into_iter().next().unwrap_or(Value::Nil)));
+        assert_eq!(Value::from("b"), a.call("name", vec![]).unwrap());
+        assert_eq!(Value::from("a"), b.call("name", vec![]).unwrap());
+        match a.call("fail", vec![Value::from(3)]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from(3), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        match a.call("missing", vec![]) {
+            Err(RpcError::Remote(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let (a, b, _) = pair();
+        // fac(n) on either side calls fac(n - 1) on the other side
+        for session in &[&a, &b] {
+            let peer = session.peer();
+            session.register("fac", move |params| {
+                let n = params[0].as_u64().unwrap();
+                if n <= 1 {
+                    return Ok(Value::from(1));
+                }
+                let rest = try!(peer.call("fac", vec![Value::from(n - 1)]).map_err(|e| e.into_value()));
+                Ok(Value::from(n * rest.as_u64().unwrap()))
+            });
+        }
+        assert_eq!(Value::from(3628800), a.call("fac", vec![Value::from(10)]).unwrap());
+    }
+
+    #[test]
+    fn test_notifications_in_order() {
+        let (a, b, _) = pair();
+        let (tx, rx) = mpsc::channel();
+        let tx = ::std::sync::Mutex::new(tx);
+        let peer = b.peer();
+        b.register("event", move |params| {
+            // handlers of notifications may call the peer as well
+            let echoed = peer.call("echo", params).unwrap();
+            tx.lock().unwrap().send(echoed).unwrap();
+            Ok(Value::Nil)
+        });
+        a.register("echo", |params| Ok(params[0].clone()));
+        for i in 0 .. 20 {
+            a.notify("event", vec![Value::from(i)]).unwrap();
+        }
+        for i in 0 .. 20 {
+            assert_eq!(Value::from(i), rx.recv().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (a, b, a_stream) = pair();
+        let (started, wait) = mpsc::channel();
+        let started = ::std::sync::Mutex::new(started);
+        b.register("hang", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            thread::sleep(Duration::from_secs(3600));
+            Ok(Value::Nil)
+        });
+        let caller = a.clone();
+        let call = thread::spawn(move || caller.call("hang", vec![]));
+        wait.recv().unwrap();
+
+        a_stream.shutdown(Shutdown::Both).unwrap();
+        match call.join().unwrap() {
+            Err(RpcError::Transport(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+        while !a.is_closed() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(a.call("hang", vec![]).is_err());
+    }
+
+    fn assert_error_kind(kind: io::ErrorKind, result: Result<Value, RpcError>) {
+        match result {
+            Err(RpcError::Transport(::byteorder::Error::Io(ref e))) if e.kind() == kind => {}
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_pipelined_calls() {
+        let (a, b, _) = pair();
+        b.register("echo", |params| {
+            // answer out of order
+            thread::sleep(Duration::from_millis(params[1].as_u64().unwrap() % 3));
+            Ok(Value::Array(params))
+        });
+        let threads: Vec<_> = (0 .. 8).map(|i| {
+            let a = a.clone();
+            thread::spawn(move || {
+                for j in 0 .. 30 {
+                    let params = vec![Value::from(i), Value::from(j)];
+                    assert_eq!(Value::Array(params.clone()), a.call("echo", params).unwrap());
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(0, a.pending());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (a, b, _) = pair();
+        let (release, wait) = mpsc::channel::<()>();
+        let wait = Mutex::new(wait);
+        b.register("block", move |_| {
+            wait.lock().unwrap().recv().unwrap();
+            Ok(Value::from("late"))
+        });
+        b.register("now", |_| Ok(Value::from("now")));
+        let (discarded, late) = mpsc::channel();
+        let discarded = Mutex::new(discarded);
+        a.on_discarded(move |msg| discarded.lock().unwrap().send(msg.clone()).unwrap());
+
+        assert_error_kind(io::ErrorKind::TimedOut, a.call_timeout("block", vec![], Duration::from_millis(50)));
+        assert_eq!(0, a.pending());
+        a.set_timeout(Some(Duration::from_millis(50)));
+        assert_error_kind(io::ErrorKind::TimedOut, a.call("block", vec![]));
+        a.set_timeout(None);
+
+        // the connection is still usable, the late responses are discarded
+        assert_eq!(Value::from("now"), a.call("now", vec![]).unwrap());
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        let mut msgids = vec![];
+        for _ in 0 .. 2 {
+            match late.recv().unwrap() {
+                RpcMessage::RpcResponse {msgid, result, ..} => {
+                    assert_eq!(Value::from("late"), result);
+                    msgids.push(msgid);
+                }
+                msg => panic!("unexpected {:?}", msg)
+            }
+        }
+        msgids.sort();
+        assert_eq!(vec![0, 1], msgids);
+    }
+
+    #[test]
+    fn test_timeout_race() {
+        let (a, b, _) = pair();
+        b.register("echo", |params| {
+            thread::sleep(Duration::from_millis(params[0].as_u64().unwrap() % 3));
+            Ok(Value::Array(params))
+        });
+        let discarded = Arc::new(AtomicUsize::new(0));
+        let count = discarded.clone();
+        a.on_discarded(move |_| {
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let mut timed_out = 0;
+        for i in 0 .. 300 {
+            let params = vec![Value::from(i)];
+            match a.call_timeout("echo", params.clone(), Duration::from_millis(1)) {
+                Ok(result) => assert_eq!(Value::Array(params), result),
+                r => {
+                    assert_error_kind(io::ErrorKind::TimedOut, r);
+                    timed_out += 1;
+                }
+            }
+        }
+        // every response was either returned or discarded
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while discarded.load(Ordering::SeqCst) < timed_out {
+            assert!(Instant::now() < deadline, "{} of {} late responses discarded",
+                    discarded.load(Ordering::SeqCst), timed_out);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(timed_out, discarded.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_max_pending() {
+        let (a, b, _) = pair();
+        let running = Arc::new(Mutex::new((0, 0)));
+        let counts = running.clone();
+        b.register("work", move |_| {
+            {
+                let mut counts = counts.lock().unwrap();
+                counts.0 += 1;
+                counts.1 = ::std::cmp::max(counts.0, counts.1);
+            }
+            thread::sleep(Duration::from_millis(20));
+            counts.lock().unwrap().0 -= 1;
+            Ok(Value::Nil)
+        });
+        a.set_max_pending(Some(2));
+        let threads: Vec<_> = (0 .. 6).map(|_| {
+            let a = a.clone();
+            thread::spawn(move || a.call("work", vec![]).unwrap())
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!((0, 2), *running.lock().unwrap());
+
+        // waiting for a free slot counts towards the timeout
+        a.set_max_pending(Some(0));
+        assert_error_kind(io::ErrorKind::TimedOut, a.call_timeout("work", vec![], Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_disconnect_fails_all_calls() {
+        let (a, b, a_stream) = pair();
+        let (started, wait) = mpsc::channel();
+        let started = Mutex::new(started);
+        b.register("hang", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            thread::sleep(Duration::from_secs(3600));
+            Ok(Value::Nil)
+        });
+        a.set_max_pending(Some(3));
+        let calls: Vec<_> = (0 .. 5).map(|_| {
+            let a = a.clone();
+            thread::spawn(move || a.call("hang", vec![]))
+        }).collect();
+        for _ in 0 .. 3 {
+            wait.recv().unwrap();
+        }
+        // three calls are pending, two wait for a slot
+        a_stream.shutdown(Shutdown::Both).unwrap();
+        for call in calls {
+            assert_error_kind(io::ErrorKind::NotConnected, call.join().unwrap());
+        }
+        assert_eq!(0, a.pending());
+    }
+
+    fn wait_closed(session: &RpcSession) {
+        for _ in 0 .. 1000 {
+            if session.is_closed() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("session was not closed");
+    }
+
+    fn unix_pair() -> (RpcSession, RpcSession) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (RpcSession::unix(a).unwrap(), RpcSession::unix(b).unwrap())
+    }
+
+    #[test]
+    fn test_close() {
+        let (a, b) = unix_pair();
+        let (started, wait) = mpsc::channel();
+        let started = Mutex::new(started);
+        b.register("hang", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            thread::sleep(Duration::from_secs(3600));
+            Ok(Value::Nil)
+        });
+        let caller = a.clone();
+        let call = thread::spawn(move || caller.call("hang", vec![]));
+        wait.recv().unwrap();
+
+        a.close();
+        assert_error_kind(io::ErrorKind::NotConnected, call.join().unwrap());
+        assert_error_kind(io::ErrorKind::NotConnected, a.call("hang", vec![]));
+        assert!(a.notify("hang", vec![]).is_err());
+        wait_closed(&b);
+    }
+
+    #[test]
+    fn test_drop_closes() {
+        let (a, b) = unix_pair();
+        // handlers that capture a peer do not keep the session alive
+        let peer = a.peer();
+        a.register("self", move |_| peer.call("self", vec![]).map_err(|e| e.into_value()));
+        b.register("one", |_| Ok(Value::from(1)));
+        assert_eq!(Value::from(1), a.call("one", vec![]).unwrap());
+        drop(a);
+        wait_closed(&b);
+    }
+
+    #[test]
+    fn test_max_handlers() {
+        let (a, b, _) = pair();
+        let (started, wait) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let started = Mutex::new(started);
+        let released = Mutex::new(released);
+        b.register("block", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            Ok(Value::from("done"))
+        });
+        b.set_max_handlers(2);
+        let calls: Vec<_> = (0 .. 2).map(|_| {
+            let a = a.clone();
+            thread::spawn(move || a.call("block", vec![]))
+        }).collect();
+        wait.recv().unwrap();
+        wait.recv().unwrap();
+        match a.call("block", vec![]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Too many concurrent requests"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        for call in calls {
+            assert_eq!(Value::from("done"), call.join().unwrap().unwrap());
+        }
+
+        // the threads are reused
+        let c = a.clone();
+        let call = thread::spawn(move || c.call("block", vec![]));
+        wait.recv().unwrap();
+        release.send(()).unwrap();
+        assert_eq!(Value::from("done"), call.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_register_in_handler() {
+        let (a, b, _) = pair();
+        let session = b.clone();
+        b.register("define", move |params| {
+            let value = params[0].clone();
+            session.register("defined", move |_| Ok(value.clone()));
+            Ok(Value::Nil)
+        });
+        a.call("define", vec![Value::from("x")]).unwrap();
+        assert_eq!(Value::from("x"), a.call("defined", vec![]).unwrap());
+        b.close();
+    }
+
+    #[test]
+    fn test_middleware() {
+        let (a, b, _) = pair();
+        b.register("add", |params| Ok(Value::from(params.iter().map(|p| p.as_u64().unwrap()).sum::<u64>())));
+        b.add_handler_middleware(Validator::new().arity("add", 2));
+        let metrics = Arc::new(Metrics::new());
+        a.add_middleware(Validator::new().arity("sub", 2));
+        a.add_middleware(metrics.clone());
+
+        let params = vec![Value::from(1), Value::from(2)];
+        assert_eq!(Value::from(3u64), a.call("add", params.clone()).unwrap());
+        assert_eq!(Value::from(3u64), a.peer().call("add", params).unwrap());
+        match a.call("add", vec![Value::from(1)]) {
+            Err(RpcError::Remote(e)) => assert_eq!(Value::from("Invalid params for add: expected 2 arguments, got 1"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        match a.call("sub", vec![]) {
+            Err(RpcError::Rejected(e)) => assert_eq!(Value::from("Invalid params for sub: expected 2 arguments, got 0"), e),
+            r => panic!("unexpected {:?}", r)
+        }
+        let add = metrics.get("add").unwrap();
+        assert_eq!((3, 1), (add.calls, add.errors));
+        assert_eq!(None, metrics.get("sub"));
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use super::{RpcClient, RpcServer};
+
+/// A listening socket that `RpcListener` can serve on.
+pub trait Listener: Send + Sync + 'static {
+    type Stream: Read + Write + Send + 'static;
+    type Addr: Clone;
+
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+    fn address(&self) -> io::Result<Self::Addr>;
+    /// Connects to the listener, to return from a blocked `accept_stream`.
+    fn wake(&self) -> io::Result<()>;
+    fn clone_stream(stream: &Self::Stream) -> io::Result<Self::Stream>;
+    /// Shuts down the reading half, so that the connection ends after the
+    /// request being handled.
+    fn shutdown_read(stream: &Self::Stream) -> io::Result<()>;
+    /// Called after the listener was shut down.
+    fn cleanup(&self) {}
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Addr = SocketAddr;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        let (stream, _) = try!(self.accept());
+        // messages are written with a single write each
+        try!(stream.set_nodelay(true));
+        Ok(stream)
+    }
+
+    fn address(&self) -> io::Result<SocketAddr> {
+        self.local_addr()
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        let mut addr = try!(self.local_addr());
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))
+            });
+        }
+        TcpStream::connect(addr).map(|_| ())
+    }
+
+    fn clone_stream(stream: &TcpStream) -> io::Result<TcpStream> {
+        stream.try_clone()
+    }
+
+    fn shutdown_read(stream: &TcpStream) -> io::Result<()> {
+        stream.shutdown(Shutdown::Read)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+    type Addr = PathBuf;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn address(&self) -> io::Result<PathBuf> {
+        let addr = try!(self.local_addr());
+        match addr.as_pathname() {
+            Some(path) => Ok(path.to_path_buf()),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Unnamed Unix socket"))
+        }
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        UnixStream::connect(try!(self.address())).map(|_| ())
+    }
+
+    fn clone_stream(stream: &UnixStream) -> io::Result<UnixStream> {
+        stream.try_clone()
+    }
+
+    fn shutdown_read(stream: &UnixStream) -> io::Result<()> {
+        stream.shutdown(Shutdown::Read)
+    }
+
+    fn cleanup(&self) {
+        if let Ok(path) = self.address() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Serves an `RpcServer` on a listening socket, with a thread per
+/// connection.
+///
+/// `shutdown`, also called on drop, stops accepting connections, lets every
+/// connection finish the request it is handling and waits for all threads.
+pub struct RpcListener<L: Listener> {
+    addr: L::Addr,
+    state: Arc<State<L>>,
+    thread: Option<JoinHandle<()>>
+}
+
+struct State<L: Listener> {
+    listener: L,
+    shutdown: AtomicBool,
+    // a clone of the stream and the thread of each open connection
+    connections: Mutex<HashMap<usize, (L::Stream, JoinHandle<()>)>>
+}
+
+impl RpcListener<TcpListener> {
+    /// Listens on `addr`, use port 0 to pick a free port.
+    pub fn tcp<A: ToSocketAddrs>(addr: A, server: RpcServer) -> io::Result<RpcListener<TcpListener>> {
+        RpcListener::new(try!(TcpListener::bind(addr)), server)
+    }
+}
+
+#[cfg(unix)]
+impl RpcListener<UnixListener> {
+    /// Listens on a new Unix socket at `path`, which is removed again on
+    /// shutdown.
+    pub fn unix<P: AsRef<Path>>(path: P, server: RpcServer) -> io::Result<RpcListener<UnixListener>> {
+        RpcListener::new(try!(UnixListener::bind(path)), server)
+    }
+}
+
+impl<L: Listener> RpcListener<L> {
+    pub fn new(listener: L, server: RpcServer) -> io::Result<RpcListener<L>> {
+        let addr = try!(listener.address());
+        let state = Arc::new(State {
+            listener: listener,
+            shutdown: AtomicBool::new(false),
+            connections: Mutex::new(HashMap::new())
+        });
+        let accept_state = state.clone();
+        let server = Arc::new(server);
+        let thread = thread::spawn(move || accept_loop(accept_state, server));
+        Ok(RpcListener { addr: addr, state: state, thread: Some(thread) })
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> L::Addr {
+        self.addr.clone()
+    }
+
+    /// The number of open connections.
+    pub fn connections(&self) -> usize {
+        self.state.connections.lock().unwrap().len()
+    }
+
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return
+        };
+        self.state.shutdown.store(true, Ordering::SeqCst);
+        // The connection returns the accept thread from `accept_stream`. If
+        // none can be made, it may block until someone else connects, so it
+        // is left to end on its own then.
+        if self.state.listener.wake().is_ok() {
+            let _ = thread.join();
+        }
+        let connections: Vec<_> = self.state.connections.lock().unwrap().drain().collect();
+        for (_, (stream, thread)) in connections {
+            let _ = L::shutdown_read(&stream);
+            let _ = thread.join();
+        }
+        self.state.listener.cleanup();
+    }
+}
+
+impl<L: Listener> Drop for RpcListener<L> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn accept_loop<L: Listener>(state: Arc<State<L>>, server: Arc<RpcServer>) {
+    let mut next_id = 0;
+    loop {
+        let stream = state.listener.accept_stream();
+        if state.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        let (stream, clone) = match stream.and_then(|s| L::clone_stream(&s).map(|c| (s, c))) {
+            Ok(streams) => streams,
+            // e.g. the connection was reset before it was accepted
+            Err(_) => continue
+        };
+        let id = next_id;
+        next_id += 1;
+        let mut connections = state.connections.lock().unwrap();
+        // checked again, as `stop` may not wait for this thread
+        if state.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        let thread_state = state.clone();
+        let thread_server = server.clone();
+        let thread = thread::spawn(move || {
+            let _ = thread_server.serve(stream);
+            thread_state.connections.lock().unwrap().remove(&id);
+        });
+        connections.insert(id, (clone, thread));
+    }
+}
+
+/// A transport whose reads can time out, for `RpcClient::call_timeout`.
+pub trait ReadTimeout {
+    /// Sets how long reads block at most; `None` blocks indefinitely.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl ReadTimeout for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Connects a client to a TCP server.
+pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<RpcClient<TcpStream>> {
+    let stream = try!(TcpStream::connect(addr));
+    try!(stream.set_nodelay(true));
+    Ok(RpcClient::new(stream))
+}
+
+/// Connects a client to a server on a Unix socket.
+#[cfg(unix)]
+pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<RpcClient<UnixStream>> {
+    UnixStream::connect(path).map(RpcClient::new)
+}
+
+/// The standard input and output of this process as one transport, for
+/// running as a child process of the peer.
+pub struct StdioTransport {
+    stdin: io::Stdin,
+    stdout: io::Stdout
+}
+
+pub fn stdio() -> StdioTransport {
+    StdioTransport { stdin: io::stdin(), stdout: io::stdout() }
+}
+
+impl Read for StdioTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for StdioTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+/// The standard input and output of a child process as one transport.
+/// Dropping it closes the input of the child and waits for it to exit.
+pub struct ChildTransport {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout
+}
+
+/// Spawns `command` with piped standard input and output.
+pub fn spawn(command: &mut Command) -> io::Result<ChildTransport> {
+    let mut child = try!(command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn());
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take().unwrap();
+    Ok(ChildTransport { child: child, stdin: stdin, stdout: stdout })
+}
+
+impl ChildTransport {
+    pub fn child(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Read for ChildTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for ChildTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for ChildTransport {
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::process;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    use super::{RpcListener, connect_tcp};
+    use super::super::{RpcServer, RpcError};
+    use super::super::super::Value;
+
+    fn server() -> RpcServer {
+        let mut server = RpcServer::new();
+        server.register("echo", |params| Ok(Value::Array(params)));
+        server
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = RpcListener::tcp("127.0.0.1:0", server()).unwrap();
+        let addr = listener.local_addr();
+        let clients: Vec<_> = (0 .. 4).map(|i| {
+            thread::spawn(move || {
+                let mut client = connect_tcp(addr).unwrap();
+                for j in 0 .. 10 {
+                    let params = vec![Value::from(i), Value::from(j)];
+                    assert_eq!(Value::Array(params.clone()), client.call("echo", params).unwrap());
+                }
+                client
+            })
+        }).collect();
+        let clients: Vec<_> = clients.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(4, listener.connections());
+        drop(clients);
+        listener.shutdown();
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        let (started, wait) = mpsc::channel();
+        let started = Mutex::new(started);
+        let mut server = server();
+        server.register("slow", move |_| {
+            started.lock().unwrap().send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            Ok(Value::from("done"))
+        });
+        let listener = RpcListener::tcp("127.0.0.1:0", server).unwrap();
+        let mut client = connect_tcp(listener.local_addr()).unwrap();
+        let call = thread::spawn(move || {
+            let result = client.call("slow", vec![]);
+            (result, client)
+        });
+        wait.recv().unwrap();
+        // waits for the running call, then closes the connection
+        listener.shutdown();
+        let (result, mut client) = call.join().unwrap();
+        assert_eq!(Value::from("done"), result.unwrap());
+        match client.call("echo", vec![]) {
+            Err(RpcError::Transport(_)) => {}
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix() {
+        use super::connect_unix;
+        let path = env::temp_dir().join(format!("msgpack-rpc-test-{}.sock", process::id()));
+        let listener = RpcListener::unix(&path, server()).unwrap();
+        assert_eq!(path, listener.local_addr());
+        {
+            let mut client = connect_unix(&path).unwrap();
+            assert_eq!(Value::Array(vec![Value::Nil]), client.call("echo", vec![Value::Nil]).unwrap());
+        }
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_child_process() {
+        use std::process::Command;
+        use super::spawn;
+        use super::super::{RpcClient, RpcMessage, write_message, read_message};
+        use std::io::BufReader;
+        // `cat` sends every message back
+        let mut child = spawn(&mut Command::new("cat")).unwrap();
+        let msg = RpcMessage::RpcNotification { method: "n".to_string(), params: vec![Value::from(1)] };
+        write_message(&mut child, &msg).unwrap();
+        assert_eq!(msg, read_message(&mut BufReader::new(&mut child)).unwrap());
+
+        let mut client = RpcClient::new(child);
+        client.notify("m", vec![]).unwrap();
+        assert_eq!(("m".to_string(), vec![]), client.wait_notification().unwrap());
+    }
+}
@@ -1,5 +1,6 @@
 use byteorder::{BigEndian, ReadBytesExt};
 use std::mem;
+use super::{encoder, Strictness};
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -439,17 +440,86 @@ pub fn parse_next<'a>(data: &'a[u8]) -> Result<(Value<'a>, &'a[u8]), Error> {
     }
 }
 
+/// Like `parse_next`, but fails for every encoding that is not the shortest
+/// one, i.e. that the functions in `encoder` would not have written.
+#[inline]
+pub fn parse_next_canonical<'a>(data: &'a[u8]) -> Result<(Value<'a>, &'a[u8]), Error> {
+    let (value, rest) = try!(parse_next(data));
+    let expected = match value {
+        Value::Unsigned(n)  => encoder::marker(|w| encoder::encode_unsigned(w, n)),
+        Value::Signed(n)    => encoder::marker(|w| encoder::encode_signed(w, n)),
+        Value::String(s)    => encoder::marker(|w| encoder::encode_str_len(w, s.len() as u32)),
+        Value::Binary(b)    => encoder::marker(|w| encoder::encode_bin_len(w, b.len() as u32)),
+        Value::Array(n)     => encoder::marker(|w| encoder::encode_vec_len(w, n as u32)),
+        Value::Map(n)       => encoder::marker(|w| encoder::encode_map_len(w, n as u32)),
+        _                   => data[0]
+    };
+    if data[0] == expected {
+        Ok((value, rest))
+    } else {
+        Err(Error::Invalid("Non-canonical encoding"))
+    }
+}
+
+/// Checks that the next value (recursively) is canonically encoded and that
+/// the keys of its maps are sorted by their encoded bytes, without
+/// duplicates. Returns the remaining data.
+pub fn validate_canonical<'a>(data: &'a[u8]) -> Result<&'a[u8], Error> {
+    match try!(parse_next_canonical(data)) {
+        (Value::Array(n), rest) => {
+            let mut next = rest;
+            for _ in 0 .. n {
+                next = try!(validate_canonical(next));
+            }
+            Ok(next)
+        }
+        (Value::Map(n), rest) => {
+            // in canonical form, keys are sorted by their encodings, and
+            // equal keys have equal encodings
+            let mut prev: Option<&[u8]> = None;
+            let mut next = rest;
+            for _ in 0 .. n {
+                let after_key = try!(validate_canonical(next));
+                let key = &next[.. next.len() - after_key.len()];
+                match prev {
+                    Some(p) if p == key => return Err(Error::Invalid("Duplicate map key")),
+                    Some(p) if p > key  => return Err(Error::Invalid("Unsorted map keys")),
+                    _                   => {}
+                }
+                prev = Some(key);
+                next = try!(validate_canonical(after_key));
+            }
+            Ok(next)
+        }
+        (_, rest) => {
+            Ok(rest)
+        }
+    }
+}
+
 pub struct Reader<'a> {
-    data: &'a[u8]
+    data: &'a[u8],
+    strictness: Strictness
 }
 
 impl<'a> Reader<'a> {
     pub fn new(data: &'a[u8]) -> Reader<'a> {
-        Reader{data: data}
+        Reader{data: data, strictness: Strictness::Strict}
+    }
+
+    /// With `Strictness::Canonical`, `next` fails for non-minimal
+    /// encodings. Duplicate map keys are only detected by
+    /// `validate_canonical`.
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
     }
 
     pub fn next(&mut self) -> Result<Value<'a>, Error> {
-        let r = parse_next(self.data);
+        let r = if self.strictness == Strictness::Canonical {
+            parse_next_canonical(self.data)
+        } else {
+            parse_next(self.data)
+        };
         match r {
             Ok((res, rest)) => {
                 self.data = rest;
@@ -530,3 +600,34 @@ fn test_decode_string() {
         _ => assert!(false)
     }
 }
+
+#[test]
+fn test_parse_next_canonical() {
+    assert!(parse_next_canonical(&[0x05]).is_ok());
+    assert_eq!(Err(Error::Invalid("Non-canonical encoding")), parse_next_canonical(&[0xcd, 0x00, 0x05]));
+    assert!(parse_next_canonical(&[0xd0, 0x05]).is_err());
+    assert!(parse_next_canonical(&[0xd1, 0xff, 0x00]).is_ok());
+    assert!(parse_next_canonical(&[0xda, 0x00, 0x03, b'a', b'b', b'c']).is_err());
+    assert!(parse_next_canonical(&[0xa3, b'a', b'b', b'c']).is_ok());
+    assert!(parse_next_canonical(&[0xdc, 0x00, 0x02]).is_err());
+    assert!(parse_next_canonical(&[0xdf, 0x00, 0x00, 0x00, 0x02]).is_err());
+    assert!(parse_next(&[0xcd, 0x00, 0x05]).is_ok());
+
+    let mut reader = Reader::new(&[0x92, 0x01, 0xcc, 0x02]);
+    reader.set_strictness(Strictness::Canonical);
+    assert_eq!(Ok(Value::Array(2)), reader.next());
+    assert_eq!(Ok(Value::Unsigned(1)), reader.next());
+    assert!(reader.next().is_err());
+}
+
+#[test]
+fn test_validate_canonical() {
+    let data = [0x82, 0xa1, b'a', 0x91, 0x01, 0xa1, b'b', 0xc0, 0x07];
+    assert_eq!(Ok(&[0x07][..]), validate_canonical(&data));
+    assert_eq!(Err(Error::Invalid("Duplicate map key")),
+               validate_canonical(&[0x82, 0xa1, b'a', 0x01, 0xa1, b'a', 0x02]));
+    assert_eq!(Err(Error::Invalid("Unsorted map keys")),
+               validate_canonical(&[0x82, 0xa1, b'b', 0x01, 0xa1, b'a', 0x02]));
+    assert!(validate_canonical(&[0x81, 0x91, 0xcc, 0x01, 0xc0]).is_err());
+    assert_eq!(Err(Error::Eos), validate_canonical(&[0x91]));
+}
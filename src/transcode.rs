@@ -0,0 +1,774 @@
+//! Streaming conversion between msgpack and JSON.
+//!
+//! msgpack is converted to JSON token by token, without building a
+//! `Value`, so memory use only depends on the nesting depth and the size of
+//! single strings, not on the length of arrays and maps. The output is the
+//! same as that of `Value::to_json` with the default `JsonOptions`, except
+//! for the order of the `ext` object fields.
+//!
+//! JSON is converted to msgpack with the smallest encoding for every
+//! integer and every array and map header. As msgpack puts the length of
+//! arrays and maps in front of their elements, the output of each JSON
+//! value is buffered until the value is complete. For long documents,
+//! `json_to_msgpack_seekable` buffers at most 64 KiB instead, at the cost
+//! of 32 bit headers for the arrays and maps that do not fit.
+//!
+//! Both directions read and write in many small pieces, so wrap unbuffered
+//! readers and writers in a `BufReader` or `BufWriter`.
+
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::Json;
+use super::{encoder, Decoder, Value, Token, MsgpackResult, _invalid_input};
+
+/// Converts the next msgpack value read by `dec` to JSON.
+pub fn msgpack_to_json<R: Read, W: Write>(dec: &mut Decoder<R>, wr: &mut W) -> MsgpackResult<()> {
+    let token = try!(dec._read_token());
+    write_value(dec, wr, token)
+}
+
+/// Converts all msgpack values up to the end of the input to JSON, one
+/// line per value. Returns the number of values.
+pub fn msgpack_to_json_lines<R: Read, W: Write>(dec: &mut Decoder<R>, wr: &mut W) -> MsgpackResult<u64> {
+    let mut n = 0;
+    loop {
+        match dec._peek_byte() {
+            Ok(_) => {}
+            Err(byteorder::Error::UnexpectedEOF) => return Ok(n),
+            Err(e) => return Err(e)
+        }
+        try!(msgpack_to_json(dec, wr));
+        try!(wr.write_all(b"\n"));
+        n += 1;
+    }
+}
+
+// Writes the value starting with `token`, reading arrays and maps element
+// by element. Open containers are kept on an explicit stack.
+fn write_value<R: Read, W: Write>(dec: &mut Decoder<R>, wr: &mut W, token: Token) -> MsgpackResult<()> {
+    // (is map, length, elements started)
+    let mut stack: Vec<(bool, usize, usize)> = Vec::new();
+    let mut token = token;
+    loop {
+        match token {
+            Token::Array(len) => { try!(wr.write_all(b"[")); stack.push((false, len, 0)); }
+            Token::Map(len) => { try!(wr.write_all(b"{")); stack.push((true, len, 0)); }
+            Token::Value(ref v) => try!(write_scalar(wr, v))
+        }
+        loop {
+            let (map, len, i) = match stack.last() {
+                Some(&top) => top,
+                None => return Ok(())
+            };
+            if i == len {
+                try!(wr.write_all(if map { b"}" } else { b"]" }));
+                stack.pop();
+                continue;
+            }
+            if i > 0 {
+                try!(wr.write_all(b","));
+            }
+            stack.last_mut().unwrap().2 += 1;
+            if map {
+                try!(write_key(dec, wr));
+                try!(wr.write_all(b":"));
+            }
+            break;
+        }
+        token = try!(dec._read_token());
+    }
+}
+
+// JSON keys are strings. Others are converted like `KeyPolicy::Stringify`
+// does, using the JSON text of the key.
+fn write_key<R: Read, W: Write>(dec: &mut Decoder<R>, wr: &mut W) -> MsgpackResult<()> {
+    match try!(dec._read_token()) {
+        Token::Value(Value::Str(ref s)) => write_str(wr, s),
+        Token::Value(Value::Binary(ref b)) => write_base64(wr, b),
+        token => {
+            let mut buf = Vec::new();
+            try!(write_value(dec, &mut buf, token));
+            write_str(wr, &buf)
+        }
+    }
+}
+
+fn write_scalar<W: Write>(wr: &mut W, v: &Value) -> MsgpackResult<()> {
+    match *v {
+        Value::Nil => try!(wr.write_all(b"null")),
+        Value::Boolean(b) => try!(write!(wr, "{}", b)),
+        Value::Unsigned(u) => try!(write!(wr, "{}", u)),
+        Value::Integer(i) => try!(write!(wr, "{}", i)),
+        // NaN and infinities become null
+        Value::Float(f) => try!(write!(wr, "{}", Json::F64(f as f64))),
+        Value::Double(f) => try!(write!(wr, "{}", Json::F64(f))),
+        Value::Str(ref s) => try!(write_str(wr, s)),
+        Value::Binary(ref b) => try!(write_base64(wr, b)),
+        Value::Extended(typ, ref data) => {
+            try!(write!(wr, "{{\"type\":{},\"data\":", typ));
+            try!(write_base64(wr, data));
+            try!(wr.write_all(b"}"));
+        }
+        Value::Array(_) | Value::Map(_) => unreachable!()
+    }
+    Ok(())
+}
+
+fn write_base64<W: Write>(wr: &mut W, data: &[u8]) -> MsgpackResult<()> {
+    try!(wr.write_all(b"\""));
+    try!(wr.write_all(data.to_base64(STANDARD).as_bytes()));
+    try!(wr.write_all(b"\""));
+    Ok(())
+}
+
+// Writes `s` as JSON string, replacing invalid UTF-8 with U+FFFD.
+fn write_str<W: Write>(wr: &mut W, mut s: &[u8]) -> MsgpackResult<()> {
+    try!(wr.write_all(b"\""));
+    while !s.is_empty() {
+        let (valid, invalid) = match ::std::str::from_utf8(s) {
+            Ok(_) => (s.len(), 0),
+            Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(s.len() - e.valid_up_to()))
+        };
+        // all escaped characters are ASCII, so `valid` can be split at them
+        let mut start = 0;
+        for (i, &b) in s[.. valid].iter().enumerate() {
+            let escaped: &[u8] = match b {
+                b'"' => b"\\\"",
+                b'\\' => b"\\\\",
+                b'\n' => b"\\n",
+                b'\r' => b"\\r",
+                b'\t' => b"\\t",
+                0x08 => b"\\b",
+                0x0c => b"\\f",
+                0x00 ... 0x1f => b"",
+                _ => continue
+            };
+            try!(wr.write_all(&s[start .. i]));
+            if escaped.is_empty() {
+                try!(write!(wr, "\\u{:04x}", b));
+            } else {
+                try!(wr.write_all(escaped));
+            }
+            start = i + 1;
+        }
+        try!(wr.write_all(&s[start .. valid]));
+        if invalid > 0 {
+            try!(wr.write_all("\u{fffd}".as_bytes()));
+        }
+        s = &s[valid + invalid ..];
+    }
+    try!(wr.write_all(b"\""));
+    Ok(())
+}
+
+/// Converts a JSON document, a single value with optional whitespace
+/// around it, to msgpack. The output is written once the value is
+/// complete, so nothing is written on errors.
+pub fn json_to_msgpack<R: Read, W: Write>(rd: &mut R, wr: &mut W) -> MsgpackResult<()> {
+    convert_json(rd, Output { wr: wr, buf: Vec::new(), pos: 0, patch: None })
+}
+
+/// Like `json_to_msgpack`, but buffers at most 64 KiB of output. Arrays
+/// and maps whose header is written before they end get a 32 bit length,
+/// which is filled in by seeking back. On errors, part of the output may
+/// have been written already.
+pub fn json_to_msgpack_seekable<R: Read, W: Write + Seek>(rd: &mut R, wr: &mut W) -> MsgpackResult<()> {
+    let pos = try!(wr.seek(SeekFrom::Current(0)));
+    convert_json(rd, Output { wr: wr, buf: Vec::new(), pos: pos, patch: Some(seek_patch::<W>) })
+}
+
+fn convert_json<R: Read, W: Write>(rd: &mut R, mut out: Output<W>) -> MsgpackResult<()> {
+    let mut rd = JsonReader { rd: rd, peeked: None };
+    try!(read_json(&mut rd, &mut out));
+    if try!(rd.skip_ws()).is_some() {
+        return Err(_invalid_input("Trailing characters after JSON value"));
+    }
+    out.flush()
+}
+
+/// Converts all JSON values up to the end of the input to msgpack. Values
+/// have to be separated by whitespace if they would run together otherwise,
+/// as in JSON lines. Each value is written once it is complete. Returns the
+/// number of values.
+pub fn json_lines_to_msgpack<R: Read, W: Write>(rd: &mut R, wr: &mut W) -> MsgpackResult<u64> {
+    let mut rd = JsonReader { rd: rd, peeked: None };
+    let mut out = Output { wr: wr, buf: Vec::new(), pos: 0, patch: None };
+    let mut n = 0;
+    while try!(rd.skip_ws()).is_some() {
+        try!(read_json(&mut rd, &mut out));
+        try!(out.flush());
+        n += 1;
+    }
+    Ok(n)
+}
+
+struct JsonReader<'a, R: Read + 'a> {
+    rd: &'a mut R,
+    peeked: Option<u8>
+}
+
+impl<'a, R: Read> JsonReader<'a, R> {
+    // The next byte, or `None` at the end of the input.
+    fn peek(&mut self) -> MsgpackResult<Option<u8>> {
+        if self.peeked.is_none() {
+            self.peeked = match self.rd.read_u8() {
+                Ok(c) => Some(c),
+                Err(byteorder::Error::UnexpectedEOF) => None,
+                Err(e) => return Err(e)
+            };
+        }
+        Ok(self.peeked)
+    }
+
+    fn next(&mut self) -> MsgpackResult<u8> {
+        match try!(self.peek()) {
+            Some(c) => { self.peeked = None; Ok(c) }
+            None => Err(byteorder::Error::UnexpectedEOF)
+        }
+    }
+
+    // Skips whitespace and peeks at the byte after it.
+    fn skip_ws(&mut self) -> MsgpackResult<Option<u8>> {
+        loop {
+            match try!(self.peek()) {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => self.peeked = None,
+                c => return Ok(c)
+            }
+        }
+    }
+
+    fn expect(&mut self, literal: &[u8]) -> MsgpackResult<()> {
+        for &c in literal {
+            if try!(self.next()) != c {
+                return Err(_invalid_input("Invalid JSON literal"));
+            }
+        }
+        Ok(())
+    }
+
+    fn hex4(&mut self) -> MsgpackResult<u32> {
+        let mut v = 0;
+        for _ in 0 .. 4 {
+            match (try!(self.next()) as char).to_digit(16) {
+                Some(d) => v = v * 16 + d,
+                None => return Err(_invalid_input("Invalid \\u escape in JSON string"))
+            }
+        }
+        Ok(v)
+    }
+
+    // Reads the rest of a string after the opening quote.
+    fn string(&mut self) -> MsgpackResult<String> {
+        let mut s = Vec::new();
+        loop {
+            match try!(self.next()) {
+                b'"' => break,
+                b'\\' => {
+                    let c = match try!(self.next()) {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut u = try!(self.hex4());
+                            if u >= 0xd800 && u < 0xdc00 {
+                                try!(self.expect(b"\\u"));
+                                let low = try!(self.hex4());
+                                if low < 0xdc00 || low >= 0xe000 {
+                                    return Err(_invalid_input("Invalid surrogate pair in JSON string"));
+                                }
+                                u = 0x10000 + ((u - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            match ::std::char::from_u32(u) {
+                                Some(c) => c,
+                                None => return Err(_invalid_input("Invalid surrogate pair in JSON string"))
+                            }
+                        }
+                        _ => return Err(_invalid_input("Invalid escape in JSON string"))
+                    };
+                    let mut buf = [0; 4];
+                    s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                0x00 ... 0x1f => return Err(_invalid_input("Control character in JSON string")),
+                c => s.push(c)
+            }
+        }
+        String::from_utf8(s).map_err(|_| _invalid_input("Invalid UTF-8 in JSON string"))
+    }
+
+    // Reads a number starting with `first`.
+    fn number<W: Write>(&mut self, first: u8, wr: &mut W) -> MsgpackResult<()> {
+        let mut s = String::new();
+        s.push(first as char);
+        loop {
+            match try!(self.peek()) {
+                Some(c @ b'0' ... b'9') | Some(c @ b'-') | Some(c @ b'+') |
+                Some(c @ b'.') | Some(c @ b'e') | Some(c @ b'E') => {
+                    s.push(c as char);
+                    self.peeked = None;
+                }
+                _ => break
+            }
+        }
+        if !is_json_number(&s) {
+            return Err(_invalid_input("Invalid JSON number"));
+        }
+        // integers that fit are encoded as integers, everything else as f64
+        if let Ok(u) = s.parse() {
+            encoder::encode_unsigned(wr, u)
+        } else if let Ok(i) = s.parse() {
+            encoder::encode_signed(wr, i)
+        } else {
+            encoder::encode_f64(wr, s.parse().unwrap())
+        }
+    }
+}
+
+// -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+fn is_json_number(s: &str) -> bool {
+    fn digits(s: &[u8]) -> usize {
+        s.iter().take_while(|c| c.is_ascii_digit()).count()
+    }
+    let mut s = s.as_bytes();
+    if s.first() == Some(&b'-') {
+        s = &s[1 ..];
+    }
+    let n = digits(s);
+    if n == 0 || (n > 1 && s[0] == b'0') {
+        return false;
+    }
+    s = &s[n ..];
+    if s.first() == Some(&b'.') {
+        let n = digits(&s[1 ..]);
+        if n == 0 {
+            return false;
+        }
+        s = &s[1 + n ..];
+    }
+    if s.first() == Some(&b'e') || s.first() == Some(&b'E') {
+        s = &s[1 ..];
+        if s.first() == Some(&b'+') || s.first() == Some(&b'-') {
+            s = &s[1 ..];
+        }
+        let n = digits(s);
+        if n == 0 {
+            return false;
+        }
+        s = &s[n ..];
+    }
+    s.is_empty()
+}
+
+// The size up to which the output of `json_to_msgpack_seekable` is
+// buffered, not counting the last scalar.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+// Buffers the output in front of `wr`. Arrays and maps get a 5 byte header
+// when they are opened. If it is still buffered when they are closed, it is
+// replaced with the shortest header, otherwise filled in by `patch` as
+// array32 or map32. Without `patch`, everything stays buffered until it is
+// flushed.
+struct Output<'a, W: Write + 'a> {
+    wr: &'a mut W,
+    buf: Vec<u8>,
+    // the position of `buf` in `wr`
+    pos: u64,
+    // writes a header at a position, and returns to the end of the output
+    patch: Option<fn(&mut W, u64, &[u8], u64) -> io::Result<()>>
+}
+
+fn seek_patch<W: Write + Seek>(wr: &mut W, at: u64, header: &[u8], end: u64) -> io::Result<()> {
+    try!(wr.seek(SeekFrom::Start(at)));
+    try!(wr.write_all(header));
+    try!(wr.seek(SeekFrom::Start(end)));
+    Ok(())
+}
+
+impl<'a, W: Write> Output<'a, W> {
+    // Reserves the header of an array or map and returns its position.
+    fn open(&mut self) -> u64 {
+        let header = self.pos + self.buf.len() as u64;
+        self.buf.extend_from_slice(&[0; 5]);
+        header
+    }
+
+    fn close(&mut self, header: u64, map: bool, len: usize) -> MsgpackResult<()> {
+        let len = try!(encoder::checked_len(len));
+        if header >= self.pos {
+            let mut short = Vec::new();
+            if map {
+                try!(encoder::encode_map_len(&mut short, len));
+            } else {
+                try!(encoder::encode_vec_len(&mut short, len));
+            }
+            let start = (header - self.pos) as usize;
+            self.buf.splice(start .. start + 5, short);
+        } else {
+            let mut long = vec![if map { 0xdf } else { 0xdd }];
+            try!(long.write_u32::<BigEndian>(len));
+            let patch = self.patch.expect("only flushed with open containers if they can be patched");
+            try!(patch(self.wr, header, &long, self.pos));
+        }
+        Ok(())
+    }
+
+    // Writes the buffer out once it is full, if headers can be patched.
+    fn spill(&mut self) -> MsgpackResult<()> {
+        if self.patch.is_some() && self.buf.len() >= MAX_BUFFERED {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> MsgpackResult<()> {
+        try!(self.wr.write_all(&self.buf));
+        self.pos += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+// Reads one JSON value. Open arrays and objects are kept on an explicit
+// stack as (is object, number of elements, position of the header).
+fn read_json<R: Read, W: Write>(rd: &mut JsonReader<R>, out: &mut Output<W>) -> MsgpackResult<()> {
+    let mut stack: Vec<(bool, usize, u64)> = Vec::new();
+    loop {
+        try!(out.spill());
+        let mut complete = true;
+        match try!(rd.skip_ws()) {
+            None => return Err(byteorder::Error::UnexpectedEOF),
+            Some(_) => match try!(rd.next()) {
+                c @ b'[' | c @ b'{' => {
+                    let map = c == b'{';
+                    let header = out.open();
+                    if try!(rd.skip_ws()) == Some(if map { b'}' } else { b']' }) {
+                        rd.peeked = None;
+                        try!(out.close(header, map, 0));
+                    } else {
+                        stack.push((map, 0, header));
+                        try!(element(rd, out, &mut stack));
+                        complete = false;
+                    }
+                }
+                b'"' => try!(encoder::encode_str(&mut out.buf, &try!(rd.string()))),
+                b't' => { try!(rd.expect(b"rue")); try!(encoder::encode_bool(&mut out.buf, true)) }
+                b'f' => { try!(rd.expect(b"alse")); try!(encoder::encode_bool(&mut out.buf, false)) }
+                b'n' => { try!(rd.expect(b"ull")); try!(encoder::encode_nil(&mut out.buf)) }
+                c @ b'-' | c @ b'0' ... b'9' => try!(rd.number(c, &mut out.buf)),
+                _ => return Err(_invalid_input("Unexpected character in JSON"))
+            }
+        }
+        // after a complete value, close containers until one continues
+        while complete {
+            let (map, len, header) = match stack.last() {
+                Some(&top) => top,
+                None => return Ok(())
+            };
+            match (try!(rd.skip_ws()), map) {
+                (Some(b','), _) => {
+                    rd.peeked = None;
+                    try!(element(rd, out, &mut stack));
+                    complete = false;
+                }
+                (Some(b']'), false) | (Some(b'}'), true) => {
+                    rd.peeked = None;
+                    stack.pop();
+                    try!(out.close(header, map, len));
+                }
+                (None, _) => return Err(byteorder::Error::UnexpectedEOF),
+                _ => return Err(_invalid_input("Expected ',' or closing bracket in JSON"))
+            }
+        }
+    }
+}
+
+// Starts the next element of the innermost container, reading the key if
+// it is an object.
+fn element<R: Read, W: Write>(rd: &mut JsonReader<R>, out: &mut Output<W>,
+                              stack: &mut [(bool, usize, u64)]) -> MsgpackResult<()> {
+    let top = stack.len() - 1;
+    stack[top].1 += 1;
+    if stack[top].0 {
+        if try!(rd.skip_ws()) != Some(b'"') {
+            return Err(_invalid_input("Expected string key in JSON object"));
+        }
+        rd.peeked = None;
+        try!(encoder::encode_str(&mut out.buf, &try!(rd.string())));
+        if try!(rd.skip_ws()) != Some(b':') {
+            return Err(_invalid_input("Expected ':' in JSON object"));
+        }
+        rd.peeked = None;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+    use std::str;
+    use rustc_serialize::json::Json;
+    use super::super::{Value, Decoder, Encoder};
+    use rustc_serialize::Encodable;
+    use super::{msgpack_to_json, msgpack_to_json_lines, json_to_msgpack, json_to_msgpack_seekable,
+                json_lines_to_msgpack, MAX_BUFFERED};
+
+    fn encode(v: &Value) -> Vec<u8> {
+        let mut buf = Vec::new();
+        v.encode(&mut Encoder::new(&mut buf)).unwrap();
+        buf
+    }
+
+    fn transcode(data: &[u8]) -> String {
+        let mut out = Vec::new();
+        msgpack_to_json(&mut Decoder::new(data), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_msgpack_to_json() {
+        let v = msgpack!({
+            "a": [1u8, -2i8, 1.5f32, 0.1, null, true, 18446744073709551615u64],
+            "b": bin([0, 1, 2]),
+            "e": [[], {}, [[1u8]]],
+            1u8: "x",
+            [1u8, {"k": null}]: "y",
+            2.5: ext(5, [0xff])
+        });
+        let json = transcode(&encode(&v));
+        assert_eq!(r#"{"a":[1,-2,1.5,0.1,null,true,18446744073709551615],"b":"AAEC","e":[[],{},[[1]]],"#.to_string() +
+                   r#""1":"x","[1,{\"k\":null}]":"y","2.5":{"type":5,"data":"/w=="}}"#, json);
+        assert_eq!(v.to_json(), Json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn test_msgpack_to_json_strings() {
+        let v = Value::Str(b"q\"b\\n\n\x01\x7f\xc3\xa4\xff\xe2\x82".to_vec());
+        let json = transcode(&encode(&v));
+        assert_eq!("\"q\\\"b\\\\n\\n\\u0001\x7f\u{e4}\u{fffd}\u{fffd}\"", json);
+        assert_eq!(v.to_json(), Json::from_str(&json).unwrap());
+        assert_eq!("\"\"", transcode(&[0xa0]));
+    }
+
+    #[test]
+    fn test_msgpack_to_json_special_floats() {
+        assert_eq!("[null,null]", transcode(&encode(&msgpack!([::std::f64::NAN, ::std::f32::INFINITY]))));
+    }
+
+    #[test]
+    fn test_msgpack_to_json_huge_array() {
+        // 100000 elements, each encoded as a positive fixint
+        let mut data = vec![0xdd, 0x00, 0x01, 0x86, 0xa0];
+        data.extend(::std::iter::repeat(7).take(100000));
+        let json = transcode(&data);
+        assert_eq!(200001, json.len());
+        assert!(json.starts_with("[7,7,") && json.ends_with(",7]"));
+    }
+
+    #[test]
+    fn test_msgpack_to_json_lines() {
+        let mut data = encode(&msgpack!({"a": 1}));
+        data.extend(encode(&msgpack!([true])));
+        data.extend(encode(&msgpack!("x")));
+        let mut out = Vec::new();
+        assert_eq!(3, msgpack_to_json_lines(&mut Decoder::new(&data[..]), &mut out).unwrap());
+        assert_eq!("{\"a\":1}\n[true]\n\"x\"\n", str::from_utf8(&out).unwrap());
+
+        let mut out = Vec::new();
+        assert_eq!(0, msgpack_to_json_lines(&mut Decoder::new(&[][..]), &mut out).unwrap());
+    }
+
+    #[test]
+    fn test_msgpack_to_json_errors() {
+        let mut out = Vec::new();
+        // truncated in the middle of an array, of a string, reserved marker
+        assert!(msgpack_to_json(&mut Decoder::new(&[0x92, 0x01][..]), &mut out).is_err());
+        assert!(msgpack_to_json(&mut Decoder::new(&[0xa3, b'a'][..]), &mut out).is_err());
+        assert!(msgpack_to_json(&mut Decoder::new(&[0x91, 0xc1][..]), &mut out).is_err());
+        assert!(msgpack_to_json_lines(&mut Decoder::new(&[0x01, 0x92][..]), &mut out).is_err());
+    }
+
+    fn from_json(json: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        json_to_msgpack(&mut json.as_bytes(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_json_to_msgpack() {
+        let json = r#" {"a": [1, -2, 1.5, -0.0, 1e2, null, true, false], "b": {}, "c": [[], [{"d": "e"}]]} "#;
+        let v = msgpack!({"a": [1u8, -2i8, 1.5, -0.0, 100.0, null, true, false], "b": {}, "c": [[], [{"d": "e"}]]});
+        assert_eq!(encode(&v), from_json(json));
+    }
+
+    #[test]
+    fn test_json_to_msgpack_ints() {
+        assert_eq!(vec![0x7f], from_json("127"));
+        assert_eq!(vec![0xcc, 0x80], from_json("128"));
+        assert_eq!(vec![0xe0], from_json("-32"));
+        assert_eq!(vec![0xd0, 0xdf], from_json("-33"));
+        assert_eq!(vec![0xcd, 0x01, 0x00], from_json("256"));
+        assert_eq!(vec![0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], from_json("18446744073709551615"));
+        assert_eq!(vec![0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0], from_json("-9223372036854775808"));
+        // too big for any integer type
+        assert_eq!(encode(&Value::Double(18446744073709551616.0)), from_json("18446744073709551616"));
+    }
+
+    #[test]
+    fn test_json_to_msgpack_strings() {
+        assert_eq!(encode(&msgpack!("a\"\\/\u{8}\u{c}\n\r\t\u{e4}\u{1f600}")),
+                   from_json(r#""a\"\\\/\b\f\n\r\t\u00e4\ud83d\ude00""#));
+        assert_eq!(encode(&msgpack!("\u{e4}")), from_json("\"\u{e4}\""));
+    }
+
+    #[test]
+    fn test_json_to_msgpack_lengths() {
+        // 20 elements need an array16 header, 16 entries a map16 header
+        let json = format!("[{}]", vec!["{}"; 20].join(","));
+        let out = from_json(&json);
+        assert_eq!(&[0xdc, 0x00, 0x14, 0x80], &out[.. 4]);
+        assert_eq!(23, out.len());
+
+        let entries: Vec<String> = (0 .. 16).map(|i| format!("\"{:x}\": [[]]", i)).collect();
+        let out = from_json(&format!("{{{}}}", entries.join(",")));
+        assert_eq!(&[0xde, 0x00, 0x10, 0xa1, b'0', 0x91, 0x90], &out[.. 7]);
+        let mut dec = Decoder::new(&out[..]);
+        assert_eq!(16, dec.decode_value().unwrap().as_map().unwrap().len());
+    }
+
+    #[test]
+    fn test_json_to_msgpack_long() {
+        // longer than the buffer of `json_to_msgpack_seekable`
+        let json = format!("{{\"a\": [{}]}}", vec!["\"0123456789\""; 10000].join(","));
+        let out = from_json(&json);
+        assert_eq!(&[0x81, 0xa1, b'a', 0xdc, 0x27, 0x10, 0xaa], &out[.. 7]);
+        assert_eq!(6 + 11 * 10000, out.len());
+
+        let mut seekable = Cursor::new(Vec::new());
+        json_to_msgpack_seekable(&mut json.as_bytes(), &mut seekable).unwrap();
+        let seekable = seekable.into_inner();
+        assert_eq!(&[0xdf, 0, 0, 0, 1, 0xa1, b'a', 0xdd, 0, 0, 0x27, 0x10, 0xaa], &seekable[.. 13]);
+        let mut dec = Decoder::new(&seekable[..]);
+        assert_eq!(Decoder::new(&out[..]).decode_value().unwrap(), dec.decode_value().unwrap());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let v = msgpack!({"a": [1u8, -2i8, 0.1, null, "\u{1}\"x"], "b": {"c": [[], {}]}});
+        let mut json = Vec::new();
+        msgpack_to_json(&mut Decoder::new(&encode(&v)[..]), &mut json).unwrap();
+        assert_eq!(encode(&v), from_json(str::from_utf8(&json).unwrap()));
+    }
+
+    #[test]
+    fn test_json_lines_to_msgpack() {
+        let mut out = Vec::new();
+        assert_eq!(4, json_lines_to_msgpack(&mut &b"{\"a\":1}\n[true]\n\"x\" 5\n"[..], &mut out).unwrap());
+        let mut expected = encode(&msgpack!({"a": 1u8}));
+        expected.extend(encode(&msgpack!([true])));
+        expected.extend(encode(&msgpack!("x")));
+        expected.push(5);
+        assert_eq!(expected, out);
+        assert_eq!(0, json_lines_to_msgpack(&mut &b" \n"[..], &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn test_json_to_msgpack_errors() {
+        for json in &["", "[1,", "[1 2]", "{\"a\" 1}", "{1: 2}", "[1,]", "{\"a\":1]", "tru", "nul",
+                      "01", "1.", "-", ".5", "1e", "+1", "\"abc", "\"\\x\"", "\"\\ud800\"",
+                      "\"\\ud800\\u0041\"", "\"\\udc00\"", "\"\u{1}\"", "1 2", "@"] {
+            let mut out = Vec::new();
+            assert!(json_to_msgpack(&mut json.as_bytes(), &mut out).is_err(), "{:?}", json);
+            // nothing is written
+            assert!(out.is_empty());
+        }
+        assert!(json_to_msgpack(&mut &[b'"', 0xff, b'"'][..], &mut Vec::new()).is_err());
+    }
+
+    // Counts the bytes as they are written.
+    struct CountingWriter {
+        inner: Cursor<Vec<u8>>,
+        written: Rc<Cell<usize>>
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = try!(self.inner.write(buf));
+            self.written.set(self.written.get() + n);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for CountingWriter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    // Reads `[0,0,...]`, and tracks how many of the elements read so far
+    // were not written yet.
+    struct Zeros {
+        len: usize,
+        read: usize,
+        written: Rc<Cell<usize>>,
+        max_buffered: usize
+    }
+
+    impl Read for Zeros {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let total = 2 * self.len + 1;
+            let mut n = 0;
+            while n < buf.len() && self.read < total {
+                buf[n] = match self.read {
+                    0 => b'[',
+                    i if i == total - 1 => b']',
+                    i if i % 2 == 1 => b'0',
+                    _ => b','
+                };
+                n += 1;
+                self.read += 1;
+            }
+            let elements = self.read / 2;
+            if elements > self.written.get() {
+                self.max_buffered = ::std::cmp::max(self.max_buffered, elements - self.written.get());
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_json_to_msgpack_bounded() {
+        let written = Rc::new(Cell::new(0));
+        let mut rd = Zeros { len: 300000, read: 0, written: written.clone(), max_buffered: 0 };
+        let mut wr = CountingWriter { inner: Cursor::new(Vec::new()), written: written };
+        json_to_msgpack_seekable(&mut rd, &mut wr).unwrap();
+        assert!(rd.max_buffered <= MAX_BUFFERED + 8, "{}", rd.max_buffered);
+
+        // the header was filled in afterwards
+        let out = wr.inner.into_inner();
+        assert_eq!(&[0xdd, 0x00, 0x04, 0x93, 0xe0, 0x00], &out[.. 6]);
+        assert_eq!(300005, out.len());
+        assert_eq!(300000, Decoder::new(&out[..]).decode_value().unwrap().as_array().unwrap().len());
+
+        // written after other data, with long and short containers inside
+        let json = format!("{{\"a\": [{}], \"b\": [[1]]}}", vec!["[]"; 100000].join(","));
+        let mut out = Cursor::new(vec![0xc0]);
+        out.seek(SeekFrom::End(0)).unwrap();
+        json_to_msgpack_seekable(&mut json.as_bytes(), &mut out).unwrap();
+        let out = out.into_inner();
+        assert_eq!(&[0xc0, 0xdf, 0, 0, 0, 2, 0xa1, b'a', 0xdd, 0x00, 0x01, 0x86, 0xa0, 0x90], &out[.. 14]);
+        assert_eq!(&[0xa1, b'b', 0x91, 0x91, 0x01], &out[out.len() - 5 ..]);
+        let v = Decoder::new(&out[1 ..]).decode_value().unwrap();
+        assert_eq!(100000, v["a"].as_array().unwrap().len());
+    }
+}
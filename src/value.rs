@@ -0,0 +1,409 @@
+//! Accessors, conversions, comparison and hashing of `Value`s. The order
+//! is documented at `Value`.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Index;
+use std::str::from_utf8;
+use super::{Value, ByteBuf, u64_to_f64_exact, i64_to_f64_exact};
+
+// Returned by `Index` for missing keys and indices.
+static NIL: Value = Value::Nil;
+
+impl Value {
+    pub fn is_nil(&self) -> bool {
+        match *self {
+            Value::Nil => true,
+            _ => false
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(b) => Some(b),
+            _ => None
+        }
+    }
+
+    /// Returns an integer value that fits into `u64`.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Unsigned(u) => Some(u),
+            Value::Integer(i) if i >= 0 => Some(i as u64),
+            _ => None
+        }
+    }
+
+    /// Returns an integer value that fits into `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(i) => Some(i),
+            Value::Unsigned(u) if u <= ::std::i64::MAX as u64 => Some(u as i64),
+            _ => None
+        }
+    }
+
+    /// Returns a float value, or an integer value that `f64` represents
+    /// exactly.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Float(f) => Some(f as f64),
+            Value::Double(d) => Some(d),
+            Value::Unsigned(u) => u64_to_f64_exact(u),
+            Value::Integer(i) => i64_to_f64_exact(i),
+            _ => None
+        }
+    }
+
+    /// Returns a `Str` value if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::Str(ref s) => from_utf8(s).ok(),
+            _ => None
+        }
+    }
+
+    /// Returns the bytes of a `Binary` or `Str` value.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::Binary(ref b) => Some(b),
+            Value::Str(ref s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match *self {
+            Value::Array(ref a) => Some(a),
+            _ => None
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&Vec<(Value, Value)>> {
+        match *self {
+            Value::Map(ref m) => Some(m),
+            _ => None
+        }
+    }
+
+    /// Looks up the value of the first entry of a map whose key is the
+    /// string `key`.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Map(ref m) => m.iter().find(|e| is_key(&e.0, key)).map(|e| &e.1),
+            _ => None
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        match *self {
+            Value::Map(ref mut m) => m.iter_mut().find(|e| is_key(&e.0, key)).map(|e| &mut e.1),
+            _ => None
+        }
+    }
+}
+
+fn is_key(k: &Value, key: &str) -> bool {
+    match *k {
+        Value::Str(ref s) => &s[..] == key.as_bytes(),
+        _ => false
+    }
+}
+
+/// Looks up a map entry by string key, or gives `Nil` if there is none.
+impl<'a> Index<&'a str> for Value {
+    type Output = Value;
+    fn index(&self, key: &str) -> &Value {
+        self.get(key).unwrap_or(&NIL)
+    }
+}
+
+/// Looks up an array element, or gives `Nil` if there is none.
+impl Index<usize> for Value {
+    type Output = Value;
+    fn index(&self, idx: usize) -> &Value {
+        match *self {
+            Value::Array(ref a) => a.get(idx).unwrap_or(&NIL),
+            _ => &NIL
+        }
+    }
+}
+
+macro_rules! from_primitive {
+    ($variant:ident, $target:ty, $($ty:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(v: $ty) -> Value { Value::$variant(v as $target) }
+            }
+        )*
+    }
+}
+
+from_primitive! { Unsigned, u64, u8, u16, u32, u64, usize }
+from_primitive! { Integer, i64, i8, i16, i32, i64, isize }
+from_primitive! { Float, f32, f32 }
+from_primitive! { Double, f64, f64 }
+
+impl From<()> for Value {
+    fn from(_: ()) -> Value { Value::Nil }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Value { Value::Boolean(v) }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Value { Value::Str(v.into_bytes()) }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(v: &str) -> Value { Value::Str(v.as_bytes().to_vec()) }
+}
+
+impl From<ByteBuf> for Value {
+    fn from(v: ByteBuf) -> Value { Value::Binary(v.0) }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Value {
+        match v {
+            Some(v) => v.into(),
+            None => Value::Nil
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Value {
+        Value::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<Value> + Eq + Hash, V: Into<Value>> From<HashMap<K, V>> for Value {
+    fn from(v: HashMap<K, V>) -> Value {
+        Value::Map(v.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+fn rank(v: &Value) -> u8 {
+    match *v {
+        Value::Nil => 0,
+        Value::Boolean(_) => 1,
+        Value::Integer(_) | Value::Unsigned(_) => 2,
+        Value::Float(_) | Value::Double(_) => 3,
+        Value::Str(_) => 4,
+        Value::Binary(_) => 5,
+        Value::Array(_) => 6,
+        Value::Map(_) => 7,
+        Value::Extended(_, _) => 8
+    }
+}
+
+// Integers as (is non-negative, two's complement bits), which orders
+// correctly when compared as a tuple.
+fn int_key(v: &Value) -> (bool, u64) {
+    match *v {
+        Value::Integer(i) => (i >= 0, i as u64),
+        Value::Unsigned(u) => (true, u),
+        _ => unreachable!()
+    }
+}
+
+fn float_of(v: &Value) -> f64 {
+    match *v {
+        Value::Float(f) => f as f64,
+        Value::Double(d) => d,
+        _ => unreachable!()
+    }
+}
+
+fn cmp_floats(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap()
+    }
+}
+
+fn sorted_entries(m: &[(Value, Value)]) -> Vec<&(Value, Value)> {
+    let mut entries: Vec<&(Value, Value)> = m.iter().collect();
+    entries.sort();
+    entries
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        match rank(self).cmp(&rank(other)) {
+            Ordering::Equal => {}
+            ord => return ord
+        }
+        match (self, other) {
+            (&Value::Boolean(a), &Value::Boolean(b)) => a.cmp(&b),
+            (&Value::Str(ref a), &Value::Str(ref b)) => a.cmp(b),
+            (&Value::Binary(ref a), &Value::Binary(ref b)) => a.cmp(b),
+            (&Value::Array(ref a), &Value::Array(ref b)) => a.cmp(b),
+            (&Value::Map(ref a), &Value::Map(ref b)) => sorted_entries(a).cmp(&sorted_entries(b)),
+            (&Value::Extended(t, ref a), &Value::Extended(u, ref b)) => (t, a).cmp(&(u, b)),
+            (a, b) => match rank(a) {
+                2 => int_key(a).cmp(&int_key(b)),
+                3 => cmp_floats(float_of(a), float_of(b)),
+                _ => Ordering::Equal // Nil
+            }
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        rank(self).hash(state);
+        match *self {
+            Value::Nil => {}
+            Value::Boolean(b) => b.hash(state),
+            Value::Integer(_) | Value::Unsigned(_) => int_key(self).hash(state),
+            Value::Float(_) | Value::Double(_) => {
+                let f = float_of(self);
+                let bits = if f.is_nan() {
+                    0x7ff8000000000000
+                } else if f == 0.0 {
+                    0
+                } else {
+                    f.to_bits()
+                };
+                bits.hash(state)
+            }
+            Value::Str(ref s) => s.hash(state),
+            Value::Binary(ref b) => b.hash(state),
+            Value::Array(ref a) => a.hash(state),
+            Value::Map(ref m) => sorted_entries(m).hash(state),
+            Value::Extended(t, ref d) => { t.hash(state); d.hash(state) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::f64;
+    use super::super::{Value, ByteBuf};
+
+    #[test]
+    fn test_integer_equality() {
+        assert_eq!(Value::Integer(1), Value::Unsigned(1));
+        assert!(Value::Integer(-1) < Value::Unsigned(0));
+        assert!(Value::Unsigned(::std::u64::MAX) > Value::Integer(::std::i64::MAX));
+        assert!(Value::Integer(::std::i64::MIN) < Value::Integer(-1));
+        assert!(Value::Unsigned(5) != Value::Double(5.0));
+    }
+
+    #[test]
+    fn test_float_order() {
+        assert_eq!(Value::Float(0.5), Value::Double(0.5));
+        assert_eq!(Value::Double(-0.0), Value::Double(0.0));
+        assert_eq!(Value::Double(f64::NAN), Value::Float(::std::f32::NAN));
+        assert!(Value::Double(f64::NAN) > Value::Double(f64::INFINITY));
+        assert!(Value::Double(f64::NEG_INFINITY) < Value::Float(-1.0));
+    }
+
+    #[test]
+    fn test_kind_order() {
+        let v = vec![Value::Extended(1, vec![]), Value::Map(vec![]), Value::Array(vec![]),
+                     Value::Binary(vec![]), Value::Str(vec![]), Value::Double(0.0),
+                     Value::Integer(0), Value::Boolean(false), Value::Nil];
+        let mut sorted = v.clone();
+        sorted.sort();
+        sorted.reverse();
+        assert_eq!(v, sorted);
+    }
+
+    #[test]
+    fn test_map_order_independent() {
+        let a = Value::Map(vec![(Value::Unsigned(1), Value::Nil), (Value::Unsigned(2), Value::Nil)]);
+        let b = Value::Map(vec![(Value::Integer(2), Value::Nil), (Value::Unsigned(1), Value::Nil)]);
+        assert_eq!(a, b);
+        assert!(a != Value::Map(vec![(Value::Unsigned(1), Value::Nil)]));
+    }
+
+    #[test]
+    fn test_collections() {
+        let values = vec![Value::Integer(1), Value::Unsigned(1), Value::Double(-0.0), Value::Float(0.0),
+                          Value::Double(f64::NAN), Value::Double(f64::NAN),
+                          Value::Map(vec![(Value::Nil, Value::Unsigned(1)), (Value::Boolean(true), Value::Nil)]),
+                          Value::Map(vec![(Value::Boolean(true), Value::Nil), (Value::Nil, Value::Integer(1))])];
+        let hashed: HashSet<Value> = values.iter().cloned().collect();
+        let ordered: BTreeSet<Value> = values.iter().cloned().collect();
+        assert_eq!(4, hashed.len());
+        assert_eq!(4, ordered.len());
+    }
+
+    #[test]
+    fn test_accessors() {
+        assert_eq!(Some(5), Value::Integer(5).as_u64());
+        assert_eq!(None, Value::Integer(-5).as_u64());
+        assert_eq!(Some(-5), Value::Integer(-5).as_i64());
+        assert_eq!(None, Value::Unsigned(::std::u64::MAX).as_i64());
+        assert_eq!(Some(0.5), Value::Float(0.5).as_f64());
+        assert_eq!(Some(3.0), Value::Unsigned(3).as_f64());
+        assert_eq!(None, Value::Unsigned(::std::u64::MAX).as_f64());
+        assert_eq!(None, Value::Str(b"1".to_vec()).as_u64());
+        assert_eq!(Some("abc"), Value::Str(b"abc".to_vec()).as_str());
+        assert_eq!(None, Value::Str(vec![0xff]).as_str());
+        assert_eq!(Some(&[0xff][..]), Value::Str(vec![0xff]).as_bytes());
+        assert_eq!(Some(&[1][..]), Value::Binary(vec![1]).as_bytes());
+        assert_eq!(Some(true), Value::Boolean(true).as_bool());
+        assert!(Value::Nil.is_nil());
+        assert!(!Value::Boolean(false).is_nil());
+        assert_eq!(Some(&vec![Value::Nil]), Value::Array(vec![Value::Nil]).as_array());
+        assert_eq!(None, Value::Nil.as_map());
+    }
+
+    #[test]
+    fn test_index() {
+        let mut v = Value::Map(vec![
+            (Value::from("a"), Value::from(vec![1u8, 2, 3])),
+            (Value::from(1u8), Value::from("int key")),
+            (Value::from("b"), Value::from(true))]);
+        assert_eq!(Value::Unsigned(2), v["a"][1]);
+        assert!(v["a"][3].is_nil());
+        assert!(v["missing"]["deeper"][0].is_nil());
+        assert!(v["b"][0].is_nil());
+        assert_eq!(Some(&Value::Boolean(true)), v.get("b"));
+        assert_eq!(None, v.get("1"));
+
+        *v.get_mut("b").unwrap() = Value::from(false);
+        assert_eq!(Value::Boolean(false), v["b"]);
+        assert!(v.get_mut("c").is_none());
+    }
+
+    #[test]
+    fn test_from() {
+        assert_eq!(Value::Unsigned(1), Value::from(1u16));
+        assert_eq!(Value::Integer(-1), Value::from(-1i8));
+        assert!(match Value::from(0.5f32) { Value::Float(_) => true, _ => false });
+        assert!(match Value::from(0.5f64) { Value::Double(_) => true, _ => false });
+        assert_eq!(Value::Str(b"x".to_vec()), Value::from("x".to_string()));
+        assert_eq!(Value::Binary(vec![1]), Value::from(ByteBuf(vec![1])));
+        assert_eq!(Value::Nil, Value::from(None::<u8>));
+        assert_eq!(Value::Nil, Value::from(()));
+        assert_eq!(Value::Array(vec![Value::from("a"), Value::from("b")]), Value::from(vec!["a", "b"]));
+
+        let mut m = HashMap::new();
+        m.insert("k", vec![1i32]);
+        assert_eq!(Value::Map(vec![(Value::from("k"), Value::Array(vec![Value::Integer(1)]))]), Value::from(m));
+    }
+}